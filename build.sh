@@ -32,10 +32,21 @@ Backspace ---> Back to game listing
 Space     ---> Pause game
 I         ---> Enter/Exit Debug mode
 O         ---> When in debug mode, print CPU state, decrement timers, execute 1 instruction
+P         ---> Print the display as text (native only)
 +/=       ---> Increase CPU Hz by 10
 _/-       ---> Decrease CPU Hz by 10
 0         ---> Set CPU Hz to Chip-8 default of 500hz
 9         ---> Set CPU Hz to SChip default of 1000hz
+F5        ---> Start/stop recording an animated GIF (native only)
+F6        ---> Start/stop recording an animated PNG (native only)
+F7        ---> Reset game and start/stop recording an input movie (native only)
+F8        ---> Start/stop recording sound to a WAV file (native only)
+F1        ---> Start a cheat search, with every byte of RAM and V0-VF a candidate (native only)
+F2        ---> Cheat search: keep what went down since the last filter (native only)
+F3        ---> Cheat search: keep what went up since the last filter (native only)
+F4        ---> Cheat search: keep what stayed the same since the last filter (native only)
+F9        ---> Cheat search: keep what changed since the last filter (native only)
+F10       ---> Turn the cheats from --cheats=FILE off/back on (native only)
 </pre>
 <a href="https://github.com/moparisthebest/oxi8">oxi8 git repo here</a><br/>
 Click a game to play in your browser:
@@ -0,0 +1,445 @@
+use core::fmt;
+use std::{
+    collections::VecDeque,
+    f32::consts::PI,
+    sync::{Arc, Mutex},
+};
+
+// the buzzer is generated here as the Cpu runs, timed against emulated instructions instead of
+// wall clock, frontends just pull samples out of it with fill_samples() from their audio callback
+
+// if nothing pulls samples out, don't buffer more than this many seconds of them
+const MAX_BUFFERED_SECONDS: u32 = 1;
+
+// when pacing, how far ahead of the audio device emulation tries to stay
+const PACING_TARGET_SECONDS: f64 = 0.05;
+// when pacing, the most samples per emulated second are stretched or squashed to absorb drift
+// between the audio clock and the emulated clock, 0.5% is far below what anyone can hear
+const PACING_MAX_RATE_ADJUST: f64 = 0.005;
+// when pacing, never catch up more than this many frames at once, so a long stall can't spiral
+const PACING_MAX_FRAMES: u32 = 4;
+
+const FRAMES_PER_SEC: f64 = 60.0; // frames_needed() counts in 60hz frames, same as the timers
+
+// what Buzzer::parse accepts, anything outside these can't be heard or turns into DC or NaNs
+const FREQUENCY_RANGE_HZ: (f32, f32) = (1.0, 20_000.0);
+const RAMP_RANGE_SECONDS: (f32, f32) = (0.0, 10.0);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Waveform {
+    Square,
+    Sine,
+    Triangle,
+    Noise,
+}
+
+impl Waveform {
+    // phase is 0-1 through the current cycle, noise is the random level for this cycle
+    fn sample(&self, phase: f32, noise: f32) -> f32 {
+        match self {
+            Waveform::Square => {
+                if phase < 0.5 {
+                    1.0
+                } else {
+                    -1.0
+                }
+            }
+            Waveform::Sine => (phase * 2.0 * PI).sin(),
+            Waveform::Triangle => 1.0 - 4.0 * (phase - 0.5).abs(),
+            Waveform::Noise => noise,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Waveform::Square => "square",
+            Waveform::Sine => "sine",
+            Waveform::Triangle => "triangle",
+            Waveform::Noise => "noise",
+        }
+    }
+}
+
+// how the buzzer sounds, the defaults are what oxi8 always sounded like, minus the clicks
+#[derive(Clone, Debug, PartialEq)]
+pub struct Buzzer {
+    pub frequency_hz: f32,
+    pub waveform: Waveform,
+    pub volume: f32,          // 0-1
+    pub attack_seconds: f32,  // time to fade in from silent to volume
+    pub release_seconds: f32, // time to fade out from volume to silent
+    // the COSMAC VIP makes no sound at all for a sound timer set below 2
+    pub vip_min_sound: bool,
+}
+
+impl Default for Buzzer {
+    fn default() -> Self {
+        Buzzer {
+            frequency_hz: 440.0,
+            waveform: Waveform::Sine,
+            volume: 1.0,
+            attack_seconds: 0.005,
+            release_seconds: 0.005,
+            vip_min_sound: false,
+        }
+    }
+}
+
+impl Buzzer {
+    // one 'name = value' per line, any not given keep their default, # starts a comment:
+    //   frequency = 440   # 1-20000
+    //   waveform = square
+    //   volume = 0.5      # 0-1
+    //   attack = 0.005    # seconds, 0-10
+    //   release = 0.01    # seconds, 0-10
+    //   vip = true
+    pub fn parse(settings: &str) -> Result<Buzzer, String> {
+        let mut buzzer = Buzzer::default();
+        for line in settings.lines() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let mut parts = line.splitn(2, '=').map(|part| part.trim());
+            let (name, value) = match (parts.next(), parts.next()) {
+                (Some(name), Some(value)) => (name, value),
+                _ => return Err(format!("expected 'name = value': {}", line)),
+            };
+            let bad_value = || format!("bad value for {}: {}", name, value);
+            // NaN fails both comparisons, infinity the max
+            let number = |(min, max): (f32, f32)| match value.parse::<f32>() {
+                Ok(number) if number >= min && number <= max => Ok(number),
+                _ => Err(format!(
+                    "{} must be a number from {} to {}, not {}",
+                    name, min, max, value
+                )),
+            };
+            match name {
+                "frequency" => buzzer.frequency_hz = number(FREQUENCY_RANGE_HZ)?,
+                "volume" => buzzer.volume = number((0.0, 1.0))?,
+                "attack" => buzzer.attack_seconds = number(RAMP_RANGE_SECONDS)?,
+                "release" => buzzer.release_seconds = number(RAMP_RANGE_SECONDS)?,
+                "waveform" => {
+                    buzzer.waveform = match value {
+                        "square" => Waveform::Square,
+                        "sine" => Waveform::Sine,
+                        "triangle" => Waveform::Triangle,
+                        "noise" => Waveform::Noise,
+                        _ => return Err(bad_value()),
+                    }
+                }
+                "vip" => buzzer.vip_min_sound = value.parse().map_err(|_| bad_value())?,
+                _ => return Err(format!("unknown buzzer setting: {}", name)),
+            }
+        }
+        Ok(buzzer)
+    }
+}
+
+impl fmt::Display for Buzzer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "frequency = {}", self.frequency_hz)?;
+        writeln!(f, "waveform = {}", self.waveform.name())?;
+        writeln!(f, "volume = {}", self.volume)?;
+        writeln!(f, "attack = {}", self.attack_seconds)?;
+        writeln!(f, "release = {}", self.release_seconds)?;
+        writeln!(f, "vip = {}", self.vip_min_sound)
+    }
+}
+
+struct Synth {
+    sample_rate: u32,
+    buzzer: Buzzer,
+    samples: VecDeque<f32>,
+    owed: f64,      // fractional samples not generated yet
+    phase: f32,     // 0-1, kept running while silent so the tone never jumps
+    amplitude: f32, // 0-volume, ramps up while the buzzer is on and down while off
+    noise: f32,     // current level for Waveform::Noise, changes every cycle
+    noise_state: u32,
+    capture: Option<Vec<f32>>, // every sample emulation generated since start_capture()
+    pacing: bool,
+    sound: u8,    // last known sound timer, used to keep going if the frontend runs dry
+    silent: bool, // this beep started below 2 with vip_min_sound on, stays silent until it ends
+}
+
+impl Synth {
+    fn next_sample(&mut self, sound: u8) -> f32 {
+        if sound == 0 {
+            self.silent = false;
+        } else if self.sound == 0 {
+            // a new beep just started
+            self.silent = self.buzzer.vip_min_sound && sound < 2;
+        }
+        self.sound = sound;
+        let sound_on = sound > 0 && !self.silent;
+
+        let volume = self.buzzer.volume;
+        let ramp_seconds = if sound_on {
+            self.buzzer.attack_seconds
+        } else {
+            self.buzzer.release_seconds
+        };
+        let ramp_step = volume / (ramp_seconds * self.sample_rate as f32).max(1.0);
+        self.amplitude = if sound_on {
+            (self.amplitude + ramp_step).min(volume)
+        } else {
+            (self.amplitude - ramp_step).max(0.0)
+        };
+
+        self.phase += self.buzzer.frequency_hz / self.sample_rate as f32;
+        if self.phase >= 1.0 {
+            self.phase %= 1.0;
+            // xorshift32, noise only needs to sound random
+            self.noise_state ^= self.noise_state << 13;
+            self.noise_state ^= self.noise_state >> 17;
+            self.noise_state ^= self.noise_state << 5;
+            self.noise = (self.noise_state >> 16) as f32 / 32768.0 - 1.0;
+        }
+        self.buzzer.waveform.sample(self.phase, self.noise) * self.amplitude
+    }
+
+    // sound is the sound timer for all of seconds of emulated time
+    fn generate(&mut self, sound: u8, seconds: f64) {
+        let rate = if self.pacing {
+            // fuller than the target generates slightly fewer samples, emptier slightly more
+            let target = self.sample_rate as f64 * PACING_TARGET_SECONDS;
+            let error = (target - self.samples.len() as f64) / target;
+            1.0 + error.clamp(-1.0, 1.0) * PACING_MAX_RATE_ADJUST
+        } else {
+            1.0
+        };
+        self.owed += seconds * self.sample_rate as f64 * rate;
+        while self.owed >= 1.0 {
+            self.owed -= 1.0;
+            let sample = self.next_sample(sound);
+            self.samples.push_back(sample);
+            if let Some(capture) = self.capture.as_mut() {
+                capture.push(sample);
+            }
+        }
+        let max_buffered = (self.sample_rate * MAX_BUFFERED_SECONDS) as usize;
+        while self.samples.len() > max_buffered {
+            self.samples.pop_front();
+        }
+    }
+}
+
+// cheap to clone, every clone shares the same samples, so the Cpu can produce them on one
+// thread while an audio callback consumes them on another
+pub struct Audio {
+    synth: Arc<Mutex<Synth>>,
+    // sound timer values and the emulated seconds they lasted, not turned into samples yet, so
+    // the synth is only locked once a frame instead of once an instruction
+    pending: Vec<(u8, f64)>,
+}
+
+// a clone doesn't take over what the original still has pending, only the original flushes it
+impl Clone for Audio {
+    fn clone(&self) -> Self {
+        Audio {
+            synth: self.synth.clone(),
+            pending: Vec::new(),
+        }
+    }
+}
+
+impl Audio {
+    // a sample_rate of 0 disables audio entirely, which is what Cpu::new starts with
+    pub fn new(sample_rate: u32) -> Audio {
+        Audio {
+            synth: Arc::new(Mutex::new(Synth {
+                sample_rate,
+                buzzer: Buzzer::default(),
+                samples: VecDeque::new(),
+                owed: 0.0,
+                phase: 0.0,
+                amplitude: 0.0,
+                noise: 0.0,
+                noise_state: 0x2545_F491,
+                capture: None,
+                pacing: false,
+                sound: 0,
+                silent: false,
+            })),
+            pending: Vec::new(),
+        }
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.synth.lock().unwrap().sample_rate
+    }
+
+    pub fn buzzer(&self) -> Buzzer {
+        self.synth.lock().unwrap().buzzer.clone()
+    }
+
+    // takes effect from the next sample generated on
+    pub fn set_buzzer(&self, buzzer: Buzzer) {
+        self.synth.lock().unwrap().buzzer = buzzer;
+    }
+
+    // pacing lets the audio device clock drive emulation: frontends run frames_needed() frames
+    // instead of 1 frame per 60hz tick, and the sample rate is nudged up or down a tiny bit to
+    // keep the buffer right around PACING_TARGET_SECONDS, so it never underruns or piles up
+    pub fn set_pacing(&self, on: bool) {
+        self.synth.lock().unwrap().pacing = on;
+    }
+
+    pub fn pacing(&self) -> bool {
+        self.synth.lock().unwrap().pacing
+    }
+
+    // how many 60hz frames should be ran right now to keep the audio device fed
+    pub fn frames_needed(&self) -> u32 {
+        let synth = self.synth.lock().unwrap();
+        if synth.sample_rate == 0 {
+            return 1;
+        }
+        let samples_per_frame = synth.sample_rate as f64 / FRAMES_PER_SEC;
+        let target = synth.sample_rate as f64 * PACING_TARGET_SECONDS;
+        let missing = target - synth.samples.len() as f64;
+        if missing <= 0.0 {
+            0
+        } else {
+            ((missing / samples_per_frame).ceil() as u32).min(PACING_MAX_FRAMES)
+        }
+    }
+
+    // number of samples generated but not pulled out by fill_samples() yet
+    pub fn buffered(&self) -> usize {
+        self.synth.lock().unwrap().samples.len()
+    }
+
+    // fills out with mono samples in -1.0 to 1.0, if emulation hasn't produced enough yet the
+    // buzzer keeps going in whatever state it was last in rather than cutting out with a click
+    pub fn fill_samples(&self, out: &mut [f32]) {
+        let mut synth = self.synth.lock().unwrap();
+        for sample in out.iter_mut() {
+            *sample = match synth.samples.pop_front() {
+                Some(sample) => sample,
+                None => {
+                    let sound = synth.sound;
+                    synth.next_sample(sound)
+                }
+            };
+        }
+    }
+
+    // starts keeping a copy of every sample emulation generates, these are timed against the
+    // emulated 60hz frames, so they line up with anything else recorded per frame
+    pub fn start_capture(&self) {
+        self.synth.lock().unwrap().capture = Some(Vec::new());
+    }
+
+    pub fn is_capturing(&self) -> bool {
+        self.synth.lock().unwrap().capture.is_some()
+    }
+
+    // returns everything captured since start_capture(), empty if it was never called
+    pub fn stop_capture(&self) -> Vec<f32> {
+        self.synth
+            .lock()
+            .unwrap()
+            .capture
+            .take()
+            .unwrap_or_default()
+    }
+
+    // called by the Cpu for every instruction with the sound timer after it ran,
+    // seconds is how much emulated time it took, nothing is generated until flush()
+    pub(crate) fn advance(&mut self, sound: u8, seconds: f64) {
+        match self.pending.last_mut() {
+            Some((last, pending)) if *last == sound => *pending += seconds,
+            _ => self.pending.push((sound, seconds)),
+        }
+    }
+
+    // generates the samples for everything advance() was told since the last flush, called by
+    // the Cpu at the end of every frame
+    pub(crate) fn flush(&mut self) {
+        if self.pending.is_empty() {
+            return;
+        }
+        let mut synth = self.synth.lock().unwrap();
+        if synth.sample_rate == 0 {
+            self.pending.clear();
+            return;
+        }
+        for (sound, seconds) in self.pending.drain(..) {
+            synth.generate(sound, seconds);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{BoolDisplay, Cpu, SeededRand};
+
+    #[test]
+    fn samples_follow_frames() {
+        let rom = [
+            0x60, 0x1E, // LD V0, 30
+            0xF0, 0x18, // LD ST, V0
+            0x12, 0x04, // JP 0x204
+        ];
+        let mut cpu = Cpu::new(&rom, BoolDisplay::new(1), SeededRand::new(0));
+        cpu.audio = Audio::new(44_100);
+        cpu.audio.start_capture();
+        for _ in 0..60 {
+            cpu.cycle_60hz();
+        }
+        let samples = cpu.audio.stop_capture();
+        // a frame is 735 samples at 44100hz, give or take rounding
+        assert!(
+            (44_099..=44_100).contains(&samples.len()),
+            "{}",
+            samples.len()
+        );
+        // the beep lasts the first half second, then fades out
+        assert!(samples[..22_000].iter().any(|sample| sample.abs() > 0.5));
+        assert!(samples[23_000..].iter().all(|sample| *sample == 0.0));
+    }
+
+    #[test]
+    fn buzzer_round_trips() {
+        let buzzer = Buzzer {
+            frequency_hz: 880.0,
+            waveform: Waveform::Triangle,
+            volume: 0.25,
+            attack_seconds: 0.0,
+            release_seconds: 0.5,
+            vip_min_sound: true,
+        };
+        assert_eq!(Buzzer::parse(&buzzer.to_string()), Ok(buzzer));
+        assert_eq!(
+            Buzzer::parse("# just a comment\nwaveform = square # loud\n"),
+            Ok(Buzzer {
+                waveform: Waveform::Square,
+                ..Buzzer::default()
+            })
+        );
+    }
+
+    #[test]
+    fn buzzer_rejects_unplayable_values() {
+        for settings in &[
+            "frequency = 0",
+            "frequency = -440",
+            "frequency = NaN",
+            "frequency = inf",
+            "frequency = 30000",
+            "volume = 1.5",
+            "volume = -0.1",
+            "volume = nan",
+            "attack = -1",
+            "release = inf",
+            "waveform = saw",
+            "vip = maybe",
+            "pitch = 440",
+            "frequency 440",
+        ] {
+            assert!(Buzzer::parse(settings).is_err(), "{}", settings);
+        }
+    }
+}
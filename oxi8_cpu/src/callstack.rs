@@ -0,0 +1,141 @@
+use core::fmt;
+
+use crate::{Cpu, Display, Rand, SymbolMap, STACK_SIZE};
+
+// a subroutine call still waiting on its 00EE, as debuggers show it
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct StackFrame {
+    pub call_site: u16, // address of the 2nnn that made the call
+    pub target: u16,    // the subroutine it called
+    // where this call has got to, the Cpu's pc for the innermost frame, and the call site of
+    // the frame inside it for the rest
+    pub pc: u16,
+}
+
+impl StackFrame {
+    // with labels wherever symbols has them
+    pub fn describe(&self, symbols: &SymbolMap) -> String {
+        let target = match symbols.name(self.target) {
+            Some(name) => name.to_string(),
+            None => format!("sub 0x{:03X}", self.target),
+        };
+        format!(
+            "{} in {} called from {}",
+            symbols.format(self.pc),
+            target,
+            symbols.format(self.call_site)
+        )
+    }
+}
+
+impl fmt::Display for StackFrame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.describe(&SymbolMap::default()))
+    }
+}
+
+impl<T: Display, R: Rand> Cpu<T, R> {
+    // innermost first, empty outside of any subroutine
+    pub fn call_stack(&self) -> Vec<StackFrame> {
+        let mut pc = self.pc;
+        (0..self.stack.sp)
+            .rev()
+            .map(|n| {
+                let frame = StackFrame {
+                    call_site: self.stack.stack[n],
+                    target: self.stack.targets[n],
+                    pc,
+                };
+                pc = frame.call_site;
+                frame
+            })
+            .collect()
+    }
+
+    // the deepest the stack has been since this rom was loaded, reset() doesn't clear it,
+    // reaching stack_capacity() means one more call crashes with "exceeded maximum stack size"
+    pub fn stack_high_water(&self) -> usize {
+        self.stack.high_water
+    }
+
+    pub fn stack_capacity(&self) -> usize {
+        STACK_SIZE
+    }
+
+    // one frame per line for panic messages
+    pub(crate) fn format_call_stack(&self) -> String {
+        self.call_stack()
+            .iter()
+            .map(|frame| format!("  {}\n", frame.describe(&self.symbols)))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{BoolDisplay, SeededRand};
+
+    // main calls f, which calls g twice, then main loops
+    const CALLS: [u8; 14] = [
+        0x22, 0x04, // CALL f
+        0x12, 0x02, // JP 0x202
+        0x00, 0xE0, // f: CLS
+        0x22, 0x0C, // CALL g
+        0x22, 0x0C, // CALL g
+        0x00, 0xEE, // RET
+        0x00, 0xEE, // g: RET
+    ];
+
+    fn frame(call_site: u16, target: u16, pc: u16) -> StackFrame {
+        StackFrame {
+            call_site,
+            target,
+            pc,
+        }
+    }
+
+    #[test]
+    fn chains_frames_innermost_first() {
+        let mut cpu = Cpu::new(&CALLS, BoolDisplay::new(1), SeededRand::new(0));
+        cpu.symbols.insert("f", 0x204);
+        assert!(cpu.call_stack().is_empty());
+        for _ in 0..3 {
+            cpu.step();
+        }
+        // in g from the first call, each frame's pc is the call site of the one inside it
+        let stack = cpu.call_stack();
+        assert_eq!(
+            stack,
+            [frame(0x206, 0x20C, 0x20C), frame(0x200, 0x204, 0x206)]
+        );
+        assert_eq!(stack[0].to_string(), "0x20C in sub 0x20C called from 0x206");
+        assert_eq!(
+            cpu.format_call_stack(),
+            "  0x20C <f+8> in sub 0x20C called from 0x206 <f+2>\n  0x206 <f+2> in f called from 0x200\n"
+        );
+        cpu.step();
+        assert_eq!(cpu.call_stack(), [frame(0x200, 0x204, 0x208)]);
+        cpu.step();
+        assert_eq!(
+            cpu.call_stack(),
+            [frame(0x208, 0x20C, 0x20C), frame(0x200, 0x204, 0x208)]
+        );
+    }
+
+    #[test]
+    fn keeps_the_high_water_mark() {
+        let mut cpu = Cpu::new(&CALLS, BoolDisplay::new(1), SeededRand::new(0));
+        assert_eq!(cpu.stack_high_water(), 0);
+        for _ in 0..8 {
+            cpu.step();
+        }
+        // back in main, having been 2 calls deep twice
+        assert_eq!(cpu.pc, 0x202);
+        assert!(cpu.call_stack().is_empty());
+        assert_eq!(cpu.stack_high_water(), 2);
+        cpu.reset();
+        assert_eq!(cpu.stack_high_water(), 2);
+        assert_eq!(cpu.stack_capacity(), STACK_SIZE);
+    }
+}
@@ -0,0 +1,426 @@
+use core::fmt;
+
+use crate::{CheatTarget, Cpu, Display, Rand, FNV_OFFSET_BASIS, FNV_PRIME, RAM_SIZE};
+
+// cheat files, text like states and movies, one file can hold cheats for any number of roms, each
+// under a 'rom' line with the hash of the rom as loaded, so a shared file works no matter what the
+// rom file is called, the name after the hash is only for people reading it
+//   oxi8 cheats 1
+//   rom 8f3a51c1f3c6d2b7 PONG
+//   on infinite lives: freeze 0x2F0 3
+//   off player 2 stands still: freeze V7 0x10; freeze V8 0x0C
+//   on skip title: patch 0x204 0x12 0x40
+// a freeze writes its value back before every instruction, so the rom never sees anything else,
+// a patch is written over the rom once, when the cheats are loaded or it's turned on, and the
+// bytes it replaced are put back when it's turned off
+// every change to the cheats checkpoints history so replay runs with the cheats of the time
+
+const MAGIC: &str = "oxi8 cheats 1";
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum CheatAction {
+    Freeze(CheatTarget, u8),
+    Patch(u16, Vec<u8>), // bytes written starting at the address
+}
+
+impl CheatAction {
+    // freeze TARGET VALUE or patch ADDR BYTE..., numbers in hex with 0x or decimal
+    pub fn parse(action: &str) -> Result<CheatAction, String> {
+        let mut words = action.split_whitespace();
+        match (words.next(), words.next()) {
+            (Some("freeze"), Some(target)) => {
+                let target = CheatTarget::parse(target)?;
+                match (words.next(), words.next()) {
+                    (Some(value), None) => Ok(CheatAction::Freeze(target, parse_byte(value)?)),
+                    _ => Err(format!("expected freeze TARGET VALUE, not {}", action)),
+                }
+            }
+            (Some("patch"), Some(addr)) => {
+                let addr = match CheatTarget::parse(addr)? {
+                    CheatTarget::Ram(addr) => addr,
+                    CheatTarget::V(_) => return Err(format!("can only patch ram, not {}", addr)),
+                };
+                let bytes = words.map(parse_byte).collect::<Result<Vec<u8>, String>>()?;
+                if bytes.is_empty() {
+                    return Err(format!("expected patch ADDR BYTE..., not {}", action));
+                }
+                if addr as usize + bytes.len() > RAM_SIZE {
+                    return Err(format!("patch at 0x{:03X} runs past the end of ram", addr));
+                }
+                Ok(CheatAction::Patch(addr, bytes))
+            }
+            _ => Err(format!("expected freeze or patch, not {}", action)),
+        }
+    }
+}
+
+impl fmt::Display for CheatAction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CheatAction::Freeze(target, value) => write!(f, "freeze {} 0x{:02X}", target, value),
+            CheatAction::Patch(addr, bytes) => {
+                write!(f, "patch 0x{:03X}", addr)?;
+                for byte in bytes {
+                    write!(f, " 0x{:02X}", byte)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+fn parse_byte(byte: &str) -> Result<u8, String> {
+    match byte.strip_prefix("0x") {
+        Some(hex) => u8::from_str_radix(hex, 16),
+        None => byte.parse(),
+    }
+    .map_err(|_| format!("not a byte value: {}", byte))
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Cheat {
+    name: String,
+    enabled: bool,
+    actions: Vec<CheatAction>,
+    replaced: Vec<Vec<u8>>, // what each patch wrote over while enabled on a cpu
+}
+
+impl Cheat {
+    pub fn new(name: &str, enabled: bool, actions: Vec<CheatAction>) -> Cheat {
+        Cheat {
+            name: name.to_string(),
+            enabled,
+            actions,
+            replaced: Vec::new(),
+        }
+    }
+
+    // on|off NAME: ACTION; ACTION...
+    pub fn parse(line: &str) -> Result<Cheat, String> {
+        let (enabled, rest) = if let Some(rest) = line.strip_prefix("on ") {
+            (true, rest)
+        } else if let Some(rest) = line.strip_prefix("off ") {
+            (false, rest)
+        } else {
+            return Err(format!("cheats start with on or off: {}", line));
+        };
+        // actions never have a ':' so names can
+        let colon = rest
+            .rfind(':')
+            .ok_or_else(|| format!("expected NAME: ACTIONS, not {}", rest))?;
+        let actions = rest[colon + 1..]
+            .split(';')
+            .map(|action| CheatAction::parse(action.trim()))
+            .collect::<Result<Vec<CheatAction>, String>>()?;
+        Ok(Cheat::new(rest[..colon].trim(), enabled, actions))
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn actions(&self) -> &[CheatAction] {
+        &self.actions
+    }
+}
+
+impl fmt::Display for Cheat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} {}:",
+            if self.enabled { "on" } else { "off" },
+            self.name
+        )?;
+        for (n, action) in self.actions.iter().enumerate() {
+            write!(f, "{} {}", if n == 0 { "" } else { ";" }, action)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct RomCheats {
+    pub hash: u64,
+    pub name: String,
+    pub cheats: Vec<Cheat>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CheatFile {
+    pub roms: Vec<RomCheats>,
+}
+
+impl CheatFile {
+    pub fn parse(cheats: &str) -> Result<CheatFile, String> {
+        let mut lines = cheats
+            .lines()
+            .enumerate()
+            .map(|(n, line)| (n + 1, line.trim()))
+            .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'));
+        if lines.next().map(|(_, line)| line) != Some(MAGIC) {
+            return Err(format!("not oxi8 cheats, first line must be '{}'", MAGIC));
+        }
+        let mut file = CheatFile::default();
+        for (n, line) in lines {
+            if let Some(rom) = line.strip_prefix("rom ") {
+                let mut words = rom.splitn(2, char::is_whitespace);
+                let hash = words.next().unwrap_or("");
+                let hash = u64::from_str_radix(hash, 16)
+                    .map_err(|_| format!("line {}: not a rom hash: {}", n, hash))?;
+                file.roms.push(RomCheats {
+                    hash,
+                    name: words.next().unwrap_or("").trim().to_string(),
+                    cheats: Vec::new(),
+                });
+            } else {
+                let cheat = Cheat::parse(line).map_err(|e| format!("line {}: {}", n, e))?;
+                file.roms
+                    .last_mut()
+                    .ok_or_else(|| format!("line {}: cheat before any rom line", n))?
+                    .cheats
+                    .push(cheat);
+            }
+        }
+        Ok(file)
+    }
+
+    // every cheat for the rom with this hash, none if it isn't in the file
+    pub fn cheats_for(&self, hash: u64) -> Vec<Cheat> {
+        self.roms
+            .iter()
+            .filter(|rom| rom.hash == hash)
+            .flat_map(|rom| rom.cheats.iter().cloned())
+            .collect()
+    }
+
+    // replaces whatever the file had for the rom, the rest is kept as is
+    pub fn set_cheats(&mut self, hash: u64, name: &str, cheats: &[Cheat]) {
+        self.roms.retain(|rom| rom.hash != hash);
+        self.roms.push(RomCheats {
+            hash,
+            name: name.to_string(),
+            cheats: cheats
+                .iter()
+                .map(|cheat| Cheat::new(&cheat.name, cheat.enabled, cheat.actions.clone()))
+                .collect(),
+        });
+    }
+}
+
+impl fmt::Display for CheatFile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{}", MAGIC)?;
+        for rom in &self.roms {
+            writeln!(f, "rom {:016x} {}", rom.hash, rom.name)?;
+            for cheat in &rom.cheats {
+                writeln!(f, "{}", cheat)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// 64-bit FNV-1a, same as frame hashes, over the rom file as loaded
+pub fn rom_hash(rom: &[u8]) -> u64 {
+    rom.iter().fold(FNV_OFFSET_BASIS, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(FNV_PRIME)
+    })
+}
+
+impl<T: Display, R: Rand> Cpu<T, R> {
+    pub fn rom_hash(&self) -> u64 {
+        self.rom_hash
+    }
+
+    pub fn cheats(&self) -> &[Cheat] {
+        &self.cheats
+    }
+
+    // replaces the cheats in effect, undoing the patches of the old ones first, enabled patches
+    // are written right away so load these before running
+    pub fn load_cheats(&mut self, cheats: Vec<Cheat>) {
+        for n in 0..self.cheats.len() {
+            self.enable_cheat(n, false);
+        }
+        self.cheats = cheats;
+        for n in 0..self.cheats.len() {
+            let enabled = self.cheats[n].enabled;
+            self.cheats[n].enabled = false;
+            self.enable_cheat(n, enabled);
+        }
+        self.checkpoint();
+    }
+
+    pub fn add_cheat(&mut self, cheat: Cheat) {
+        let enabled = cheat.enabled;
+        self.cheats.push(Cheat {
+            enabled: false,
+            ..cheat
+        });
+        self.enable_cheat(self.cheats.len() - 1, enabled);
+        self.checkpoint();
+    }
+
+    pub fn remove_cheat(&mut self, index: usize) -> Option<Cheat> {
+        self.enable_cheat(index, false);
+        if index < self.cheats.len() {
+            let cheat = self.cheats.remove(index);
+            self.checkpoint();
+            Some(cheat)
+        } else {
+            None
+        }
+    }
+
+    // turns a cheat on or off, patching or unpatching ram as needed, false if there's no such
+    // cheat
+    pub fn enable_cheat(&mut self, index: usize, enabled: bool) -> bool {
+        let ram = &mut self.ram;
+        let cheat = match self.cheats.get_mut(index) {
+            Some(cheat) => cheat,
+            None => return false,
+        };
+        if cheat.enabled == enabled {
+            return true;
+        }
+        cheat.enabled = enabled;
+        if enabled {
+            cheat.replaced = cheat
+                .actions
+                .iter()
+                .filter_map(|action| match action {
+                    CheatAction::Patch(addr, bytes) => {
+                        let at = *addr as usize..*addr as usize + bytes.len();
+                        let replaced = ram[at.clone()].to_vec();
+                        ram[at].copy_from_slice(bytes);
+                        Some(replaced)
+                    }
+                    CheatAction::Freeze(..) => None,
+                })
+                .collect();
+        } else {
+            let patches = cheat.actions.iter().filter_map(|action| match action {
+                CheatAction::Patch(addr, _) => Some(*addr as usize),
+                CheatAction::Freeze(..) => None,
+            });
+            // in reverse so overlapping patches come undone in the right order
+            let replaced = std::mem::take(&mut cheat.replaced);
+            for (addr, bytes) in patches.zip(replaced).collect::<Vec<_>>().iter().rev() {
+                ram[*addr..*addr + bytes.len()].copy_from_slice(bytes);
+            }
+        }
+        self.freeze_cheats();
+        self.checkpoint();
+        true
+    }
+
+    // after loading a state, which has whatever was patched when it was saved, enabled patches
+    // are written again over anything different, disabled ones are left as the state had them
+    // since there's no telling what a patch saved on would have replaced
+    pub(crate) fn resync_cheats(&mut self) {
+        let ram = &mut self.ram;
+        for cheat in self.cheats.iter_mut().filter(|cheat| cheat.enabled) {
+            let patches = cheat.actions.iter().filter_map(|action| match action {
+                CheatAction::Patch(addr, bytes) => Some((*addr as usize, bytes)),
+                CheatAction::Freeze(..) => None,
+            });
+            for ((addr, bytes), replaced) in patches.zip(cheat.replaced.iter_mut()) {
+                let at = addr..addr + bytes.len();
+                if ram[at.clone()] != bytes[..] {
+                    *replaced = ram[at.clone()].to_vec();
+                    ram[at].copy_from_slice(bytes);
+                }
+            }
+        }
+        self.freeze_cheats();
+    }
+
+    pub(crate) fn freeze_cheats(&mut self) {
+        for n in 0..self.cheats.len() {
+            if !self.cheats[n].enabled {
+                continue;
+            }
+            for a in 0..self.cheats[n].actions.len() {
+                if let CheatAction::Freeze(target, value) = self.cheats[n].actions[a] {
+                    self.set_cheat_value(target, value);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FILE: &str = "oxi8 cheats 1
+# comment
+rom 8f3a51c1f3c6d2b7 PONG
+on infinite lives: freeze 0x2F0 3
+off player 2: stands still: freeze V7 0x10; freeze V8 12
+on skip title: patch 0x204 0x12 0x40
+";
+
+    #[test]
+    fn parses() {
+        let file = CheatFile::parse(FILE).unwrap();
+        let cheats = file.cheats_for(0x8f3a51c1f3c6d2b7);
+        assert_eq!(cheats.len(), 3);
+        assert_eq!(cheats[1].name(), "player 2: stands still");
+        assert!(!cheats[1].is_enabled());
+        assert_eq!(
+            cheats[1].actions(),
+            [
+                CheatAction::Freeze(CheatTarget::V(7), 0x10),
+                CheatAction::Freeze(CheatTarget::V(8), 12),
+            ]
+        );
+        assert_eq!(
+            cheats[2].actions(),
+            [CheatAction::Patch(0x204, vec![0x12, 0x40])]
+        );
+        assert!(file.cheats_for(0).is_empty());
+    }
+
+    #[test]
+    fn round_trips() {
+        let file = CheatFile::parse(FILE).unwrap();
+        assert_eq!(CheatFile::parse(&file.to_string()).unwrap(), file);
+        assert!(file
+            .to_string()
+            .contains("on infinite lives: freeze 0x2F0 0x03\n"));
+    }
+
+    #[test]
+    fn errors() {
+        for (line, error) in [
+            ("maybe x: freeze V0 1", "start with on or off"),
+            ("on no actions", "expected NAME: ACTIONS"),
+            ("on x: freeze V0", "expected freeze TARGET VALUE"),
+            ("on x: freeze V0 1 2", "expected freeze TARGET VALUE"),
+            ("on x: freeze V0 256", "not a byte value: 256"),
+            ("on x: patch V0 1", "can only patch ram"),
+            ("on x: patch 0x200", "expected patch ADDR BYTE"),
+            ("on x: patch 0xFFF 1 2", "runs past the end of ram"),
+            ("on x: poke 0x200 1", "expected freeze or patch"),
+        ] {
+            let err = Cheat::parse(line).unwrap_err();
+            assert!(err.contains(error), "{}: {}", line, err);
+        }
+        assert!(CheatFile::parse("cheats")
+            .unwrap_err()
+            .contains("not oxi8 cheats"));
+        assert_eq!(
+            CheatFile::parse("oxi8 cheats 1\non x: freeze V0 1").unwrap_err(),
+            "line 2: cheat before any rom line"
+        );
+        assert!(CheatFile::parse("oxi8 cheats 1\nrom xyz")
+            .unwrap_err()
+            .contains("line 2: not a rom hash"));
+    }
+}
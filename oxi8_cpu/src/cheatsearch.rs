@@ -0,0 +1,253 @@
+use core::fmt;
+
+use crate::{Cpu, Display, Rand, NUM_REGISTERS, RAM_SIZE};
+
+// finding where a rom keeps something like lives or a score the way classic emulators do: start
+// with every byte of ram and V0-VF as a candidate, then play a bit and keep only the candidates
+// that changed the way the thing did, like decreased after losing a life, unchanged while
+// nothing happened, or equal to the 3 lives on screen, until few enough are left to try poking
+// each value is compared against the one kept at the last filter, not at the start
+
+// a byte a cheat can look at or change
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum CheatTarget {
+    Ram(u16),
+    V(u8),
+}
+
+impl CheatTarget {
+    // 0x2F0 style addresses, decimal too, or V0-VF in any case
+    pub fn parse(target: &str) -> Result<CheatTarget, String> {
+        let lower = target.to_lowercase();
+        if lower.len() == 2 && lower.starts_with('v') {
+            if let Ok(x) = u8::from_str_radix(&lower[1..], 16) {
+                return Ok(CheatTarget::V(x));
+            }
+        }
+        let addr = match lower.strip_prefix("0x") {
+            Some(hex) => usize::from_str_radix(hex, 16),
+            None => lower.parse(),
+        }
+        .map_err(|_| format!("not an address or V register: {}", target))?;
+        if addr >= RAM_SIZE {
+            return Err(format!("0x{:X} is past the end of ram", addr));
+        }
+        Ok(CheatTarget::Ram(addr as u16))
+    }
+}
+
+impl fmt::Display for CheatTarget {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CheatTarget::Ram(addr) => write!(f, "0x{:03X}", addr),
+            CheatTarget::V(x) => write!(f, "V{:X}", x),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CheatFilter {
+    Unchanged,
+    Changed,
+    Increased,
+    Decreased,
+    Value(u8), // equal to this now
+}
+
+impl CheatFilter {
+    // same, changed, up, down, or a number, in hex with 0x
+    pub fn parse(filter: &str) -> Result<CheatFilter, String> {
+        match filter {
+            "same" | "unchanged" => Ok(CheatFilter::Unchanged),
+            "changed" => Ok(CheatFilter::Changed),
+            "up" | "increased" => Ok(CheatFilter::Increased),
+            "down" | "decreased" => Ok(CheatFilter::Decreased),
+            _ => match filter.strip_prefix("0x") {
+                Some(hex) => u8::from_str_radix(hex, 16),
+                None => filter.parse(),
+            }
+            .map(CheatFilter::Value)
+            .map_err(|_| {
+                format!(
+                    "expected same, changed, up, down or a byte value, not {}",
+                    filter
+                )
+            }),
+        }
+    }
+
+    fn keeps(&self, was: u8, is: u8) -> bool {
+        match self {
+            CheatFilter::Unchanged => is == was,
+            CheatFilter::Changed => is != was,
+            CheatFilter::Increased => is > was,
+            CheatFilter::Decreased => is < was,
+            CheatFilter::Value(value) => is == *value,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CheatSearch {
+    candidates: Vec<(CheatTarget, u8)>, // with the value at the last filter
+    filters: usize,
+}
+
+impl CheatSearch {
+    pub fn len(&self) -> usize {
+        self.candidates.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.candidates.is_empty()
+    }
+
+    // how many filters have narrowed it down so far
+    pub fn filters(&self) -> usize {
+        self.filters
+    }
+
+    // V registers first, then ram lowest first, each with its value at the last filter
+    pub fn candidates(&self) -> &[(CheatTarget, u8)] {
+        &self.candidates
+    }
+}
+
+impl<T: Display, R: Rand> Cpu<T, R> {
+    pub fn cheat_value(&self, target: CheatTarget) -> u8 {
+        match target {
+            CheatTarget::Ram(addr) => self.ram.get(addr as usize).cloned().unwrap_or(0),
+            CheatTarget::V(x) => self.v.get(x as usize).cloned().unwrap_or(0),
+        }
+    }
+
+    pub fn set_cheat_value(&mut self, target: CheatTarget, value: u8) {
+        let byte = match target {
+            CheatTarget::Ram(addr) => self.ram.get_mut(addr as usize),
+            CheatTarget::V(x) => self.v.get_mut(x as usize),
+        };
+        if let Some(byte) = byte {
+            *byte = value;
+        }
+    }
+
+    // a new search with everything a candidate, as it is now
+    pub fn cheat_search(&self) -> CheatSearch {
+        let targets = (0..NUM_REGISTERS as u8)
+            .map(CheatTarget::V)
+            .chain((0..RAM_SIZE as u16).map(CheatTarget::Ram));
+        CheatSearch {
+            candidates: targets
+                .map(|target| (target, self.cheat_value(target)))
+                .collect(),
+            filters: 0,
+        }
+    }
+
+    // keeps only the candidates filter is true for, comparing with their last values, returns
+    // how many are left
+    pub fn cheat_filter(&self, search: &mut CheatSearch, filter: CheatFilter) -> usize {
+        search.candidates.retain_mut(|(target, was)| {
+            let is = self.cheat_value(*target);
+            let keep = filter.keeps(*was, is);
+            *was = is;
+            keep
+        });
+        search.filters += 1;
+        search.candidates.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{BoolDisplay, SeededRand};
+
+    #[test]
+    fn parses_targets() {
+        for (target, expected) in [
+            ("v0", CheatTarget::V(0)),
+            ("VF", CheatTarget::V(0xF)),
+            ("0x2F0", CheatTarget::Ram(0x2F0)),
+            ("0xfff", CheatTarget::Ram(0xFFF)),
+            ("752", CheatTarget::Ram(752)),
+        ]
+        .iter()
+        {
+            assert_eq!(CheatTarget::parse(target).as_ref(), Ok(expected));
+        }
+        assert_eq!(CheatTarget::V(0xA).to_string(), "VA");
+        assert_eq!(CheatTarget::Ram(0x2F).to_string(), "0x02F");
+        for (target, error) in [
+            ("vg", "not an address or V register: vg"),
+            ("v10", "not an address or V register: v10"),
+            ("0x1000", "0x1000 is past the end of ram"),
+            ("vé", "not an address or V register: vé"),
+            ("", "not an address or V register: "),
+        ]
+        .iter()
+        {
+            assert_eq!(CheatTarget::parse(target), Err(error.to_string()));
+        }
+    }
+
+    #[test]
+    fn parses_filters() {
+        for (filter, expected) in [
+            ("same", CheatFilter::Unchanged),
+            ("unchanged", CheatFilter::Unchanged),
+            ("changed", CheatFilter::Changed),
+            ("up", CheatFilter::Increased),
+            ("increased", CheatFilter::Increased),
+            ("down", CheatFilter::Decreased),
+            ("decreased", CheatFilter::Decreased),
+            ("3", CheatFilter::Value(3)),
+            ("0xff", CheatFilter::Value(0xFF)),
+        ]
+        .iter()
+        {
+            assert_eq!(CheatFilter::parse(filter), Ok(*expected));
+        }
+        for filter in ["256", "0x100", "-1", "Up", ""].iter() {
+            assert_eq!(
+                CheatFilter::parse(filter),
+                Err(format!(
+                    "expected same, changed, up, down or a byte value, not {}",
+                    filter
+                ))
+            );
+        }
+    }
+
+    #[test]
+    fn narrows_down() {
+        let mut cpu = Cpu::new(&[0x12, 0x00], BoolDisplay::new(1), SeededRand::new(0));
+        let mut search = cpu.cheat_search();
+        assert_eq!(search.len(), NUM_REGISTERS + RAM_SIZE);
+        assert_eq!(search.candidates()[0], (CheatTarget::V(0), 0));
+        assert_eq!(search.candidates()[16], (CheatTarget::Ram(0), 0xF0));
+        // 3 lives show up in both, and in bytes that were 3 all along
+        cpu.set_cheat_value(CheatTarget::V(5), 3);
+        cpu.set_cheat_value(CheatTarget::Ram(0x300), 3);
+        assert_eq!(cpu.cheat_filter(&mut search, CheatFilter::Changed), 2);
+        assert_eq!(cpu.cheat_filter(&mut search, CheatFilter::Value(3)), 2);
+        assert_eq!(cpu.cheat_filter(&mut search, CheatFilter::Unchanged), 2);
+        // losing one only takes it from the one that's really lives
+        cpu.set_cheat_value(CheatTarget::V(5), 4);
+        cpu.set_cheat_value(CheatTarget::Ram(0x300), 2);
+        assert_eq!(cpu.cheat_filter(&mut search, CheatFilter::Changed), 2);
+        // compared with the values at the last filter, so nothing changed since
+        assert_eq!(cpu.cheat_filter(&mut search, CheatFilter::Decreased), 0);
+        assert!(search.is_empty());
+        assert_eq!(search.filters(), 5);
+
+        let mut search = cpu.cheat_search();
+        cpu.set_cheat_value(CheatTarget::V(5), 5);
+        cpu.set_cheat_value(CheatTarget::Ram(0x300), 1);
+        assert_eq!(cpu.cheat_filter(&mut search, CheatFilter::Decreased), 1);
+        assert_eq!(search.candidates(), &[(CheatTarget::Ram(0x300), 1)][..]);
+        cpu.set_cheat_value(CheatTarget::Ram(0x300), 0);
+        assert_eq!(cpu.cheat_filter(&mut search, CheatFilter::Decreased), 1);
+        assert_eq!(cpu.cheat_value(CheatTarget::Ram(0x300)), 0);
+    }
+}
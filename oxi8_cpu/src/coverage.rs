@@ -0,0 +1,390 @@
+use core::fmt;
+use std::{collections::BTreeMap, fmt::Write, ops::Range};
+
+use crate::{Cpu, Display, Rand, RAM_SIZE};
+
+// per byte of ram, whether it was ever executed as part of an opcode, read as data by Dxyn or
+// Fx65, or written by Fx55 or Fx33 (which only writes, its BCD digits come from a register)
+// bytes only ever read are data, bytes executed are code, which is what the disassembler uses
+// to tell them apart, and bytes executed after being written are self-modifying code
+// the text format is for merging coverage over several runs of a test rom:
+//   oxi8 coverage 1
+//   0x200 1111111111111111222222220000...
+//   modified 0x20A 1234 0x2F0
+// one hex digit per byte, 1 executed + 2 read + 4 written, 64 to a row, rows of all 0 left out,
+// then a line for each SelfModification, its addr, cycle and writer
+
+const MAGIC: &str = "oxi8 coverage 1";
+const ROW_LEN: usize = 64;
+
+const EXECUTED: u8 = 1;
+const READ: u8 = 2;
+const WRITTEN: u8 = 4;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ByteCoverage {
+    pub executed: bool,
+    pub read: bool,
+    pub written: bool,
+}
+
+// the first time code that had been written was run
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SelfModification {
+    pub addr: u16,   // the instruction that ran
+    pub cycle: u64,  // Cpu::cycles() right before it ran
+    pub writer: u16, // the instruction that last wrote it
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Coverage {
+    active: bool,
+    flags: Vec<u8>,
+    writers: BTreeMap<u16, u16>, // written byte to the pc that last wrote it
+    self_modified: BTreeMap<u16, SelfModification>,
+}
+
+impl Coverage {
+    pub fn start(&mut self) {
+        self.active = true;
+        self.flags.resize(RAM_SIZE, 0);
+    }
+
+    // what was covered stays until clear()
+    pub fn stop(&mut self) {
+        self.active = false;
+    }
+
+    pub fn clear(&mut self) {
+        let active = self.active;
+        *self = Coverage::default();
+        if active {
+            self.start();
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.active
+    }
+
+    pub fn at(&self, addr: u16) -> ByteCoverage {
+        let flags = self.flags.get(addr as usize).cloned().unwrap_or(0);
+        ByteCoverage {
+            executed: flags & EXECUTED != 0,
+            read: flags & READ != 0,
+            written: flags & WRITTEN != 0,
+        }
+    }
+
+    // Some(true) for code, Some(false) for data that was read but never run, None if unknown
+    pub fn is_code(&self, addr: u16) -> Option<bool> {
+        let at = self.at(addr);
+        if at.executed {
+            Some(true)
+        } else if at.read {
+            Some(false)
+        } else {
+            None
+        }
+    }
+
+    // lowest address first
+    pub fn self_modified(&self) -> impl Iterator<Item = &SelfModification> {
+        self.self_modified.values()
+    }
+
+    // adds everything other covered, for coverage over several runs
+    pub fn merge(&mut self, other: &Coverage) {
+        if self.flags.len() < other.flags.len() {
+            self.flags.resize(other.flags.len(), 0);
+        }
+        for (flags, other) in self.flags.iter_mut().zip(other.flags.iter()) {
+            *flags |= other;
+        }
+        for (addr, modification) in other.self_modified.iter() {
+            self.self_modified.entry(*addr).or_insert(*modification);
+        }
+    }
+
+    pub fn parse(coverage: &str) -> Result<Coverage, String> {
+        let mut lines = coverage.lines();
+        if lines.next().map(str::trim) != Some(MAGIC) {
+            return Err(format!(
+                "not an oxi8 coverage map, expected '{}' first",
+                MAGIC
+            ));
+        }
+        let mut ret = Coverage::default();
+        ret.flags.resize(RAM_SIZE, 0);
+        for line in lines {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            if let Some(modification) = line.strip_prefix("modified ") {
+                let modification = parse_modification(modification)
+                    .ok_or_else(|| format!("bad self-modification in coverage map: {}", line))?;
+                ret.self_modified.insert(modification.addr, modification);
+                continue;
+            }
+            let mut words = line.split_whitespace();
+            let start = words
+                .next()
+                .and_then(|addr| addr.strip_prefix("0x"))
+                .and_then(|addr| usize::from_str_radix(addr, 16).ok())
+                .ok_or_else(|| format!("bad address in coverage map: {}", line))?;
+            let digits = words.next().unwrap_or("");
+            if start + digits.len() > RAM_SIZE {
+                return Err(format!("coverage map goes past the end of ram: {}", line));
+            }
+            for (n, digit) in digits.chars().enumerate() {
+                ret.flags[start + n] = digit
+                    .to_digit(16)
+                    .filter(|digit| *digit < 8)
+                    .ok_or_else(|| format!("bad digit '{}' in coverage map: {}", digit, line))?
+                    as u8;
+            }
+        }
+        Ok(ret)
+    }
+}
+
+impl fmt::Display for Coverage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{}", MAGIC)?;
+        for (row, flags) in self.flags.chunks(ROW_LEN).enumerate() {
+            if flags.iter().all(|flags| *flags == 0) {
+                continue;
+            }
+            write!(f, "0x{:03X} ", row * ROW_LEN)?;
+            for flags in flags {
+                write!(f, "{:X}", flags)?;
+            }
+            writeln!(f)?;
+        }
+        for modification in self.self_modified() {
+            writeln!(
+                f,
+                "modified 0x{:03X} {} 0x{:03X}",
+                modification.addr, modification.cycle, modification.writer
+            )?;
+        }
+        Ok(())
+    }
+}
+
+// ADDR CYCLE WRITER from a 'modified' line, None if any are bad
+fn parse_modification(modification: &str) -> Option<SelfModification> {
+    let hex = |word: Option<&str>| u16::from_str_radix(word?.strip_prefix("0x")?, 16).ok();
+    let mut words = modification.split_whitespace();
+    let modification = SelfModification {
+        addr: hex(words.next())?,
+        cycle: words.next()?.parse().ok()?,
+        writer: hex(words.next())?,
+    };
+    match words.next() {
+        None => Some(modification),
+        Some(_) => None,
+    }
+}
+
+impl<T: Display, R: Rand> Cpu<T, R> {
+    // called right before the instruction at pc runs
+    pub(crate) fn cover_step(&mut self) {
+        let pc = self.pc;
+        let access = self.memory_access();
+        let coverage = &mut self.coverage;
+        for addr in pc..pc.saturating_add(2) {
+            let flags = match coverage.flags.get_mut(addr as usize) {
+                Some(flags) => flags,
+                None => continue,
+            };
+            *flags |= EXECUTED;
+            if let Some(writer) = coverage.writers.get(&addr) {
+                let modification = SelfModification {
+                    addr: pc,
+                    cycle: self.cycles,
+                    writer: *writer,
+                };
+                coverage.self_modified.entry(pc).or_insert(modification);
+            }
+        }
+        for addr in access.read.unwrap_or(0..0) {
+            if let Some(flags) = coverage.flags.get_mut(addr as usize) {
+                *flags |= READ;
+            }
+        }
+        for addr in access.write.unwrap_or(0..0) {
+            if let Some(flags) = coverage.flags.get_mut(addr as usize) {
+                *flags |= WRITTEN;
+                coverage.writers.insert(addr, pc);
+            }
+        }
+    }
+
+    // how much of range, usually rom_range(), was run, read or written, and what never was
+    pub fn coverage_report(&self, range: Range<u16>) -> String {
+        let coverage = &self.coverage;
+        let len = range.len().max(1);
+        let count = |test: fn(&ByteCoverage) -> bool| {
+            range
+                .clone()
+                .filter(|addr| test(&coverage.at(*addr)))
+                .count()
+        };
+        let percent = |count: usize| count as f64 * 100.0 / len as f64;
+        let executed = count(|at| at.executed);
+        let data = count(|at| at.read && !at.executed);
+        let written = count(|at| at.written);
+        let untouched = count(|at| *at == ByteCoverage::default());
+        let mut report = String::new();
+        let _ = writeln!(
+            report,
+            "0x{:03X}-0x{:03X}, {} bytes:",
+            range.start,
+            range.end.saturating_sub(1),
+            range.len()
+        );
+        let _ = writeln!(
+            report,
+            "  executed:         {:>5} ({:.1}%)",
+            executed,
+            percent(executed)
+        );
+        let _ = writeln!(
+            report,
+            "  data, only read:  {:>5} ({:.1}%)",
+            data,
+            percent(data)
+        );
+        let _ = writeln!(
+            report,
+            "  written:          {:>5} ({:.1}%)",
+            written,
+            percent(written)
+        );
+        let _ = writeln!(
+            report,
+            "  never touched:    {:>5} ({:.1}%)",
+            untouched,
+            percent(untouched)
+        );
+
+        let mut gaps = Vec::new();
+        let mut gap_start = None;
+        for addr in range.clone() {
+            match (coverage.at(addr) == ByteCoverage::default(), gap_start) {
+                (true, None) => gap_start = Some(addr),
+                (false, Some(start)) => {
+                    gaps.push(start..addr);
+                    gap_start = None;
+                }
+                _ => (),
+            }
+        }
+        if let Some(start) = gap_start {
+            gaps.push(start..range.end);
+        }
+        if !gaps.is_empty() {
+            let _ = writeln!(report, "never touched:");
+            for gap in gaps {
+                let start = self.symbols.format(gap.start);
+                let _ = match gap.len() {
+                    1 => writeln!(report, "  {}, 1 byte", start),
+                    len => writeln!(report, "  {} - 0x{:03X}, {} bytes", start, gap.end - 1, len),
+                };
+            }
+        }
+        if coverage.self_modified.is_empty() {
+            let _ = writeln!(report, "no self-modifying code ran");
+        } else {
+            let _ = writeln!(report, "self-modifying code ran:");
+            for modification in coverage.self_modified() {
+                let _ = writeln!(
+                    report,
+                    "  {} at cycle {}, written by {}",
+                    self.symbols.format(modification.addr),
+                    modification.cycle,
+                    self.symbols.format(modification.writer)
+                );
+            }
+        }
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{BoolDisplay, SeededRand};
+
+    // writes a CLS over the jump at 0x208 then runs it
+    const MODIFY: [u8; 12] = [
+        0xA2, 0x08, // LD I, 0x208
+        0x60, 0x00, // LD V0, 0x00
+        0x61, 0xE0, // LD V1, 0xE0
+        0xF1, 0x55, // LD [I], V1
+        0x12, 0x08, // JP 0x208, a CLS by the time it runs
+        0x12, 0x0A, // JP 0x20A
+    ];
+
+    fn covered() -> Coverage {
+        let mut cpu = Cpu::new(&MODIFY, BoolDisplay::new(1), SeededRand::new(0));
+        cpu.coverage.start();
+        for _ in 0..6 {
+            cpu.execute_next_instruction();
+        }
+        cpu.coverage
+    }
+
+    #[test]
+    fn finds_self_modification() {
+        let coverage = covered();
+        let modified: Vec<&SelfModification> = coverage.self_modified().collect();
+        assert_eq!(
+            modified,
+            [&SelfModification {
+                addr: 0x208,
+                cycle: 4,
+                writer: 0x206,
+            }]
+        );
+        assert_eq!(coverage.is_code(0x208), Some(true));
+        assert!(coverage.at(0x209).written);
+    }
+
+    #[test]
+    fn round_trips() {
+        let coverage = covered();
+        let text = coverage.to_string();
+        assert!(text.ends_with("\nmodified 0x208 4 0x206\n"), "{}", text);
+        let parsed = Coverage::parse(&text).unwrap();
+        assert_eq!(parsed.to_string(), text);
+        let mut merged = Coverage::default();
+        merged.merge(&parsed);
+        assert_eq!(merged.self_modified().count(), 1);
+        assert_eq!(merged.at(0x200), coverage.at(0x200));
+    }
+
+    #[test]
+    fn errors() {
+        for (text, error) in [
+            ("coverage", "not an oxi8 coverage map"),
+            ("oxi8 coverage 1\n200 11", "bad address"),
+            ("oxi8 coverage 1\n0xFFF 11", "past the end of ram"),
+            ("oxi8 coverage 1\n0x200 18", "bad digit '8'"),
+            (
+                "oxi8 coverage 1\nmodified 0x206 x 0x204",
+                "bad self-modification",
+            ),
+            ("oxi8 coverage 1\nmodified 0x206 3", "bad self-modification"),
+            (
+                "oxi8 coverage 1\nmodified 0x206 3 0x204 1",
+                "bad self-modification",
+            ),
+        ] {
+            let err = Coverage::parse(text).unwrap_err();
+            assert!(err.contains(error), "{}: {}", text, err);
+        }
+    }
+}
@@ -0,0 +1,557 @@
+use core::{fmt, ops::Range};
+
+use crate::{Cpu, Display, Expr, Finding, LogMessage, Rand, STACK_SIZE};
+
+// the debugger core: breakpoints, watchpoints and stepping goals live in Cpu::debugger, and
+// Cpu::step() / Cpu::cycle_60hz() check them around every instruction and report why they stopped,
+// breakpoints before the instruction at their address runs, watchpoints after it touched memory
+// with nothing set none of this runs, so it costs nothing when not debugging
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Access {
+    Read,
+    Write,
+    ReadWrite, // either, for watchpoints, a stop says which one it was for with ReadWrite too
+    Execute,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Register {
+    V(u8),
+    I,
+    Pc,
+    Sp,
+    Delay,
+    Sound,
+}
+
+impl Register {
+    // v0-vf, i, pc, sp, dt/delay, st/sound, any case
+    pub fn from_name(name: &str) -> Option<Register> {
+        let name = name.to_lowercase();
+        match name.as_str() {
+            "i" => Some(Register::I),
+            "pc" => Some(Register::Pc),
+            "sp" => Some(Register::Sp),
+            "dt" | "delay" => Some(Register::Delay),
+            "st" | "sound" => Some(Register::Sound),
+            _ if name.len() == 2 && name.starts_with('v') => {
+                u8::from_str_radix(&name[1..], 16).ok().map(Register::V)
+            }
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Register {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Register::V(x) => write!(f, "V{:X}", x),
+            Register::I => write!(f, "I"),
+            Register::Pc => write!(f, "PC"),
+            Register::Sp => write!(f, "SP"),
+            Register::Delay => write!(f, "DT"),
+            Register::Sound => write!(f, "ST"),
+        }
+    }
+}
+
+// a family of opcodes like Dxyn or Fx0A, hex digits must match, x y z n and k match anything
+#[derive(Clone, Debug, PartialEq)]
+pub struct OpcodeClass {
+    name: String,
+    mask: u16,
+    value: u16,
+}
+
+impl OpcodeClass {
+    pub fn parse(class: &str) -> Result<OpcodeClass, String> {
+        if class.chars().count() != 4 {
+            return Err(format!("opcode class must be 4 characters: {}", class));
+        }
+        let mut mask = 0;
+        let mut value = 0;
+        for c in class.chars() {
+            mask <<= 4;
+            value <<= 4;
+            match c {
+                'x' | 'y' | 'z' | 'n' | 'k' => (),
+                _ => match c.to_digit(16) {
+                    Some(nibble) => {
+                        mask |= 0xF;
+                        value |= nibble as u16;
+                    }
+                    None => return Err(format!("bad opcode class: {}", class)),
+                },
+            }
+        }
+        Ok(OpcodeClass {
+            name: class.to_string(),
+            mask,
+            value,
+        })
+    }
+
+    pub fn matches(&self, opcode: u16) -> bool {
+        opcode & self.mask == self.value
+    }
+}
+
+impl fmt::Display for OpcodeClass {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name)
+    }
+}
+
+// RAM an instruction reads or writes besides fetching itself, end is exclusive
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct MemoryAccess {
+    pub read: Option<Range<u16>>,
+    pub write: Option<Range<u16>>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum StopReason {
+    Step,                         // step_into() ran its 1 instruction
+    StepOver,                     // step_over() got back from the call
+    StepOut,                      // step_out() returned from the current subroutine
+    RunTo(u16),                   // run_to() reached the address
+    Breakpoint(u16),              // about to execute an address with a breakpoint
+    Watchpoint(Access, u16),      // the first watched address that was accessed
+    Register(Register, u16, u16), // the register, its old value and its new value
+    Opcode(OpcodeClass, u16),     // about to execute an opcode in a watched class
+    Sanitizer(Finding),           // about to execute an instruction the sanitizer objects to
+}
+
+impl fmt::Display for StopReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StopReason::Step => write!(f, "step"),
+            StopReason::StepOver => write!(f, "step over"),
+            StopReason::StepOut => write!(f, "step out"),
+            StopReason::RunTo(addr) => write!(f, "reached 0x{:03X}", addr),
+            StopReason::Breakpoint(addr) => write!(f, "breakpoint at 0x{:03X}", addr),
+            StopReason::Watchpoint(access, addr) => {
+                write!(f, "{:?} watchpoint at 0x{:03X}", access, addr)
+            }
+            StopReason::Register(register, old, new) => {
+                write!(f, "{} changed from 0x{:X} to 0x{:X}", register, old, new)
+            }
+            StopReason::Opcode(class, opcode) => write!(f, "{} opcode {:04X}", class, opcode),
+            StopReason::Sanitizer(finding) => write!(f, "sanitizer: {}", finding.problem),
+        }
+    }
+}
+
+// a pc breakpoint only stops when its condition is true, and only from its hit_count'th true hit
+// on, with a log message it's a tracepoint, which logs instead of stopping
+#[derive(Clone, Debug, PartialEq)]
+pub struct Breakpoint {
+    pub addr: u16,
+    pub condition: Option<Expr>,
+    pub hit_count: u64,
+    pub log: Option<LogMessage>,
+    hits: u64,
+}
+
+impl Breakpoint {
+    pub fn new(addr: u16) -> Breakpoint {
+        Breakpoint {
+            addr,
+            condition: None,
+            hit_count: 0,
+            log: None,
+            hits: 0,
+        }
+    }
+
+    // times execution reached addr with the condition true
+    pub fn hits(&self) -> u64 {
+        self.hits
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Goal {
+    Step,
+    Return { pc: u16, depth: usize },
+    Out { depth: usize },
+    RunTo(u16),
+}
+
+#[derive(Default)]
+pub struct Debugger {
+    breakpoints: Vec<Breakpoint>,
+    watchpoints: Vec<(Range<u16>, Access)>,
+    registers: Vec<Register>,
+    opcodes: Vec<OpcodeClass>,
+    goal: Option<Goal>,
+    logs: Vec<String>,
+    stopped_at: Option<(u16, u64)>, // pc and cycles of the last stop, resuming from it runs pc
+}
+
+impl Debugger {
+    pub fn is_active(&self) -> bool {
+        self.goal.is_some()
+            || !self.breakpoints.is_empty()
+            || !self.watchpoints.is_empty()
+            || !self.registers.is_empty()
+            || !self.opcodes.is_empty()
+    }
+
+    pub fn add_breakpoint(&mut self, addr: u16) {
+        self.set_breakpoint(Breakpoint::new(addr));
+    }
+
+    // replaces any breakpoint already at the same address
+    pub fn set_breakpoint(&mut self, breakpoint: Breakpoint) {
+        self.remove_breakpoint(breakpoint.addr);
+        self.breakpoints.push(breakpoint);
+    }
+
+    pub fn remove_breakpoint(&mut self, addr: u16) {
+        self.breakpoints.retain(|b| b.addr != addr);
+    }
+
+    pub fn breakpoints(&self) -> &[Breakpoint] {
+        &self.breakpoints
+    }
+
+    pub fn add_watchpoint(&mut self, range: Range<u16>, access: Access) {
+        if !self.watchpoints.contains(&(range.clone(), access)) {
+            self.watchpoints.push((range, access));
+        }
+    }
+
+    pub fn remove_watchpoint(&mut self, range: Range<u16>, access: Access) {
+        self.watchpoints.retain(|w| *w != (range.clone(), access));
+    }
+
+    pub fn watchpoints(&self) -> &[(Range<u16>, Access)] {
+        &self.watchpoints
+    }
+
+    pub fn watch_register(&mut self, register: Register) {
+        if !self.registers.contains(&register) {
+            self.registers.push(register);
+        }
+    }
+
+    pub fn unwatch_register(&mut self, register: Register) {
+        self.registers.retain(|r| *r != register);
+    }
+
+    pub fn watched_registers(&self) -> &[Register] {
+        &self.registers
+    }
+
+    pub fn break_on_opcode(&mut self, class: OpcodeClass) {
+        if !self.opcodes.contains(&class) {
+            self.opcodes.push(class);
+        }
+    }
+
+    pub fn remove_opcode_break(&mut self, class: &OpcodeClass) {
+        self.opcodes.retain(|o| o != class);
+    }
+
+    pub fn opcode_breaks(&self) -> &[OpcodeClass] {
+        &self.opcodes
+    }
+
+    // everything tracepoints logged since the last call
+    pub fn take_logs(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.logs)
+    }
+
+    // forgets any step_*() or run_to() still in progress
+    pub fn cancel_step(&mut self) {
+        self.goal = None;
+    }
+
+    pub fn clear(&mut self) {
+        *self = Debugger::default();
+    }
+}
+
+fn overlaps(a: &Range<u16>, b: &Range<u16>) -> Option<u16> {
+    let start = a.start.max(b.start);
+    if start < a.end.min(b.end) {
+        Some(start)
+    } else {
+        None
+    }
+}
+
+impl<T: Display, R: Rand> Cpu<T, R> {
+    pub fn register(&self, register: Register) -> u16 {
+        match register {
+            Register::V(x) => self.v[x as usize & 0xF] as u16,
+            Register::I => self.i,
+            Register::Pc => self.pc,
+            Register::Sp => self.stack.sp as u16,
+            Register::Delay => self.delay as u16,
+            Register::Sound => self.sound as u16,
+        }
+    }
+
+    // values are truncated to the register's size, SP can't go past the top of the stack
+    pub fn set_register(&mut self, register: Register, value: u16) {
+        match register {
+            Register::V(x) => self.v[x as usize & 0xF] = value as u8,
+            Register::I => self.i = value,
+            Register::Pc => self.pc = value,
+            Register::Sp => self.stack.sp = (value as usize).min(STACK_SIZE),
+            Register::Delay => self.delay = value as u8,
+            Register::Sound => self.sound = value as u8,
+        }
+    }
+
+    // the RAM the next instruction will touch, worked out from the current state before it runs
+    pub fn memory_access(&self) -> MemoryAccess {
+        let instruction = self.next_instruction();
+        let i = self.i;
+        let mut access = MemoryAccess::default();
+        match (instruction.w(), instruction.yz()) {
+            (0xD, _) => {
+                let planes = self.display.selected_planes().count_selected() as u16;
+                let len = match instruction.z() {
+                    0 => 32,
+                    z => z as u16,
+                };
+                access.read = Some(i..i.saturating_add(len * planes));
+            }
+            (0xF, 0x33) => access.write = Some(i..i.saturating_add(3)),
+            (0xF, 0x55) => access.write = Some(i..i.saturating_add(instruction.x() as u16 + 1)),
+            (0xF, 0x65) => access.read = Some(i..i.saturating_add(instruction.x() as u16 + 1)),
+            _ => (),
+        }
+        access
+    }
+
+    // these only set where to stop, step() or cycle_60hz() do the actual running, so a frontend
+    // can keep drawing frames while a long step over a subroutine runs
+
+    // stop after the next instruction
+    pub fn step_into(&mut self) {
+        self.debugger.goal = Some(Goal::Step);
+    }
+
+    // like step_into(), but a 2nnn call runs until it returns
+    pub fn step_over(&mut self) {
+        self.debugger.goal = Some(if self.next_instruction().w() == 0x2 {
+            Goal::Return {
+                pc: self.pc + 2,
+                depth: self.stack.sp,
+            }
+        } else {
+            Goal::Step
+        });
+    }
+
+    // run until the current subroutine returns with 00EE
+    pub fn step_out(&mut self) {
+        self.debugger.goal = Some(Goal::Out {
+            depth: self.stack.sp,
+        });
+    }
+
+    pub fn run_to(&mut self, addr: u16) {
+        self.debugger.goal = Some(Goal::RunTo(addr));
+    }
+
+    // keeps stepping until the debugger stops or max_instructions have ran
+    pub fn run(&mut self, max_instructions: u64) -> Option<StopReason> {
+        for _ in 0..max_instructions {
+            let stop = self.step();
+            if stop.is_some() {
+                return stop;
+            }
+        }
+        None
+    }
+
+    // what is about to run, breakpoints, execute watchpoints and opcode breaks, all at pc, except
+    // right after stopping there, so resuming runs the instruction instead of stopping again
+    pub(crate) fn debug_stop(&mut self) -> Option<StopReason> {
+        if self.debugger.stopped_at.take() == Some((self.pc, self.cycles)) {
+            return None;
+        }
+        let stop = self.check_breakpoint().or_else(|| self.check_next())?;
+        Some(self.stopped(stop))
+    }
+
+    // runs the instruction at pc, then checks what it did and whether a step goal was reached
+    pub(crate) fn debug_step(&mut self) -> Option<StopReason> {
+        let access = self.memory_access();
+        let before: Vec<u16> = self
+            .debugger
+            .registers
+            .iter()
+            .map(|r| self.register(*r))
+            .collect();
+
+        self.execute_next_instruction();
+
+        let stop = self
+            .check_ran(access, before)
+            .or_else(|| self.check_goal())?;
+        Some(self.stopped(stop))
+    }
+
+    // every stop ends the step in progress and remembers where it happened
+    pub(crate) fn stopped(&mut self, stop: StopReason) -> StopReason {
+        self.debugger.goal = None;
+        self.mark_stopped();
+        stop
+    }
+
+    // resuming from here runs the instruction at pc without stopping on it first
+    pub(crate) fn mark_stopped(&mut self) {
+        self.debugger.stopped_at = Some((self.pc, self.cycles));
+    }
+
+    // conditions are checked in here, before the instruction at addr runs, and hits counted
+    fn check_breakpoint(&mut self) -> Option<StopReason> {
+        let index = self
+            .debugger
+            .breakpoints
+            .iter()
+            .position(|b| b.addr == self.pc)?;
+        let breakpoint = &self.debugger.breakpoints[index];
+        if !breakpoint
+            .condition
+            .as_ref()
+            .map_or(true, |c| c.is_true(self))
+        {
+            return None;
+        }
+        let log = breakpoint.log.as_ref().map(|log| log.format(self));
+        let breakpoint = &mut self.debugger.breakpoints[index];
+        breakpoint.hits += 1;
+        if breakpoint.hits < breakpoint.hit_count {
+            return None;
+        }
+        match log {
+            Some(log) => {
+                self.debugger.logs.push(log);
+                None
+            }
+            None => Some(StopReason::Breakpoint(self.pc)),
+        }
+    }
+
+    fn check_ran(&self, access: MemoryAccess, before: Vec<u16>) -> Option<StopReason> {
+        let debugger = &self.debugger;
+        for (range, kind) in debugger.watchpoints.iter() {
+            let read = || access.read.as_ref().and_then(|a| overlaps(a, range));
+            let write = || access.write.as_ref().and_then(|a| overlaps(a, range));
+            let accessed = match kind {
+                Access::Read => read(),
+                Access::Write => write(),
+                Access::ReadWrite => read().or_else(write),
+                Access::Execute => None,
+            };
+            if let Some(addr) = accessed {
+                return Some(StopReason::Watchpoint(*kind, addr));
+            }
+        }
+        for (register, old) in debugger.registers.iter().zip(before) {
+            let new = self.register(*register);
+            if new != old {
+                return Some(StopReason::Register(*register, old, new));
+            }
+        }
+
+        None
+    }
+
+    fn check_next(&self) -> Option<StopReason> {
+        let debugger = &self.debugger;
+        for (range, kind) in debugger.watchpoints.iter() {
+            if *kind == Access::Execute && range.contains(&self.pc) {
+                return Some(StopReason::Watchpoint(Access::Execute, self.pc));
+            }
+        }
+        let opcode = self.next_instruction().opcode();
+        debugger
+            .opcodes
+            .iter()
+            .find(|c| c.matches(opcode))
+            .map(|class| StopReason::Opcode(class.clone(), opcode))
+    }
+
+    fn check_goal(&self) -> Option<StopReason> {
+        match self.debugger.goal? {
+            Goal::Step => Some(StopReason::Step),
+            Goal::Return { pc, depth } if self.pc == pc && self.stack.sp == depth => {
+                Some(StopReason::StepOver)
+            }
+            Goal::Out { depth } if self.stack.sp < depth => Some(StopReason::StepOut),
+            Goal::RunTo(addr) if self.pc == addr => Some(StopReason::RunTo(addr)),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{BoolDisplay, SeededRand};
+
+    // sets the delay timer and waits it out, then loops on a jump to itself
+    const WAIT: [u8; 12] = [
+        0x60, 0x03, // LD V0, 3
+        0xF0, 0x15, // LD DT, V0
+        0xF1, 0x07, // LD V1, DT
+        0x31, 0x00, // SE V1, 0
+        0x12, 0x04, // JP 0x204
+        0x12, 0x0A, // JP 0x20A
+    ];
+
+    fn cpu(rom: &[u8]) -> Cpu<BoolDisplay, SeededRand> {
+        Cpu::new(rom, BoolDisplay::new(1), SeededRand::new(0))
+    }
+
+    #[test]
+    fn breakpoint_stops_before_entry() {
+        let mut cpu = cpu(&WAIT);
+        cpu.debugger.add_breakpoint(0x200);
+        assert_eq!(cpu.step(), Some(StopReason::Breakpoint(0x200)));
+        assert_eq!((cpu.pc, cpu.cycles()), (0x200, 0));
+        assert_eq!(cpu.step(), None);
+        assert_eq!((cpu.pc, cpu.cycles()), (0x202, 1));
+    }
+
+    #[test]
+    fn conditional_breakpoint_on_jump_to_itself() {
+        let mut cpu = cpu(&WAIT);
+        let mut breakpoint = Breakpoint::new(0x20A);
+        breakpoint.condition = Some(Expr::parse("frame >= 10").unwrap());
+        cpu.debugger.set_breakpoint(breakpoint);
+        assert_eq!(cpu.run(10_000), Some(StopReason::Breakpoint(0x20A)));
+        assert!(cpu.frame >= 10);
+        let cycles = cpu.cycles();
+        // resuming runs the jump, the next time around stops again
+        assert_eq!(cpu.run(10_000), Some(StopReason::Breakpoint(0x20A)));
+        assert_eq!(cpu.cycles(), cycles + 1);
+    }
+
+    #[test]
+    fn step_lands_on_breakpoint_without_stopping_twice() {
+        let mut cpu = cpu(&WAIT);
+        cpu.debugger.add_breakpoint(0x202);
+        cpu.step_into();
+        assert_eq!(cpu.step(), Some(StopReason::Step));
+        assert_eq!(cpu.pc, 0x202);
+        assert_eq!(cpu.step(), None);
+        assert_eq!(cpu.pc, 0x204);
+    }
+
+    #[test]
+    fn memory_access_saturates_at_the_top() {
+        let mut cpu = cpu(&[0xFF, 0x55]);
+        cpu.set_register(Register::I, 0xFFFE);
+        assert_eq!(cpu.memory_access().write, Some(0xFFFE..0xFFFF));
+    }
+}
@@ -0,0 +1,205 @@
+use crate::{Cpu, Display, Rand, SymbolMap};
+
+// mnemonics are the same ones the comments in Cpu::execute_instruction() use, from
+// http://devernay.free.fr/hacks/chip8/C8TECH10.HTM#3.1 plus the SCHIP and Octo additions
+// anything the Cpu would treat as a bad instruction comes out as a DW data word
+pub fn disassemble(opcode: u16) -> String {
+    disassemble_with_symbols(opcode, &SymbolMap::default())
+}
+
+// same as disassemble() but addresses in jumps, calls and LD I get labels, like 0x2D4 <score>
+pub fn disassemble_with_symbols(opcode: u16, symbols: &SymbolMap) -> String {
+    let x = (opcode >> 8) & 0xF;
+    let y = (opcode >> 4) & 0xF;
+    let z = opcode & 0xF;
+    let yz = opcode & 0xFF;
+    let xyz = opcode & 0xFFF;
+    match opcode >> 12 {
+        0x0 => match xyz {
+            0x000 => "FREEZE".to_string(),
+            0x0E0 => "CLS".to_string(),
+            0x0EE => "RET".to_string(),
+            0x0FB => "SCR".to_string(),
+            0x0FC => "SCL".to_string(),
+            0x0FD => "EXIT".to_string(),
+            0x0FE => "LOW".to_string(),
+            0x0FF => "HIGH".to_string(),
+            _ if xyz & 0xFF0 == 0x0C0 => format!("SCD {}", z),
+            // SYS, machine code routines, the Cpu can't run those
+            _ => data(opcode),
+        },
+        0x1 => format!("JP {}", symbols.format(xyz)),
+        0x2 => format!("CALL {}", symbols.format(xyz)),
+        0x3 => format!("SE V{:X}, 0x{:02X}", x, yz),
+        0x4 => format!("SNE V{:X}, 0x{:02X}", x, yz),
+        0x5 if z == 0 => format!("SE V{:X}, V{:X}", x, y),
+        0x6 => format!("LD V{:X}, 0x{:02X}", x, yz),
+        0x7 => format!("ADD V{:X}, 0x{:02X}", x, yz),
+        0x8 => {
+            let name = match z {
+                0x0 => "LD",
+                0x1 => "OR",
+                0x2 => "AND",
+                0x3 => "XOR",
+                0x4 => "ADD",
+                0x5 => "SUB",
+                0x6 => "SHR",
+                0x7 => "SUBN",
+                0xE => "SHL",
+                _ => return data(opcode),
+            };
+            format!("{} V{:X}, V{:X}", name, x, y)
+        }
+        0x9 if z == 0 => format!("SNE V{:X}, V{:X}", x, y),
+        0xA => format!("LD I, {}", symbols.format(xyz)),
+        0xB => format!("JP V0, {}", symbols.format(xyz)),
+        0xC => format!("RND V{:X}, 0x{:02X}", x, yz),
+        0xD => format!("DRW V{:X}, V{:X}, {}", x, y, z),
+        0xE => match yz {
+            0x9E => format!("SKP V{:X}", x),
+            0xA1 => format!("SKNP V{:X}", x),
+            _ => data(opcode),
+        },
+        0xF => match yz {
+            0x07 => format!("LD V{:X}, DT", x),
+            0x0A => format!("LD V{:X}, K", x),
+            0x15 => format!("LD DT, V{:X}", x),
+            0x18 => format!("LD ST, V{:X}", x),
+            0x1E => format!("ADD I, V{:X}", x),
+            0x29 => format!("LD F, V{:X}", x),
+            0x30 => format!("LD HF, V{:X}", x),
+            0x33 => format!("LD B, V{:X}", x),
+            0x55 => format!("LD [I], V{:X}", x),
+            0x65 => format!("LD V{:X}, [I]", x),
+            0x75 => format!("LD R, V{:X}", x),
+            0x85 => format!("LD V{:X}, R", x),
+            _ => data(opcode),
+        },
+        _ => data(opcode),
+    }
+}
+
+fn data(opcode: u16) -> String {
+    format!("DW 0x{:04X}", opcode)
+}
+
+impl<T: Display, R: Rand> Cpu<T, R> {
+    // opcode stored at addr, reading past the end of RAM gives 0 like everything else debug side
+    pub fn opcode_at(&self, addr: u16) -> u16 {
+        let byte = |addr: u16| self.ram.get(addr as usize).cloned().unwrap_or(0) as u16;
+        (byte(addr) << 8) | byte(addr.wrapping_add(1))
+    }
+
+    // count instructions starting at addr, as (address, opcode, mnemonic) with labels from
+    // Cpu::symbols, every one assumed to be 2 bytes so data mixed in with code comes out as
+    // garbage, same as on real hardware, unless Cpu::coverage saw it read and never run, then
+    // it's a DW data word
+    pub fn disassemble(&self, addr: u16, count: usize) -> Vec<(u16, u16, String)> {
+        (0..count)
+            .map(|n| addr.wrapping_add(n as u16 * 2))
+            .map(|addr| {
+                let opcode = self.opcode_at(addr);
+                let mnemonic = match self.coverage.is_code(addr) {
+                    Some(false) => data(opcode),
+                    _ => disassemble_with_symbols(opcode, &self.symbols),
+                };
+                (addr, opcode, mnemonic)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{BoolDisplay, SeededRand};
+
+    #[test]
+    fn disassembles_every_group() {
+        let mut symbols = SymbolMap::default();
+        symbols.insert("main", 0x200);
+        for (opcode, mnemonic) in [
+            (0x0000, "FREEZE"),
+            (0x00E0, "CLS"),
+            (0x00EE, "RET"),
+            (0x00C4, "SCD 4"),
+            (0x00FB, "SCR"),
+            (0x00FC, "SCL"),
+            (0x00FD, "EXIT"),
+            (0x00FE, "LOW"),
+            (0x00FF, "HIGH"),
+            (0x1200, "JP 0x200 <main>"),
+            (0x2204, "CALL 0x204 <main+4>"),
+            (0x3A12, "SE VA, 0x12"),
+            (0x4B34, "SNE VB, 0x34"),
+            (0x5120, "SE V1, V2"),
+            (0x6CFF, "LD VC, 0xFF"),
+            (0x7D01, "ADD VD, 0x01"),
+            (0x8120, "LD V1, V2"),
+            (0x8121, "OR V1, V2"),
+            (0x8122, "AND V1, V2"),
+            (0x8123, "XOR V1, V2"),
+            (0x8124, "ADD V1, V2"),
+            (0x8125, "SUB V1, V2"),
+            (0x8126, "SHR V1, V2"),
+            (0x8127, "SUBN V1, V2"),
+            (0x812E, "SHL V1, V2"),
+            (0x9340, "SNE V3, V4"),
+            (0xA300, "LD I, 0x300 <main+256>"),
+            (0xB200, "JP V0, 0x200 <main>"),
+            (0xC50F, "RND V5, 0x0F"),
+            (0xD125, "DRW V1, V2, 5"),
+            (0xE29E, "SKP V2"),
+            (0xE3A1, "SKNP V3"),
+            (0xF407, "LD V4, DT"),
+            (0xF40A, "LD V4, K"),
+            (0xF415, "LD DT, V4"),
+            (0xF418, "LD ST, V4"),
+            (0xF41E, "ADD I, V4"),
+            (0xF429, "LD F, V4"),
+            (0xF430, "LD HF, V4"),
+            (0xF433, "LD B, V4"),
+            (0xF455, "LD [I], V4"),
+            (0xF465, "LD V4, [I]"),
+            (0xF475, "LD R, V4"),
+            (0xF485, "LD V4, R"),
+            // everything the Cpu calls a bad instruction
+            (0x0123, "DW 0x0123"),
+            (0x00D1, "DW 0x00D1"),
+            (0x5121, "DW 0x5121"),
+            (0x8128, "DW 0x8128"),
+            (0x812F, "DW 0x812F"),
+            (0x9341, "DW 0x9341"),
+            (0xE200, "DW 0xE200"),
+            (0xF4FF, "DW 0xF4FF"),
+        ] {
+            assert_eq!(
+                disassemble_with_symbols(opcode, &symbols),
+                mnemonic,
+                "{:04X}",
+                opcode
+            );
+        }
+        assert_eq!(disassemble(0x1200), "JP 0x200");
+    }
+
+    #[test]
+    fn data_the_rom_only_reads_is_dw() {
+        let rom = [
+            0xA2, 0x04, // LD I, 0x204
+            0xF0, 0x65, // LD V0, [I]
+            0x12, 0x02, // JP 0x202, also the byte read
+        ];
+        let mut cpu = Cpu::new(&rom, BoolDisplay::new(1), SeededRand::new(0));
+        let listing = |cpu: &Cpu<BoolDisplay, SeededRand>| -> Vec<String> {
+            cpu.disassemble(0x200, 3).into_iter().map(|d| d.2).collect()
+        };
+        assert_eq!(listing(&cpu), ["LD I, 0x204", "LD V0, [I]", "JP 0x202"]);
+        cpu.coverage.start();
+        cpu.step();
+        cpu.step();
+        // read and never ran as far as coverage knows
+        assert_eq!(listing(&cpu)[2], "DW 0x1202");
+        assert_eq!(cpu.opcode_at(0xFFF), 0);
+    }
+}
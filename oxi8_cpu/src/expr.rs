@@ -0,0 +1,415 @@
+use core::fmt;
+
+use crate::{Cpu, Display, Rand, Register, SymbolMap};
+
+// a tiny expression language over Cpu state for breakpoint conditions and log messages:
+//   v3 == 0x1F && i > 0x300
+//   ram[0x2F0] != 0 || !(delay == 0)
+//   frame > 600
+// names are v0-vf, i, pc, sp, dt/delay, st/sound, frame and cycles, numbers are decimal or 0x hex
+// operators from loosest to tightest are || && then == != < <= > >= then & then + - and unary ! -
+// everything is an integer, comparisons give 1 or 0, and anything non-zero is true
+// parsed with a SymbolMap, any other name is a label's address, as in ram[score] or pc == loop
+
+#[derive(Clone, Debug, PartialEq)]
+enum Node {
+    Num(i64),
+    Register(Register),
+    Frame,
+    Cycles,
+    Ram(Box<Node>),
+    Not(Box<Node>),
+    Neg(Box<Node>),
+    Binary(Op, Box<Node>, Box<Node>),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Op {
+    Or,
+    And,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Add,
+    Sub,
+    BitAnd,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Num(i64),
+    Name(String),
+    Op(&'static str),
+}
+
+// longest first so <= isn't read as <
+const OPS: [&str; 16] = [
+    "||", "&&", "==", "!=", "<=", ">=", "<", ">", "+", "-", "&", "!", "(", ")", "[", "]",
+];
+
+fn tokenize(source: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut rest = source.trim_start();
+    while !rest.is_empty() {
+        if let Some(op) = OPS.iter().find(|op| rest.starts_with(*op)) {
+            tokens.push(Token::Op(op));
+            rest = &rest[op.len()..];
+        } else {
+            let len = rest
+                .find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+                .unwrap_or(rest.len());
+            if len == 0 {
+                // rest isn't empty, but the character might be more than one byte
+                let c = rest.chars().next().unwrap_or_default();
+                return Err(format!("unexpected '{}' in: {}", c, source));
+            }
+            let word = &rest[..len];
+            let hex = word.strip_prefix("0x").or_else(|| word.strip_prefix("0X"));
+            let number = if let Some(hex) = hex {
+                Some(i64::from_str_radix(hex, 16))
+            } else if word.starts_with(|c: char| c.is_ascii_digit()) {
+                Some(word.parse())
+            } else {
+                None
+            };
+            tokens.push(match number {
+                Some(Ok(number)) => Token::Num(number),
+                Some(Err(_)) => return Err(format!("bad number '{}' in: {}", word, source)),
+                None => Token::Name(word.to_string()),
+            });
+            rest = &rest[len..];
+        }
+        rest = rest.trim_start();
+    }
+    Ok(tokens)
+}
+
+struct Parser<'a> {
+    tokens: Vec<Token>,
+    pos: usize,
+    source: &'a str,
+    symbols: &'a SymbolMap,
+}
+
+impl<'a> Parser<'a> {
+    fn peek_op(&self) -> Option<&'static str> {
+        match self.tokens.get(self.pos) {
+            Some(Token::Op(op)) => Some(op),
+            _ => None,
+        }
+    }
+
+    fn expect(&mut self, op: &str) -> Result<(), String> {
+        if self.peek_op() == Some(op) {
+            self.pos += 1;
+            Ok(())
+        } else {
+            Err(format!("expected '{}' in: {}", op, self.source))
+        }
+    }
+
+    // each level is a list of operators that bind equally, parsed left to right
+    fn binary(&mut self, level: usize) -> Result<Node, String> {
+        const LEVELS: [&[(&str, Op)]; 5] = [
+            &[("||", Op::Or)],
+            &[("&&", Op::And)],
+            &[
+                ("==", Op::Eq),
+                ("!=", Op::Ne),
+                ("<=", Op::Le),
+                (">=", Op::Ge),
+                ("<", Op::Lt),
+                (">", Op::Gt),
+            ],
+            &[("&", Op::BitAnd)],
+            &[("+", Op::Add), ("-", Op::Sub)],
+        ];
+        if level == LEVELS.len() {
+            return self.unary();
+        }
+        let mut left = self.binary(level + 1)?;
+        while let Some(op) = self
+            .peek_op()
+            .and_then(|token| LEVELS[level].iter().find(|(name, _)| *name == token))
+        {
+            self.pos += 1;
+            let right = self.binary(level + 1)?;
+            left = Node::Binary(op.1, Box::new(left), Box::new(right));
+        }
+        Ok(left)
+    }
+
+    fn unary(&mut self) -> Result<Node, String> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        match token {
+            Some(Token::Num(number)) => Ok(Node::Num(number)),
+            Some(Token::Op("!")) => Ok(Node::Not(Box::new(self.unary()?))),
+            Some(Token::Op("-")) => Ok(Node::Neg(Box::new(self.unary()?))),
+            Some(Token::Op("(")) => {
+                let node = self.binary(0)?;
+                self.expect(")")?;
+                Ok(node)
+            }
+            // builtins are case insensitive, labels aren't
+            Some(Token::Name(name)) => match name.to_lowercase().as_str() {
+                "frame" => Ok(Node::Frame),
+                "cycles" => Ok(Node::Cycles),
+                "ram" => {
+                    self.expect("[")?;
+                    let addr = self.binary(0)?;
+                    self.expect("]")?;
+                    Ok(Node::Ram(Box::new(addr)))
+                }
+                lower => Register::from_name(lower)
+                    .map(Node::Register)
+                    .or_else(|| self.symbols.addr(&name).map(|addr| Node::Num(addr as i64)))
+                    .ok_or_else(|| format!("unknown name '{}' in: {}", name, self.source)),
+            },
+            _ => Err(format!("expected a value in: {}", self.source)),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Expr {
+    source: String,
+    root: Node,
+}
+
+impl Expr {
+    pub fn parse(source: &str) -> Result<Expr, String> {
+        Expr::parse_with_symbols(source, &SymbolMap::default())
+    }
+
+    // labels are looked up now, so changing symbols later doesn't change what this means
+    pub fn parse_with_symbols(source: &str, symbols: &SymbolMap) -> Result<Expr, String> {
+        let mut parser = Parser {
+            tokens: tokenize(source)?,
+            pos: 0,
+            source,
+            symbols,
+        };
+        let root = parser.binary(0)?;
+        if parser.pos < parser.tokens.len() {
+            return Err(format!("unexpected trailing input in: {}", source));
+        }
+        Ok(Expr {
+            source: source.trim().to_string(),
+            root,
+        })
+    }
+
+    pub fn eval<T: Display, R: Rand>(&self, cpu: &Cpu<T, R>) -> i64 {
+        eval(&self.root, cpu)
+    }
+
+    pub fn is_true<T: Display, R: Rand>(&self, cpu: &Cpu<T, R>) -> bool {
+        self.eval(cpu) != 0
+    }
+}
+
+fn eval<T: Display, R: Rand>(node: &Node, cpu: &Cpu<T, R>) -> i64 {
+    match node {
+        Node::Num(number) => *number,
+        Node::Register(register) => cpu.register(*register) as i64,
+        Node::Frame => cpu.frame() as i64,
+        Node::Cycles => cpu.cycles() as i64,
+        // reading past the end of RAM gives 0 rather than failing a breakpoint check
+        Node::Ram(addr) => {
+            let addr = eval(addr, cpu);
+            if addr < 0 {
+                0
+            } else {
+                cpu.ram().get(addr as usize).cloned().unwrap_or(0) as i64
+            }
+        }
+        Node::Not(node) => (eval(node, cpu) == 0) as i64,
+        Node::Neg(node) => eval(node, cpu).wrapping_neg(),
+        Node::Binary(op, left, right) => {
+            let left = eval(left, cpu);
+            // short circuit so ram[] on the right is only read when it matters
+            match op {
+                Op::Or if left != 0 => return 1,
+                Op::And if left == 0 => return 0,
+                _ => (),
+            }
+            let right = eval(right, cpu);
+            match op {
+                Op::Or | Op::And => (right != 0) as i64,
+                Op::Eq => (left == right) as i64,
+                Op::Ne => (left != right) as i64,
+                Op::Lt => (left < right) as i64,
+                Op::Le => (left <= right) as i64,
+                Op::Gt => (left > right) as i64,
+                Op::Ge => (left >= right) as i64,
+                Op::Add => left.wrapping_add(right),
+                Op::Sub => left.wrapping_sub(right),
+                Op::BitAnd => left & right,
+            }
+        }
+    }
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.source)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum LogPart {
+    Text(String),
+    Value(Expr, bool), // true to print in hex
+}
+
+// text with expressions in braces, like "v3 is {v3} and i is {i:x}", :x prints in hex
+#[derive(Clone, Debug, PartialEq)]
+pub struct LogMessage {
+    source: String,
+    parts: Vec<LogPart>,
+}
+
+impl LogMessage {
+    pub fn parse(source: &str) -> Result<LogMessage, String> {
+        LogMessage::parse_with_symbols(source, &SymbolMap::default())
+    }
+
+    pub fn parse_with_symbols(source: &str, symbols: &SymbolMap) -> Result<LogMessage, String> {
+        let mut parts = Vec::new();
+        let mut rest = source;
+        while let Some(start) = rest.find('{') {
+            if start > 0 {
+                parts.push(LogPart::Text(rest[..start].to_string()));
+            }
+            let end = rest[start..]
+                .find('}')
+                .ok_or_else(|| format!("missing '}}' in log message: {}", source))?
+                + start;
+            let inside = &rest[start + 1..end];
+            let (expr, hex) = match inside.strip_suffix(":x") {
+                Some(expr) => (expr, true),
+                None => (inside, false),
+            };
+            parts.push(LogPart::Value(
+                Expr::parse_with_symbols(expr, symbols)?,
+                hex,
+            ));
+            rest = &rest[end + 1..];
+        }
+        if !rest.is_empty() {
+            parts.push(LogPart::Text(rest.to_string()));
+        }
+        Ok(LogMessage {
+            source: source.to_string(),
+            parts,
+        })
+    }
+
+    pub fn format<T: Display, R: Rand>(&self, cpu: &Cpu<T, R>) -> String {
+        let mut message = String::new();
+        for part in self.parts.iter() {
+            match part {
+                LogPart::Text(text) => message.push_str(text),
+                LogPart::Value(expr, true) => message.push_str(&format!("{:X}", expr.eval(cpu))),
+                LogPart::Value(expr, false) => message.push_str(&expr.eval(cpu).to_string()),
+            }
+        }
+        message
+    }
+}
+
+impl fmt::Display for LogMessage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.source)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{BoolDisplay, SeededRand};
+
+    fn cpu() -> Cpu<BoolDisplay, SeededRand> {
+        let mut cpu = Cpu::new(&[], BoolDisplay::new(1), SeededRand::new(0));
+        cpu.set_register(Register::V(3), 0x1F);
+        cpu.set_register(Register::I, 0x300);
+        cpu
+    }
+
+    fn eval(source: &str) -> i64 {
+        Expr::parse(source).unwrap().eval(&cpu())
+    }
+
+    #[test]
+    fn precedence() {
+        assert_eq!(eval("1 + 2 & 6"), 2);
+        assert_eq!(eval("6 & 1 + 2"), 2);
+        assert_eq!(eval("v3 & 0x10 == 0x10"), 1);
+        assert_eq!(eval("v3 == 0X1f"), 1);
+        assert_eq!(eval("1 == 1 && 2 < 1 || 3 > 2"), 1);
+        assert_eq!(eval("10 - 3 - 2"), 5);
+        assert_eq!(eval("(1 + 2) & 6"), 2);
+        assert_eq!(eval("!v3 || !0"), 1);
+    }
+
+    #[test]
+    fn unary_minus() {
+        assert_eq!(eval("-1"), -1);
+        assert_eq!(eval("2 - -3"), 5);
+        assert_eq!(eval("-(i - 0x301)"), 1);
+        assert_eq!(eval("v3 == -(-0x1F)"), 1);
+    }
+
+    #[test]
+    fn names_and_ram() {
+        let mut symbols = SymbolMap::default();
+        symbols.insert("score", 0x2F0);
+        let expr = Expr::parse_with_symbols("V3 + ram[score] + I", &symbols).unwrap();
+        assert_eq!(expr.eval(&cpu()), 0x1F + 0x300);
+        assert_eq!(eval("ram[0]"), 0xF0);
+        assert_eq!(eval("ram[-1] + ram[0x10000]"), 0);
+        assert_eq!(eval("frame + cycles"), 0);
+    }
+
+    #[test]
+    fn errors() {
+        for (source, error) in [
+            ("v3 ==", "expected a value"),
+            ("(1 + 2", "expected ')'"),
+            ("ram 1", "expected '['"),
+            ("1 2", "trailing input"),
+            ("0xZZ", "bad number"),
+            ("v3 $ 1", "unexpected '$'"),
+            ("v0 == é", "unexpected 'é'"),
+            ("score", "unknown name 'score'"),
+        ] {
+            let err = Expr::parse(source).unwrap_err();
+            assert!(err.contains(error), "{}: {}", source, err);
+        }
+    }
+
+    #[test]
+    fn round_trips() {
+        let expr = Expr::parse("  v3 == 0x1F && -i < 0 ").unwrap();
+        assert_eq!(expr.to_string(), "v3 == 0x1F && -i < 0");
+        assert_eq!(Expr::parse(&expr.to_string()).unwrap(), expr);
+    }
+
+    #[test]
+    fn log_messages() {
+        let message = LogMessage::parse("v3 is {v3}, i is {i:x}{v3 - 0x20}!").unwrap();
+        assert_eq!(message.format(&cpu()), "v3 is 31, i is 300-1!");
+        assert_eq!(message.to_string(), "v3 is {v3}, i is {i:x}{v3 - 0x20}!");
+        assert_eq!(LogMessage::parse(&message.to_string()).unwrap(), message);
+        assert!(LogMessage::parse("{v3")
+            .unwrap_err()
+            .contains("missing '}'"));
+        assert!(LogMessage::parse("{nope}")
+            .unwrap_err()
+            .contains("unknown name"));
+    }
+}
@@ -0,0 +1,601 @@
+use std::{
+    io::{self, ErrorKind, Read, Write},
+    net::{SocketAddr, TcpListener, ToSocketAddrs},
+    thread,
+    time::Duration,
+};
+
+use crate::{Access, Cpu, Display, Rand, Register, StopReason};
+
+// a GDB remote serial protocol stub, so any GDB frontend can debug a running Cpu:
+//   g/G/p/P   registers, in order V0-VF, I, PC, SP, DT, ST, I and PC are 2 bytes little endian
+//   m/M       the 4 KiB of RAM
+//   Z0/z0     breakpoints, Z2-Z4/z2-z4 write, read and access watchpoints
+//   c/s       continue and step, ctrl-c interrupts
+// gdb has no CHIP-8 architecture, the register layout is described to it in target.xml
+
+const REGISTERS: [(Register, usize); 21] = [
+    (Register::V(0x0), 1),
+    (Register::V(0x1), 1),
+    (Register::V(0x2), 1),
+    (Register::V(0x3), 1),
+    (Register::V(0x4), 1),
+    (Register::V(0x5), 1),
+    (Register::V(0x6), 1),
+    (Register::V(0x7), 1),
+    (Register::V(0x8), 1),
+    (Register::V(0x9), 1),
+    (Register::V(0xA), 1),
+    (Register::V(0xB), 1),
+    (Register::V(0xC), 1),
+    (Register::V(0xD), 1),
+    (Register::V(0xE), 1),
+    (Register::V(0xF), 1),
+    (Register::I, 2),
+    (Register::Pc, 2),
+    (Register::Sp, 1),
+    (Register::Delay, 1),
+    (Register::Sound, 1),
+];
+
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+<feature name="org.oxi8.chip8">
+<reg name="v0" bitsize="8"/><reg name="v1" bitsize="8"/><reg name="v2" bitsize="8"/>
+<reg name="v3" bitsize="8"/><reg name="v4" bitsize="8"/><reg name="v5" bitsize="8"/>
+<reg name="v6" bitsize="8"/><reg name="v7" bitsize="8"/><reg name="v8" bitsize="8"/>
+<reg name="v9" bitsize="8"/><reg name="va" bitsize="8"/><reg name="vb" bitsize="8"/>
+<reg name="vc" bitsize="8"/><reg name="vd" bitsize="8"/><reg name="ve" bitsize="8"/>
+<reg name="vf" bitsize="8"/>
+<reg name="i" bitsize="16" type="data_ptr"/>
+<reg name="pc" bitsize="16" type="code_ptr"/>
+<reg name="sp" bitsize="8"/>
+<reg name="dt" bitsize="8"/>
+<reg name="st" bitsize="8"/>
+</feature>
+</target>
+"#;
+
+const INTERRUPT: u8 = 0x03;
+
+// what gdb is connected over, a non-blocking TcpStream outside of tests
+trait Stream: Read + Write + Send {}
+
+impl<S: Read + Write + Send> Stream for S {}
+
+pub struct GdbServer {
+    listener: TcpListener,
+    client: Option<Box<dyn Stream>>,
+    input: Vec<u8>,
+    running: bool, // whether the Cpu should be ran, always true with nothing connected
+}
+
+impl GdbServer {
+    pub fn bind<A: ToSocketAddrs>(addr: A) -> io::Result<GdbServer> {
+        let listener = TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+        Ok(GdbServer {
+            listener,
+            client: None,
+            input: Vec::new(),
+            running: true,
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.client.is_some()
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.running
+    }
+
+    // never blocks, accepts a new connection and handles whatever packets arrived since the last
+    // call, this should be called before every frame, which should only be ran if it returns true
+    // a newly connected gdb halts the Cpu, a disconnect clears the debugger and resumes it
+    pub fn poll<T: Display, R: Rand>(&mut self, cpu: &mut Cpu<T, R>) -> bool {
+        if self.poll_client(cpu).is_err() {
+            self.disconnect(cpu);
+        }
+        self.running
+    }
+
+    // everything Cpu::step() or Cpu::cycle_60hz() returns while running must be passed in here
+    pub fn stopped(&mut self, stop: &StopReason) {
+        if self.client.is_some() && self.running {
+            self.running = false;
+            // a failed send shows up as a disconnect on the next poll()
+            let _ = self.send(&stop_reply(stop));
+        }
+    }
+
+    // for headless hosts, waits for gdb to connect then runs the Cpu as fast as it can whenever
+    // gdb says to, returns when gdb detaches or disconnects
+    pub fn serve<T: Display, R: Rand>(&mut self, cpu: &mut Cpu<T, R>) {
+        while !self.is_connected() {
+            self.poll(cpu);
+            thread::sleep(Duration::from_millis(10));
+        }
+        while self.is_connected() {
+            if !self.poll(cpu) {
+                thread::sleep(Duration::from_millis(1));
+            } else if let Some(stop) = cpu.cycle_60hz() {
+                self.stopped(&stop);
+            }
+        }
+    }
+
+    fn connect<T: Display, R: Rand>(&mut self, client: Box<dyn Stream>, cpu: &mut Cpu<T, R>) {
+        self.client = Some(client);
+        self.running = false;
+        cpu.debugger.cancel_step();
+        cpu.mark_stopped();
+    }
+
+    fn disconnect<T: Display, R: Rand>(&mut self, cpu: &mut Cpu<T, R>) {
+        self.client = None;
+        self.input.clear();
+        self.running = true;
+        cpu.debugger.clear();
+    }
+
+    fn poll_client<T: Display, R: Rand>(&mut self, cpu: &mut Cpu<T, R>) -> io::Result<()> {
+        if self.client.is_none() {
+            match self.listener.accept() {
+                Ok((client, _)) => {
+                    client.set_nonblocking(true)?;
+                    client.set_nodelay(true)?;
+                    self.connect(Box::new(client), cpu);
+                }
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => return Ok(()),
+                Err(e) => return Err(e),
+            }
+        }
+        let mut buf = [0; 4096];
+        while let Some(client) = self.client.as_mut() {
+            match client.read(&mut buf) {
+                Ok(0) => return Err(ErrorKind::UnexpectedEof.into()),
+                Ok(len) => self.input.extend_from_slice(&buf[..len]),
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e),
+            }
+        }
+        while let Some(packet) = self.next_packet()? {
+            self.handle(&packet, cpu)?;
+        }
+        Ok(())
+    }
+
+    // pulls the next complete packet out of input, acking it, or nacking it so gdb sends it again
+    // if the checksum is wrong, acks from gdb are ignored
+    fn next_packet(&mut self) -> io::Result<Option<String>> {
+        loop {
+            match self.input.first() {
+                None => return Ok(None),
+                Some(&INTERRUPT) => {
+                    self.input.remove(0);
+                    if self.running {
+                        self.running = false;
+                        self.send("S02")?;
+                    }
+                }
+                Some(b'$') => {
+                    let end = match self.input.iter().position(|b| *b == b'#') {
+                        Some(end) if self.input.len() >= end + 3 => end,
+                        _ => return Ok(None), // wait for the rest of it
+                    };
+                    let checksum = String::from_utf8_lossy(&self.input[end + 1..end + 3]);
+                    let checksum = u8::from_str_radix(&checksum, 16).ok();
+                    let sum = self.input[1..end]
+                        .iter()
+                        .fold(0u8, |sum, b| sum.wrapping_add(*b));
+                    let packet = String::from_utf8_lossy(&self.input[1..end]).to_string();
+                    self.input.drain(..end + 3);
+                    if checksum != Some(sum) {
+                        self.write(b"-")?;
+                        continue;
+                    }
+                    self.write(b"+")?;
+                    return Ok(Some(packet));
+                }
+                Some(_) => {
+                    self.input.remove(0);
+                }
+            }
+        }
+    }
+
+    fn handle<T: Display, R: Rand>(&mut self, packet: &str, cpu: &mut Cpu<T, R>) -> io::Result<()> {
+        let command = packet.get(..1).unwrap_or("");
+        let args = packet.get(1..).unwrap_or("");
+        let reply = match command {
+            "?" => "S05".to_string(),
+            "g" => REGISTERS
+                .iter()
+                .map(|(register, size)| to_hex(&le_bytes(cpu.register(*register), *size)))
+                .collect(),
+            "G" => {
+                let bytes = from_hex(args);
+                let len: usize = REGISTERS.iter().map(|r| r.1).sum();
+                match bytes {
+                    Some(ref bytes) if bytes.len() == len => {
+                        let mut offset = 0;
+                        for (register, size) in REGISTERS.iter() {
+                            let value = from_le_bytes(&bytes[offset..offset + size]);
+                            cpu.set_register(*register, value);
+                            offset += size;
+                        }
+                        "OK".to_string()
+                    }
+                    _ => "E01".to_string(),
+                }
+            }
+            "p" => match parse_hex(args).and_then(|n| REGISTERS.get(n)) {
+                Some((register, size)) => to_hex(&le_bytes(cpu.register(*register), *size)),
+                None => "E01".to_string(),
+            },
+            "P" => {
+                let mut parts = args.splitn(2, '=');
+                let register = parts
+                    .next()
+                    .and_then(parse_hex)
+                    .and_then(|n| REGISTERS.get(n));
+                match (register, parts.next().and_then(from_hex)) {
+                    (Some((register, size)), Some(ref bytes)) if bytes.len() == *size => {
+                        cpu.set_register(*register, from_le_bytes(bytes));
+                        "OK".to_string()
+                    }
+                    _ => "E01".to_string(),
+                }
+            }
+            "m" => match parse_range(args, cpu.ram().len()) {
+                Some((addr, len)) => to_hex(&cpu.ram()[addr..addr + len]),
+                None => "E01".to_string(),
+            },
+            "M" => {
+                let mut parts = args.splitn(2, ':');
+                let range = parts.next().and_then(|r| parse_range(r, cpu.ram().len()));
+                match (range, parts.next().and_then(from_hex)) {
+                    (Some((addr, len)), Some(ref bytes)) if bytes.len() == len => {
+                        cpu.ram_mut()[addr..addr + len].copy_from_slice(bytes);
+                        "OK".to_string()
+                    }
+                    _ => "E01".to_string(),
+                }
+            }
+            "c" => {
+                if let Some(addr) = parse_hex(args) {
+                    cpu.set_register(Register::Pc, addr as u16);
+                }
+                self.running = true;
+                return Ok(());
+            }
+            "s" => {
+                if let Some(addr) = parse_hex(args) {
+                    cpu.set_register(Register::Pc, addr as u16);
+                }
+                cpu.step_into();
+                stop_reply(&cpu.step().unwrap_or(StopReason::Step))
+            }
+            "Z" | "z" => {
+                let insert = command == "Z";
+                let mut parts = args.split(',');
+                let kind = parts.next();
+                let addr = parts.next().and_then(parse_hex).map(|a| a as u16);
+                let len = parts.next().and_then(parse_hex).unwrap_or(1).max(1) as u16;
+                let access = match kind {
+                    Some("2") => Some(Access::Write),
+                    Some("3") => Some(Access::Read),
+                    Some("4") => Some(Access::ReadWrite),
+                    _ => None,
+                };
+                match (kind, addr, access) {
+                    (Some("0"), Some(addr), _) | (Some("1"), Some(addr), _) => {
+                        if insert {
+                            cpu.debugger.add_breakpoint(addr);
+                        } else {
+                            cpu.debugger.remove_breakpoint(addr);
+                        }
+                        "OK".to_string()
+                    }
+                    (_, Some(addr), Some(access)) => {
+                        let range = addr..addr.saturating_add(len);
+                        if insert {
+                            cpu.debugger.add_watchpoint(range, access);
+                        } else {
+                            cpu.debugger.remove_watchpoint(range, access);
+                        }
+                        "OK".to_string()
+                    }
+                    _ => String::new(),
+                }
+            }
+            "k" => {
+                self.disconnect(cpu);
+                return Ok(());
+            }
+            "D" => {
+                self.send("OK")?;
+                self.disconnect(cpu);
+                return Ok(());
+            }
+            "H" => "OK".to_string(),
+            "q" if packet.starts_with("qSupported") => {
+                "PacketSize=1000;qXfer:features:read+".to_string()
+            }
+            "q" if packet == "qAttached" => "1".to_string(),
+            "q" if packet == "qC" => "QC1".to_string(),
+            "q" if packet == "qfThreadInfo" => "m1".to_string(),
+            "q" if packet == "qsThreadInfo" => "l".to_string(),
+            "q" if packet.starts_with("qXfer:features:read:target.xml:") => {
+                let range = &packet["qXfer:features:read:target.xml:".len()..];
+                let mut parts = range.split(',').map(parse_hex);
+                match (parts.next().flatten(), parts.next().flatten()) {
+                    (Some(offset), Some(len)) => {
+                        let xml = TARGET_XML.as_bytes();
+                        let start = offset.min(xml.len());
+                        let end = (start + len).min(xml.len());
+                        let more = if end < xml.len() { "m" } else { "l" };
+                        format!("{}{}", more, String::from_utf8_lossy(&xml[start..end]))
+                    }
+                    _ => "E01".to_string(),
+                }
+            }
+            // an empty reply tells gdb this packet isn't supported
+            _ => String::new(),
+        };
+        self.send(&reply)
+    }
+
+    fn send(&mut self, data: &str) -> io::Result<()> {
+        let checksum = data.bytes().fold(0u8, |sum, b| sum.wrapping_add(b));
+        self.write(format!("${}#{:02x}", data, checksum).as_bytes())
+    }
+
+    // the client is non-blocking, but a reply must go out whole
+    fn write(&mut self, mut bytes: &[u8]) -> io::Result<()> {
+        while let Some(client) = self.client.as_mut().filter(|_| !bytes.is_empty()) {
+            match client.write(bytes) {
+                Ok(0) => return Err(ErrorKind::WriteZero.into()),
+                Ok(len) => bytes = &bytes[len..],
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
+                    thread::sleep(Duration::from_millis(1))
+                }
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => (),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
+
+fn stop_reply(stop: &StopReason) -> String {
+    match stop {
+        StopReason::Watchpoint(Access::Write, addr) => format!("T05watch:{:x};", addr),
+        StopReason::Watchpoint(Access::Read, addr) => format!("T05rwatch:{:x};", addr),
+        StopReason::Watchpoint(Access::ReadWrite, addr) => format!("T05awatch:{:x};", addr),
+        _ => "S05".to_string(),
+    }
+}
+
+fn le_bytes(value: u16, size: usize) -> Vec<u8> {
+    value.to_le_bytes()[..size].to_vec()
+}
+
+fn from_le_bytes(bytes: &[u8]) -> u16 {
+    bytes
+        .iter()
+        .rev()
+        .fold(0, |value, b| (value << 8) | *b as u16)
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn parse_hex(hex: &str) -> Option<usize> {
+    usize::from_str_radix(hex, 16).ok()
+}
+
+// addr,len in hex, cut short at the end of RAM
+fn parse_range(range: &str, ram_len: usize) -> Option<(usize, usize)> {
+    let mut parts = range.split(',').map(parse_hex);
+    match (parts.next().flatten(), parts.next().flatten()) {
+        (Some(addr), Some(len)) if addr < ram_len => Some((addr, len.min(ram_len - addr))),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::{BoolDisplay, SeededRand};
+
+    const ROM: [u8; 8] = [
+        0x60, 0x12, // LD V0, 0x12
+        0xA3, 0x00, // LD I, 0x300
+        0xF0, 0x55, // LD [I], V0
+        0x12, 0x06, // JP 0x206
+    ];
+
+    // an in-memory connection, what gdb sent that hasn't been read yet and what came back
+    #[derive(Clone, Default)]
+    struct Pipe(Arc<Mutex<(Vec<u8>, Vec<u8>)>>);
+
+    impl Read for Pipe {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let sent = &mut self.0.lock().unwrap().0;
+            if sent.is_empty() {
+                return Err(ErrorKind::WouldBlock.into());
+            }
+            let len = buf.len().min(sent.len());
+            buf[..len].copy_from_slice(&sent[..len]);
+            sent.drain(..len);
+            Ok(len)
+        }
+    }
+
+    impl Write for Pipe {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().1.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    struct Gdb {
+        server: GdbServer,
+        pipe: Pipe,
+        cpu: Cpu<BoolDisplay, SeededRand>,
+    }
+
+    impl Gdb {
+        fn connect() -> Gdb {
+            let mut gdb = Gdb {
+                server: GdbServer::bind("127.0.0.1:0").unwrap(),
+                pipe: Pipe::default(),
+                cpu: Cpu::new(&ROM, BoolDisplay::new(1), SeededRand::new(0)),
+            };
+            gdb.server.connect(Box::new(gdb.pipe.clone()), &mut gdb.cpu);
+            gdb
+        }
+
+        // sends raw bytes and returns everything that came back
+        fn raw(&mut self, bytes: &[u8]) -> String {
+            self.pipe.0.lock().unwrap().0.extend_from_slice(bytes);
+            self.server.poll(&mut self.cpu);
+            let received = std::mem::take(&mut self.pipe.0.lock().unwrap().1);
+            String::from_utf8(received).unwrap()
+        }
+
+        // sends a packet and returns the reply, checking it's acked and framed right
+        fn send(&mut self, packet: &str) -> String {
+            let received = self.raw(framed(packet).as_bytes());
+            let reply = received
+                .strip_prefix("+$")
+                .unwrap_or_else(|| panic!("{} got {}", packet, received));
+            let (reply, checksum) = reply.split_at(reply.len() - 3);
+            assert_eq!(checksum, &framed(reply)[reply.len() + 1..]);
+            reply.to_string()
+        }
+    }
+
+    fn framed(packet: &str) -> String {
+        let checksum = packet.bytes().fold(0u8, |sum, b| sum.wrapping_add(b));
+        format!("${}#{:02x}", packet, checksum)
+    }
+
+    #[test]
+    fn frames_and_checks_packets() {
+        let mut gdb = Gdb::connect();
+        assert!(!gdb.server.is_running());
+        assert_eq!(gdb.raw(b"$?#3f"), "+$S05#b8");
+        assert_eq!(gdb.raw(b"$?#3e"), "-");
+        assert_eq!(gdb.raw(b"$?#zz"), "-");
+        // acks and noise before a packet are skipped, half a packet waits for the rest
+        assert_eq!(gdb.raw(b"+-x$?#"), "");
+        assert_eq!(gdb.raw(b"3f$?#3f"), "+$S05#b8+$S05#b8");
+        // unknown packets get an empty reply
+        assert_eq!(gdb.send("vMustReplyEmpty"), "");
+        assert_eq!(gdb.raw(framed("c").as_bytes()), "+");
+        assert!(gdb.server.is_running());
+        assert_eq!(gdb.raw(&[INTERRUPT]), framed("S02"));
+        assert!(!gdb.server.is_running());
+    }
+
+    #[test]
+    fn reads_and_writes_registers() {
+        let mut gdb = Gdb::connect();
+        gdb.cpu.set_register(Register::V(0), 0x12);
+        gdb.cpu.set_register(Register::I, 0x234);
+        let registers = gdb.send("g");
+        // V0-VF, I and PC little endian, SP, DT, ST
+        assert_eq!(registers.len(), 46);
+        assert_eq!(&registers[..4], "1200");
+        assert_eq!(&registers[32..], "34020002000000");
+        let mut changed = registers.clone();
+        changed.replace_range(2..4, "ab");
+        assert_eq!(gdb.send(&format!("G{}", changed)), "OK");
+        assert_eq!(gdb.cpu.register(Register::V(1)), 0xAB);
+        assert_eq!(gdb.send(&format!("G{}", &changed[2..])), "E01");
+        assert_eq!(gdb.send("p10"), "3402");
+        assert_eq!(gdb.send("P11=0603"), "OK");
+        assert_eq!(gdb.cpu.register(Register::Pc), 0x306);
+        assert_eq!(gdb.send("P11=06"), "E01");
+        assert_eq!(gdb.send("p15"), "E01");
+    }
+
+    #[test]
+    fn reads_and_writes_memory() {
+        let mut gdb = Gdb::connect();
+        assert_eq!(gdb.send("m200,4"), "6012a300");
+        assert_eq!(gdb.send("m200,0"), "");
+        // cut short at the end of ram
+        assert_eq!(gdb.send("mffe,4"), "0000");
+        assert_eq!(gdb.send("m1000,1"), "E01");
+        assert_eq!(gdb.send("M300,2:abcd"), "OK");
+        assert_eq!(gdb.cpu.ram()[0x300..0x302], [0xAB, 0xCD]);
+        assert_eq!(gdb.send("M300,0:"), "OK");
+        assert_eq!(gdb.send("M300,2:ab"), "E01");
+        assert_eq!(gdb.send("Mfff,2:abcd"), "E01");
+    }
+
+    #[test]
+    fn sets_breakpoints_and_watchpoints() {
+        let mut gdb = Gdb::connect();
+        assert_eq!(gdb.send("Z0,202,2"), "OK");
+        assert_eq!(gdb.cpu.debugger.breakpoints()[0].addr, 0x202);
+        assert_eq!(gdb.send("z0,202,2"), "OK");
+        assert!(gdb.cpu.debugger.breakpoints().is_empty());
+        assert_eq!(gdb.send("Z2,300,2"), "OK");
+        assert_eq!(
+            gdb.cpu.debugger.watchpoints(),
+            [(0x300..0x302, Access::Write)]
+        );
+        assert_eq!(gdb.send("z2,300,2"), "OK");
+        assert_eq!(gdb.send("Z9,300,1"), "");
+        assert_eq!(gdb.send("Z4,300,1"), "OK");
+        assert_eq!(gdb.raw(framed("c").as_bytes()), "+");
+        let stop = gdb.cpu.cycle_60hz().unwrap();
+        gdb.server.stopped(&stop);
+        assert_eq!(gdb.raw(b""), framed("T05awatch:300;"));
+        // right after the write
+        assert_eq!(gdb.cpu.register(Register::Pc), 0x206);
+    }
+
+    #[test]
+    fn sends_target_xml() {
+        let mut gdb = Gdb::connect();
+        assert!(gdb
+            .send("qSupported:swbreak+")
+            .contains("qXfer:features:read+"));
+        assert_eq!(
+            gdb.send("qXfer:features:read:target.xml:0,10"),
+            "m<?xml version=\"1"
+        );
+        let rest = gdb.send("qXfer:features:read:target.xml:10,1000");
+        assert_eq!(rest, format!("l{}", &TARGET_XML[0x10..]));
+        assert_eq!(gdb.send("qXfer:features:read:target.xml:1000,10"), "l");
+        assert_eq!(gdb.send("qXfer:features:read:target.xml:x"), "E01");
+    }
+}
@@ -0,0 +1,126 @@
+use core::fmt;
+
+use crate::Display;
+
+// the sequence of Display::frame_hash() values of a run, one per 60hz frame,
+// so CI can compare whole runs without storing thousands of images
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FrameHashes {
+    hashes: Vec<u64>,
+}
+
+impl FrameHashes {
+    pub fn new() -> FrameHashes {
+        FrameHashes { hashes: Vec::new() }
+    }
+
+    // this should be called once per 60hz frame, right after Cpu::cycle_60hz
+    pub fn capture<T: Display>(&mut self, display: &T) {
+        self.hashes.push(display.frame_hash());
+    }
+
+    pub fn hashes(&self) -> &[u64] {
+        &self.hashes
+    }
+
+    // index of the first frame that differs, or the length of the shorter run if one is a prefix of the other
+    pub fn first_mismatch(&self, other: &FrameHashes) -> Option<usize> {
+        if self == other {
+            return None;
+        }
+        self.hashes
+            .iter()
+            .zip(other.hashes.iter())
+            .position(|(a, b)| a != b)
+            .or_else(|| Some(self.hashes.len().min(other.hashes.len())))
+    }
+
+    // one hex hash per line, the same format Display writes
+    pub fn parse(hashes: &str) -> Result<FrameHashes, String> {
+        hashes
+            .lines()
+            .map(|line| line.trim())
+            .filter(|line| !line.is_empty())
+            .map(|line| {
+                u64::from_str_radix(line, 16).map_err(|_| format!("bad frame hash: {}", line))
+            })
+            .collect::<Result<Vec<u64>, String>>()
+            .map(|hashes| FrameHashes { hashes })
+    }
+}
+
+impl fmt::Display for FrameHashes {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for hash in self.hashes.iter() {
+            writeln!(f, "{:016x}", hash)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::BoolDisplay;
+
+    // a lores frame, then a hires one, each with a sprite wrapping around the corner
+    fn frames() -> Vec<BoolDisplay> {
+        let sprite = [0xF0, 0x90, 0x90, 0x90, 0xF0];
+        let mut lores = BoolDisplay::new(1);
+        lores.draw(1, 2, &sprite);
+        lores.draw(60, 30, &sprite);
+        let mut hires = BoolDisplay::new(1);
+        hires.set_hires(true);
+        hires.draw(1, 2, &sprite);
+        hires.draw(124, 62, &sprite);
+        vec![BoolDisplay::new(1), lores, hires]
+    }
+
+    // CI keeps these, so if this fails the hash changed, not the test
+    #[test]
+    fn frame_hashes_never_change() {
+        let hashes: Vec<u64> = frames().iter().map(Display::frame_hash).collect();
+        assert_eq!(
+            hashes,
+            [
+                0x8606_2d4c_2008_33df,
+                0x54ea_09a3_08b3_828c,
+                0x1dc2_62d8_a62d_7edf
+            ]
+        );
+    }
+
+    #[test]
+    fn round_trips() {
+        let mut hashes = FrameHashes::new();
+        for frame in frames() {
+            hashes.capture(&frame);
+        }
+        let text = hashes.to_string();
+        assert!(text.starts_with("86062d4c200833df\n54ea09a308b3828c\n"));
+        assert_eq!(FrameHashes::parse(&text).unwrap(), hashes);
+        assert_eq!(
+            FrameHashes::parse(&format!("\n  {}\n", text)).unwrap(),
+            hashes
+        );
+        assert_eq!(
+            FrameHashes::parse("1234\nxyz").unwrap_err(),
+            "bad frame hash: xyz"
+        );
+    }
+
+    #[test]
+    fn finds_first_mismatch() {
+        let run = FrameHashes::parse("1\n2\n3\n").unwrap();
+        assert_eq!(run.first_mismatch(&run.clone()), None);
+        assert_eq!(
+            run.first_mismatch(&FrameHashes::parse("1\n5\n3\n").unwrap()),
+            Some(1)
+        );
+        // one run stopping early mismatches where it stopped
+        let short = FrameHashes::parse("1\n2\n").unwrap();
+        assert_eq!(run.first_mismatch(&short), Some(2));
+        assert_eq!(short.first_mismatch(&run), Some(2));
+        assert_eq!(FrameHashes::new().first_mismatch(&run), Some(0));
+    }
+}
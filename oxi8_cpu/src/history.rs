@@ -0,0 +1,344 @@
+use std::collections::VecDeque;
+
+use crate::{
+    Audio, Breakpoint, Cheat, Coverage, Cpu, Display, Profiler, Rand, Sanitizer, State, StopReason,
+    Timeline, Tracer, NUM_KEYS,
+};
+
+// reverse execution: while recording, Cpu::step() keeps a snapshot every so many cycles, going
+// back to any cycle is loading the last snapshot before it and replaying forward, which gives the
+// exact same result since emulation is deterministic given the same inputs
+// keys are the only input, so a snapshot is also taken whenever they change, that way replaying
+// between two snapshots never has to know about key presses
+// going back discards everything after the cycle gone back to, running forward from there is
+// running live again, same as if the future never happened
+// a debugger poking at registers or ram should call checkpoint() so replay sees the poke too
+// cheats aren't part of a State so each snapshot keeps its own copy, replaying runs the freezes
+// that were on back then and going back leaves the cheats as they were, patches and all
+
+#[derive(Default)]
+pub(crate) struct History {
+    every: u64, // cycles between snapshots, 0 isn't recording
+    max_snapshots: usize,
+    snapshots: VecDeque<Snapshot>, // oldest first
+    keys: [bool; NUM_KEYS],        // as of the last step, to notice changes
+}
+
+#[derive(Clone)]
+struct Snapshot {
+    state: State,
+    cheats: Vec<Cheat>,
+}
+
+impl Snapshot {
+    fn cycles(&self) -> u64 {
+        self.state.cycles()
+    }
+}
+
+impl History {
+    pub(crate) fn is_recording(&self) -> bool {
+        self.every > 0
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.snapshots.clear();
+    }
+
+    // replaces a snapshot of the same cycle, which can only be older news
+    fn push(&mut self, snapshot: Snapshot) {
+        if self.snapshots.back().map(Snapshot::cycles) == Some(snapshot.cycles()) {
+            self.snapshots.pop_back();
+        }
+        self.snapshots.push_back(snapshot);
+        while self.snapshots.len() > self.max_snapshots {
+            self.snapshots.pop_front();
+        }
+    }
+}
+
+// the instruction that last wrote a byte, as found by last_write()
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LastWrite {
+    pub cycle: u64, // Cpu::cycles() right before it ran
+    pub pc: u16,
+    pub opcode: u16,
+}
+
+impl<T: Display, R: Rand> Cpu<T, R> {
+    // snapshots every `every` cycles, keeping the last max_snapshots, so at most
+    // every * max_snapshots cycles can be gone back, each snapshot is a few KB
+    pub fn record_history(&mut self, every: u64, max_snapshots: usize) {
+        self.history.every = every;
+        self.history.max_snapshots = max_snapshots.max(1);
+        self.history.clear();
+    }
+
+    pub fn stop_history(&mut self) {
+        self.record_history(0, 0);
+    }
+
+    // the oldest cycle that can be gone back to, if recording
+    pub fn history_start(&self) -> Option<u64> {
+        self.history.snapshots.front().map(Snapshot::cycles)
+    }
+
+    // snapshots right now, for after changing state in ways replay can't know about
+    pub fn checkpoint(&mut self) {
+        if self.history.is_recording() {
+            let snapshot = self.snapshot();
+            self.history.push(snapshot);
+        }
+    }
+
+    pub(crate) fn record_step(&mut self) {
+        let keys_changed = self.keyboard.keys != self.history.keys;
+        if keys_changed || self.cycles % self.history.every == 0 {
+            self.checkpoint();
+            self.history.keys = self.keyboard.keys;
+        }
+    }
+
+    // goes back to right before the instruction at cycle ran
+    pub fn rewind_to(&mut self, cycle: u64) -> Result<(), String> {
+        if cycle > self.cycles {
+            return Err(format!(
+                "can't rewind forward to cycle {}, now is {}",
+                cycle, self.cycles
+            ));
+        }
+        let index = self
+            .history
+            .snapshots
+            .iter()
+            .rposition(|snapshot| snapshot.cycles() <= cycle)
+            .ok_or_else(|| match self.history_start() {
+                Some(start) => format!("history only goes back to cycle {}", start),
+                None => "no history recorded".to_string(),
+            })?;
+        self.history.snapshots.truncate(index + 1);
+        let snapshot = self.history.snapshots[index].clone();
+        self.replay(&snapshot, cycle);
+        self.history.keys = self.keyboard.keys;
+        self.tracer.forget_from(cycle);
+        self.timeline.forget_from(cycle);
+        self.mark_stopped();
+        Ok(())
+    }
+
+    pub fn step_back(&mut self) -> Result<(), String> {
+        if self.cycles == 0 {
+            return Err("already at the first instruction".to_string());
+        }
+        self.rewind_to(self.cycles - 1)
+    }
+
+    // goes back to the last time a breakpoint would have stopped, only conditions are checked,
+    // hit counts aren't since they count forward, and tracepoints never stop
+    // with nothing found it goes back as far as history does and returns None
+    pub fn reverse_continue(&mut self) -> Option<StopReason> {
+        let breakpoints: Vec<Breakpoint> = self
+            .debugger
+            .breakpoints()
+            .iter()
+            .filter(|breakpoint| breakpoint.log.is_none())
+            .cloned()
+            .collect();
+        let found = self.search_back(|cpu| {
+            breakpoints.iter().any(|breakpoint| {
+                breakpoint.addr == cpu.pc
+                    && breakpoint
+                        .condition
+                        .as_ref()
+                        .map_or(true, |condition| condition.is_true(cpu))
+            })
+        });
+        let cycle = found.or_else(|| self.history_start())?;
+        self.rewind_to(cycle).ok()?;
+        found.map(|_| StopReason::Breakpoint(self.pc))
+    }
+
+    // the last instruction to write addr, searching back as far as history goes, this leaves
+    // the Cpu exactly where it was
+    pub fn last_write(&mut self, addr: u16) -> Option<LastWrite> {
+        let mut last = None;
+        let cycle = self.search_back(|cpu| {
+            let wrote = cpu
+                .memory_access()
+                .write
+                .is_some_and(|write| write.contains(&addr));
+            if wrote {
+                last = Some((cpu.pc, cpu.opcode_at(cpu.pc)));
+            }
+            wrote
+        })?;
+        last.map(|(pc, opcode)| LastWrite { cycle, pc, opcode })
+    }
+
+    // the last cycle before now where found is true right before the instruction runs, checking
+    // the newest stretch between snapshots first so a recent hit doesn't replay all of history
+    fn search_back(&mut self, mut found: impl FnMut(&Self) -> bool) -> Option<u64> {
+        let now = self.snapshot();
+        let sidelined = Sidelined::take(self);
+        let mut end = self.cycles;
+        let mut ret = None;
+        for index in (0..self.history.snapshots.len()).rev() {
+            if self.history.snapshots[index].cycles() >= end {
+                continue;
+            }
+            let start = self.history.snapshots[index].clone();
+            self.restore_snapshot(&start);
+            while self.cycles < end {
+                if found(self) {
+                    ret = Some(self.cycles);
+                }
+                self.run_step(false);
+            }
+            if ret.is_some() {
+                break;
+            }
+            end = start.cycles();
+        }
+        self.restore_snapshot(&now);
+        sidelined.restore(self);
+        ret
+    }
+
+    fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            state: self.save_state(),
+            cheats: self.cheats.clone(),
+        }
+    }
+
+    fn restore_snapshot(&mut self, snapshot: &Snapshot) {
+        self.restore_state(&snapshot.state)
+            .expect("history saved from this Cpu didn't fit it");
+        self.cheats = snapshot.cheats.clone();
+    }
+
+    // loads a snapshot and runs forward to cycle without the debugger, recording, tracing,
+    // profiling, coverage, sanitizing, timeline or making any sound
+    fn replay(&mut self, snapshot: &Snapshot, cycle: u64) {
+        self.restore_snapshot(snapshot);
+        let sidelined = Sidelined::take(self);
+        while self.cycles < cycle {
+            self.run_step(false);
+        }
+        sidelined.restore(self);
+    }
+}
+
+// everything that watches the Cpu run, set aside while history replays so replaying doesn't
+// trace, profile, cover, sanitize, note on the timeline or play anything a second time
+struct Sidelined {
+    audio: Audio,
+    tracer: Tracer,
+    profiler: Profiler,
+    coverage: Coverage,
+    sanitizer: Sanitizer,
+    timeline: Timeline,
+}
+
+impl Sidelined {
+    fn take<T: Display, R: Rand>(cpu: &mut Cpu<T, R>) -> Sidelined {
+        Sidelined {
+            audio: std::mem::replace(&mut cpu.audio, Audio::new(0)),
+            tracer: std::mem::take(&mut cpu.tracer),
+            profiler: std::mem::take(&mut cpu.profiler),
+            coverage: std::mem::take(&mut cpu.coverage),
+            sanitizer: std::mem::take(&mut cpu.sanitizer),
+            timeline: std::mem::take(&mut cpu.timeline),
+        }
+    }
+
+    fn restore<T: Display, R: Rand>(self, cpu: &mut Cpu<T, R>) {
+        cpu.audio = self.audio;
+        cpu.tracer = self.tracer;
+        cpu.profiler = self.profiler;
+        cpu.coverage = self.coverage;
+        cpu.sanitizer = self.sanitizer;
+        cpu.timeline = self.timeline;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{BoolDisplay, SeededRand};
+
+    // counts up in v0 and stores it at 0x300 every time around
+    const COUNT: [u8; 8] = [
+        0xA3, 0x00, // LD I, 0x300
+        0x70, 0x01, // ADD V0, 1
+        0xF0, 0x55, // LD [I], V0
+        0x12, 0x02, // JP 0x202
+    ];
+
+    fn cpu() -> Cpu<BoolDisplay, SeededRand> {
+        let mut cpu = Cpu::new(&COUNT, BoolDisplay::new(1), SeededRand::new(0));
+        cpu.record_history(10, 100);
+        cpu.tracer.keep(1000);
+        for _ in 0..100 {
+            cpu.step();
+        }
+        cpu
+    }
+
+    #[test]
+    fn last_write_leaves_everything_as_it_was() {
+        let mut cpu = cpu();
+        let before = cpu.save_state();
+        let last = cpu.last_write(0x300).unwrap();
+        // 0x204 runs at cycles 2, 5, 8... so the last one before 100 is 98
+        assert_eq!((last.cycle, last.pc, last.opcode), (98, 0x204, 0xF055));
+        assert_eq!(cpu.save_state(), before);
+        assert_eq!(cpu.tracer.records().count(), 100);
+    }
+
+    #[test]
+    fn rewinds_and_replays_the_same() {
+        let mut cpu = cpu();
+        let at_50 = {
+            let mut other = Cpu::new(&COUNT, BoolDisplay::new(1), SeededRand::new(0));
+            for _ in 0..50 {
+                other.step();
+            }
+            other.save_state()
+        };
+        cpu.rewind_to(50).unwrap();
+        assert_eq!(cpu.save_state(), at_50);
+        assert_eq!(cpu.tracer.records().count(), 50);
+        cpu.step_back().unwrap();
+        assert_eq!(cpu.cycles(), 49);
+        assert!(cpu.rewind_to(60).unwrap_err().contains("forward"));
+    }
+
+    #[test]
+    fn rewinds_with_the_cheats_of_the_time() {
+        let mut cpu = Cpu::new(&COUNT, BoolDisplay::new(1), SeededRand::new(0));
+        cpu.record_history(10, 100);
+        let freeze = Cheat::parse("off stuck: freeze V0 0x50").unwrap();
+        // ADD V0, 2
+        let patch = Cheat::parse("off double: patch 0x202 0x70 0x02").unwrap();
+        cpu.load_cheats(vec![freeze, patch]);
+        let mut states = Vec::new();
+        for cycle in 0..100 {
+            match cycle {
+                33 => assert!(cpu.enable_cheat(0, true) && cpu.enable_cheat(1, true)),
+                66 => assert!(cpu.enable_cheat(0, false)),
+                _ => (),
+            }
+            states.push(cpu.save_state());
+            cpu.step();
+        }
+        for cycle in [90, 50, 20] {
+            cpu.rewind_to(cycle).unwrap();
+            assert_eq!(cpu.save_state(), states[cycle as usize]);
+            let enabled: Vec<bool> = cpu.cheats().iter().map(Cheat::is_enabled).collect();
+            assert_eq!(enabled, [(33..66).contains(&cycle), cycle >= 33]);
+        }
+        // back before the patch, so nothing is left to put back
+        assert_eq!(cpu.ram[0x202..0x204], [0x70, 0x01]);
+    }
+}
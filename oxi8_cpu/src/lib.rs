@@ -1,4 +1,4 @@
-use core::{fmt, slice::Iter};
+use core::{any::Any, fmt, slice::Iter};
 
 #[cfg(target_arch = "wasm32")]
 use std::time::Duration;
@@ -6,6 +6,62 @@ use std::time::Duration;
 #[cfg(not(target_arch = "wasm32"))]
 use std::time::Instant;
 
+mod audio;
+mod callstack;
+mod cheats;
+mod cheatsearch;
+mod coverage;
+mod debug;
+mod disasm;
+mod expr;
+#[cfg(feature = "gdb")]
+mod gdb;
+mod hash;
+mod history;
+mod movie;
+mod profile;
+mod ramview;
+#[cfg(feature = "recorder")]
+mod record;
+mod sanitizer;
+mod state;
+mod symbols;
+mod text;
+mod timeline;
+mod trace;
+mod tracediff;
+mod wav;
+
+pub use crate::audio::{Audio, Buzzer, Waveform};
+pub use crate::callstack::StackFrame;
+pub use crate::cheats::{rom_hash, Cheat, CheatAction, CheatFile, RomCheats};
+pub use crate::cheatsearch::{CheatFilter, CheatSearch, CheatTarget};
+pub use crate::coverage::{ByteCoverage, Coverage, SelfModification};
+pub use crate::debug::{
+    Access, Breakpoint, Debugger, MemoryAccess, OpcodeClass, Register, StopReason,
+};
+pub use crate::disasm::{disassemble, disassemble_with_symbols};
+pub use crate::expr::{Expr, LogMessage};
+#[cfg(feature = "gdb")]
+pub use crate::gdb::GdbServer;
+pub use crate::hash::FrameHashes;
+pub use crate::history::LastWrite;
+pub use crate::movie::{Movie, MovieEvent};
+pub use crate::profile::{Profiler, SubroutineProfile};
+pub use crate::ramview::{RamImage, SpritePreview};
+#[cfg(feature = "recorder")]
+pub use crate::record::{RecordFormat, Recorder};
+pub use crate::sanitizer::{Finding, Problem, Sanitizer, SanitizerMode};
+pub use crate::state::State;
+pub use crate::symbols::SymbolMap;
+pub use crate::text::{check_snapshot, diff_text, render_text, TextStyle};
+pub use crate::timeline::{Timeline, TimelineEntry, TimelineEvent};
+pub use crate::trace::{TraceRecord, Tracer};
+pub use crate::tracediff::{Divergence, TraceStep};
+pub use crate::wav::write_wav;
+
+use crate::history::History;
+
 // http://devernay.free.fr/hacks/chip8/C8TECH10.HTM#2.0
 
 const RAM_SIZE: usize = 4096; // 0-511 reserved for interpreter, useless today
@@ -63,7 +119,7 @@ const SPRITES: [u8; SPRITE_LEN] = [
 
 const NUM_KEYS: usize = 16;
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum Key {
     K0 = 0x0,
     K1 = 0x1,
@@ -83,12 +139,37 @@ pub enum Key {
     KF = 0xF,
 }
 
+const KEYS: [Key; NUM_KEYS] = [
+    Key::K0,
+    Key::K1,
+    Key::K2,
+    Key::K3,
+    Key::K4,
+    Key::K5,
+    Key::K6,
+    Key::K7,
+    Key::K8,
+    Key::K9,
+    Key::KA,
+    Key::KB,
+    Key::KC,
+    Key::KD,
+    Key::KE,
+    Key::KF,
+];
+
+impl Key {
+    pub fn from_u8(keycode: u8) -> Option<Key> {
+        KEYS.get(keycode as usize).cloned()
+    }
+}
+
 pub struct Keyboard {
     keys: [bool; NUM_KEYS],
     keywait: KeyWait,
 }
 
-#[derive(PartialEq)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 enum KeyWait {
     None,        // nothing to wait for
     Wait,        // wait for a keypress
@@ -194,21 +275,67 @@ pub trait Rand {
     fn next(&mut self) -> u8 {
         4 // chosen by fair dice roll. guaranteed to be random.
     }
+
+    // for save states, generators with nothing worth restoring can leave these alone
+    fn state(&self) -> u64 {
+        0
+    }
+
+    fn set_state(&mut self, _state: u64) {}
 }
 
 pub struct ConstantRand {}
 impl Rand for ConstantRand {}
 
+// xorshift64, same seed always gives the same game, which movies and headless runs depend on
+pub struct SeededRand {
+    state: u64,
+}
+
+impl SeededRand {
+    pub fn new(seed: u64) -> SeededRand {
+        SeededRand {
+            // xorshift gets stuck on 0 forever
+            state: if seed == 0 {
+                0x9E37_79B9_7F4A_7C15
+            } else {
+                seed
+            },
+        }
+    }
+}
+
+impl Rand for SeededRand {
+    fn next(&mut self) -> u8 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        (self.state >> 32) as u8
+    }
+
+    fn state(&self) -> u64 {
+        self.state
+    }
+
+    fn set_state(&mut self, state: u64) {
+        self.state = state;
+    }
+}
+
 struct Stack {
     stack: [u16; STACK_SIZE],
-    sp: usize, // stack pointer
+    targets: [u16; STACK_SIZE], // the subroutine each 2nnn called, for debuggers
+    sp: usize,                  // stack pointer
+    high_water: usize,          // deepest sp has ever been
 }
 
 impl Stack {
     fn new() -> Stack {
         Stack {
             stack: [0; STACK_SIZE],
+            targets: [0; STACK_SIZE],
             sp: 0,
+            high_water: 0,
         }
     }
 
@@ -221,12 +348,14 @@ impl Stack {
         }
     }
 
-    fn push(&mut self, value: u16) -> Option<()> {
+    fn push(&mut self, value: u16, target: u16) -> Option<()> {
         if self.sp == STACK_SIZE {
             return None;
         }
         self.stack[self.sp] = value;
+        self.targets[self.sp] = target;
         self.sp += 1;
+        self.high_water = self.high_water.max(self.sp);
         Some(())
     }
 
@@ -245,23 +374,46 @@ pub struct Cpu<T: Display, R: Rand> {
     delay: u8,              // when non-zero decremented at 60hz
     pub sound: u8,          // when non-zero decremented at 60hz and sound buzzer
     ram: [u8; RAM_SIZE],
+    rom_len: usize,
+    rom_hash: u64, // picks this rom's cheats from a cheat file
 
     // these are used by the emulator
     pc: u16, // program counter
     stack: Stack,
     pub display: T,
     pub keyboard: Keyboard,
+    pub audio: Audio,
     start_time: Instant,
     clock_rate_hz: u32,
     cpu_timer: Timer,
     delay_timer: Timer,
     num_instructions_per_decrement: u32,
     rand: R,
+    pub debugger: Debugger,
+    pub symbols: SymbolMap, // labels for debuggers to show, empty unless a frontend loads some
+    pub tracer: Tracer,
+    pub profiler: Profiler,
+    pub coverage: Coverage,
+    pub sanitizer: Sanitizer,
+    pub timeline: Timeline,
+    cheats: Vec<Cheat>,
+    history: History,
+    cycles: u64,      // instructions executed since power on
+    frame: u64,       // 60hz timer decrements since power on
+    frame_cycle: u32, // instructions executed so far in the current 60hz frame
 }
 
 impl<T: Display, R: Rand> fmt::Debug for Cpu<T, R> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "Cpu {{ I: {:04X?}, V: {:02X?}", self.i, self.v)?;
+        write!(f, "Cpu {{ PC: {:04X?}", self.pc)?;
+        if let Some(label) = self.symbols.label(self.pc) {
+            write!(f, " <{}>", label)?;
+        }
+        write!(f, ", I: {:04X?}", self.i)?;
+        if let Some(label) = self.symbols.label(self.i) {
+            write!(f, " <{}>", label)?;
+        }
+        write!(f, ", V: {:02X?}", self.v)?;
         //write!(f, ", delay: {}, sound: {}", self.delay, self.sound)?;
         write!(f, " }}")
     }
@@ -285,19 +437,65 @@ impl<T: Display, R: Rand> Cpu<T, R> {
             delay: 0,
             sound: 0,
             ram,
+            rom_len: rom.len(),
+            rom_hash: rom_hash(rom),
             pc: PROGRAM_OFFSET as u16,
             stack: Stack::new(),
             display,
             keyboard: Keyboard::default(),
+            audio: Audio::new(0),
             start_time: Instant::now(),
             clock_rate_hz: CLOCK_RATE_HZ,
             cpu_timer: Timer::new(CLOCK_RATE_HZ),
             delay_timer: Timer::new(DELAY_DECREMENT_HZ),
             num_instructions_per_decrement: CLOCK_RATE_HZ / DELAY_DECREMENT_HZ,
             rand,
+            debugger: Debugger::default(),
+            symbols: SymbolMap::default(),
+            tracer: Tracer::default(),
+            profiler: Profiler::default(),
+            coverage: Coverage::default(),
+            sanitizer: Sanitizer::default(),
+            timeline: Timeline::default(),
+            cheats: Vec::new(),
+            history: History::default(),
+            cycles: 0,
+            frame: 0,
+            frame_cycle: 0,
         }
     }
 
+    // where the rom was loaded, whatever it has done to itself since
+    pub fn rom_range(&self) -> std::ops::Range<u16> {
+        PROGRAM_OFFSET as u16..(PROGRAM_OFFSET + self.rom_len) as u16
+    }
+
+    pub fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    // for debuggers poking at memory, programs can't tell it happened
+    pub fn ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
+
+    pub fn stack_depth(&self) -> usize {
+        self.stack.sp
+    }
+
+    // where every 2nnn still waiting on its 00EE was called from, outermost first
+    pub fn stack(&self) -> &[u16] {
+        &self.stack.stack[..self.stack.sp]
+    }
+
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    pub fn frame(&self) -> u64 {
+        self.frame
+    }
+
     pub fn get_clock_rate_hz(&self) -> u32 {
         self.clock_rate_hz
     }
@@ -332,18 +530,69 @@ impl<T: Display, R: Rand> Cpu<T, R> {
             //println!("running x: {}", x);
             self.execute_next_instruction();
         }
+        self.audio.flush();
     }
 
     // this MUST be called at exactly 60hz, 60 times per second
-    pub fn cycle_60hz(&mut self) {
-        self.decrement_timers();
+    // if the debugger stops partway through, the rest of the frame runs on the next call
+    pub fn cycle_60hz(&mut self) -> Option<StopReason> {
+        if self.num_instructions_per_decrement == 0 {
+            self.decrement_timers();
+            return None;
+        }
+        loop {
+            let stop = self.step();
+            if stop.is_some() {
+                return stop;
+            }
+            if self.frame_cycle == 0 {
+                return None;
+            }
+        }
+    }
+
+    // runs 1 instruction on the same schedule as cycle_60hz(), so the timers decrement before
+    // the first instruction of each frame, returns why the debugger stopped, if it did, a stop on
+    // a breakpoint comes before its instruction runs, and the next step() runs it
+    pub fn step(&mut self) -> Option<StopReason> {
+        if self.sanitizer.is_active() {
+            if let Some(stop) = self.sanitize_stop() {
+                return Some(stop);
+            }
+        }
+        let debug = self.debugger.is_active();
+        if debug {
+            if let Some(stop) = self.debug_stop() {
+                return Some(stop);
+            }
+        }
+        if self.history.is_recording() {
+            self.record_step();
+        }
+        self.run_step(debug)
+    }
 
-        for _ in 0..self.num_instructions_per_decrement {
+    // step() without recording history, debug is whether to check the debugger
+    fn run_step(&mut self, debug: bool) -> Option<StopReason> {
+        if self.frame_cycle == 0 {
+            self.decrement_timers();
+        }
+        let stop = if debug {
+            self.debug_step()
+        } else {
             self.execute_next_instruction();
+            None
+        };
+        self.frame_cycle += 1;
+        if self.frame_cycle >= self.num_instructions_per_decrement {
+            self.frame_cycle = 0;
+            self.audio.flush();
         }
+        stop
     }
 
     pub fn decrement_timers(&mut self) {
+        self.frame += 1;
         if self.delay > 0 {
             self.delay -= 1;
         }
@@ -362,11 +611,33 @@ impl<T: Display, R: Rand> Cpu<T, R> {
     }
 
     pub fn execute_next_instruction(&mut self) {
+        // before fetching, frozen bytes can be code too
+        if !self.cheats.is_empty() {
+            self.freeze_cheats();
+        }
         let instruction = self.next_instruction();
-        //println!("ins: {}", instruction);
-        //print!("ins: {}, before: {:?}", instruction, self);
+        if self.profiler.is_active() {
+            self.profile_step();
+        }
+        if self.coverage.is_active() {
+            self.cover_step();
+        }
+        if self.sanitizer.is_active() {
+            self.sanitize_step();
+        }
+        if self.timeline.is_active() {
+            self.timeline_step();
+        }
+        let trace = self.trace_start();
         self.pc = self.execute_instruction(instruction);
-        //println!(", after : {:?}", self);
+        if let Some(trace) = trace {
+            self.trace_end(trace);
+        }
+        self.cycles += 1;
+        // each instruction is an equal slice of the 60hz frame it ran in, so audio lines up with the timers
+        let seconds =
+            1.0 / (DELAY_DECREMENT_HZ * self.num_instructions_per_decrement.max(1)) as f64;
+        self.audio.advance(self.sound, seconds);
     }
 
     pub fn reset(&mut self) {
@@ -380,6 +651,7 @@ impl<T: Display, R: Rand> Cpu<T, R> {
         self.stack.clear();
         self.display.set_hires(false);
         self.keyboard.keywait = KeyWait::None;
+        self.frame_cycle = 0;
         // probably don't *need* to reset these timers...
         self.start_time = Instant::now();
         self.cpu_timer.last_cycle_timestamp = 0;
@@ -436,9 +708,14 @@ impl<T: Display, R: Rand> Cpu<T, R> {
             0x1 => i.xyz(),
             // 2xyz - CALL addr: Call subroutine at xyz
             0x2 => {
-                self.stack
-                    .push(self.pc)
-                    .expect("exceeded maximum stack size");
+                if self.stack.push(self.pc, i.xyz()).is_none() {
+                    panic!(
+                        "exceeded maximum stack size calling 0x{:03X} from 0x{:03X}, calls innermost first:\n{}",
+                        i.xyz(),
+                        self.pc,
+                        self.format_call_stack()
+                    );
+                }
                 i.xyz()
             }
             // 3xyz - SE Vx, yz: Skip next instruction if Vx = yz
@@ -545,13 +822,15 @@ impl<T: Display, R: Rand> Cpu<T, R> {
                 let vy = self.v.g(i.y()) as usize;
                 let z = i.z() as usize;
                 let from = self.i as usize;
+                // one sprite per selected plane, one after another in memory
+                let planes = self.display.selected_planes().count_selected();
                 self.v[0xF] = if z == 0 {
                     // Draw SCHIP 16x16 sprite
-                    let to = from + 32;
+                    let to = from + 32 * planes;
                     self.display.schip_draw(vx, vy, &self.ram[from..to])
                 } else {
                     // Draw standard chip8 sprite
-                    let to = from + z;
+                    let to = from + z * planes;
                     self.display.draw(vx, vy, &self.ram[from..to])
                 } as u8;
                 self.next()
@@ -738,6 +1017,16 @@ impl Nibble for u8 {
     }
 }
 
+// what a panic caught with catch_unwind() said, the Cpu panics on things like a 0000 freeze or
+// returning with an empty stack, so frontends catch it to report it instead of dying
+pub fn panic_message(payload: &(dyn Any + Send)) -> String {
+    payload
+        .downcast_ref::<&str>()
+        .map(|message| message.to_string())
+        .or_else(|| payload.downcast_ref::<String>().cloned())
+        .unwrap_or_default()
+}
+
 // A chip-8 instruction can be thought of as 4 4-bit nibbles
 // here I name them in order wxyz, where wx is high, and yz is low
 pub struct Instruction {
@@ -746,6 +1035,10 @@ pub struct Instruction {
 }
 
 impl Instruction {
+    pub fn opcode(&self) -> u16 {
+        ((self.wx as u16) << 8) | self.yz as u16
+    }
+
     #[inline(always)]
     fn w(&self) -> u8 {
         self.wx.high()
@@ -813,6 +1106,12 @@ impl fmt::Debug for Instruction {
     }
 }
 
+const FNV_OFFSET_BASIS: u64 = 0xCBF2_9CE4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01B3;
+
+// pixels are color indices where bit n is the pixel in plane n, plain chip-8 and schip only have
+// plane 0, XO-CHIP has 2 planes, MegaChip uses all 8 bits as a color
+// drawing, clearing and scrolling only touch the planes selected in plane_mask()
 pub trait Display {
     fn schip_draw(&mut self, starting_x: usize, starting_y: usize, memory: &[u8]) -> bool {
         let mut pixel_turned_off = false;
@@ -820,72 +1119,225 @@ pub trait Display {
         //println!("hires: {}", hires);
         //let hires = true;
         let hires_starting_x = starting_x + 8;
-        let mut y = starting_y as usize % self.height();
-        let mut iter = memory.iter();
-        // memory *must* be 32 bytes long here, we ensure that at the call site...
-        for _ in 1..16 {
-            let left = iter.next().unwrap();
-            let right = iter.next().unwrap();
-            pixel_turned_off |= self.draw_byte(starting_x, y, left);
-            if hires {
-                pixel_turned_off |= self.draw_byte(hires_starting_x, y, right);
+        // memory *must* be 32 bytes long per selected plane here, we ensure that at the call site...
+        let mut planes = memory.chunks(32);
+        for plane in self.selected_planes() {
+            let mut y = starting_y as usize % self.height();
+            let mut iter = planes.next().unwrap().iter();
+            for _ in 1..16 {
+                let left = iter.next().unwrap();
+                let right = iter.next().unwrap();
+                pixel_turned_off |= self.draw_byte(plane, starting_x, y, left);
+                if hires {
+                    pixel_turned_off |= self.draw_byte(plane, hires_starting_x, y, right);
+                }
+                y = (y + 1) % self.height();
             }
-            y = (y + 1) % self.height();
         }
         pixel_turned_off
     }
 
+    // memory holds one sprite per selected plane, one after the other, lowest plane first
     fn draw(&mut self, starting_x: usize, starting_y: usize, memory: &[u8]) -> bool {
         let mut pixel_turned_off = false;
-        let mut y = starting_y as usize % self.height();
-        for byte in memory.iter() {
-            pixel_turned_off |= self.draw_byte(starting_x, y, byte);
-            y = (y + 1) % self.height();
+        let selected_planes = self.selected_planes();
+        let sprite_len = memory.len() / selected_planes.count_selected().max(1);
+        for (plane, sprite) in selected_planes.zip(memory.chunks(sprite_len.max(1))) {
+            let mut y = starting_y as usize % self.height();
+            for byte in sprite.iter() {
+                pixel_turned_off |= self.draw_byte(plane, starting_x, y, byte);
+                y = (y + 1) % self.height();
+            }
         }
         pixel_turned_off
     }
 
-    fn draw_byte(&mut self, starting_x: usize, y: usize, byte: &u8) -> bool {
+    fn draw_byte(&mut self, plane: u8, starting_x: usize, y: usize, byte: &u8) -> bool {
         let mut pixel_turned_off = false;
+        let plane_bit = 1 << plane;
         for bit_number in 0..8 {
             let x = (starting_x + bit_number) % self.width();
 
-            let current_bit = (byte >> (7 - bit_number)) & 1;
+            if (byte >> (7 - bit_number)) & 1 == 0 {
+                continue; // xor with 0 never changes anything
+            }
 
             let current_pixel = self.current_pixel(x, y);
-            let new_pixel = current_bit ^ current_pixel;
-
-            self.set_pixel(x, y, new_pixel);
+            self.set_pixel(x, y, current_pixel ^ plane_bit);
 
-            if current_pixel == 1 && new_pixel == 0 {
+            if current_pixel & plane_bit != 0 {
                 pixel_turned_off = true;
             }
         }
         pixel_turned_off
     }
 
+    // 64-bit FNV-1a over the resolution mode and visible pixels, this must never change between
+    // versions because CI keeps sequences of these to detect emulation regressions
+    fn frame_hash(&self) -> u64 {
+        let mut hash = FNV_OFFSET_BASIS;
+        let mut write = |byte: u8| {
+            hash ^= byte as u64;
+            hash = hash.wrapping_mul(FNV_PRIME);
+        };
+        write(self.hires() as u8);
+        // pack 8 pixels per byte so hashing stays cheap enough to do every frame,
+        // one plane after the other so single plane displays hash exactly like they always have
+        for plane in 0..self.num_planes() {
+            let plane_bit = 1 << plane;
+            for y in 0..self.height() {
+                for x in (0..self.width()).step_by(8) {
+                    let mut byte = 0;
+                    for bit in 0..8 {
+                        if x + bit < self.width() && self.current_pixel(x + bit, y) & plane_bit != 0
+                        {
+                            byte |= 0b1000_0000 >> bit;
+                        }
+                    }
+                    write(byte);
+                }
+            }
+        }
+        hash
+    }
+
+    // indices of the planes selected in plane_mask(), lowest first
+    fn selected_planes(&self) -> Planes {
+        Planes {
+            mask: self.plane_mask(),
+            plane: 0,
+            num_planes: self.num_planes(),
+        }
+    }
+
+    // only changes the bits of color in the selected planes, leaving the others alone
+    fn set_selected_planes(&mut self, x: usize, y: usize, color: u8) {
+        let mask = self.plane_mask();
+        let current_pixel = self.current_pixel(x, y);
+        self.set_pixel(x, y, (current_pixel & !mask) | (color & mask));
+    }
+
+    fn clear(&mut self) {
+        for y in 0..self.height() {
+            for x in 0..self.width() {
+                self.set_selected_planes(x, y, 0);
+            }
+        }
+    }
+
+    // for all pixel scrolling, number is halved when in lowres
+    // scroll left 4 pixels
+    fn scroll_left(&mut self) {
+        let pixels = if self.hires() { 4 } else { 2 };
+        for y in 0..self.height() {
+            for x in 0..self.width() {
+                let color = if x + pixels < self.width() {
+                    self.current_pixel(x + pixels, y)
+                } else {
+                    0
+                };
+                self.set_selected_planes(x, y, color);
+            }
+        }
+    }
+
+    // scroll right 4 pixels
+    fn scroll_right(&mut self) {
+        let pixels = if self.hires() { 4 } else { 2 };
+        for y in 0..self.height() {
+            for x in (0..self.width()).rev() {
+                let color = if x >= pixels {
+                    self.current_pixel(x - pixels, y)
+                } else {
+                    0
+                };
+                self.set_selected_planes(x, y, color);
+            }
+        }
+    }
+
+    // scroll down 0-15 pixels
+    fn scroll_down(&mut self, n: u8) {
+        let pixels = if self.hires() { n } else { n / 2 } as usize;
+        for y in (0..self.height()).rev() {
+            for x in 0..self.width() {
+                let color = if y >= pixels {
+                    self.current_pixel(x, y - pixels)
+                } else {
+                    0
+                };
+                self.set_selected_planes(x, y, color);
+            }
+        }
+    }
+
+    // scroll up 0-15 pixels, XO-CHIP only
+    fn scroll_up(&mut self, n: u8) {
+        let pixels = if self.hires() { n } else { n / 2 } as usize;
+        for y in 0..self.height() {
+            for x in 0..self.width() {
+                let color = if y + pixels < self.height() {
+                    self.current_pixel(x, y + pixels)
+                } else {
+                    0
+                };
+                self.set_selected_planes(x, y, color);
+            }
+        }
+    }
+
     fn height(&self) -> usize;
     fn width(&self) -> usize;
+    fn num_planes(&self) -> u8; // 1-8
+    fn plane_mask(&self) -> u8;
+    fn set_plane_mask(&mut self, mask: u8);
+    // color index of the pixel, bit n set means the pixel is on in plane n
     fn current_pixel(&self, x: usize, y: usize) -> u8;
-    fn set_pixel(&mut self, x: usize, y: usize, new_pixel: u8);
-    fn clear(&mut self);
+    fn set_pixel(&mut self, x: usize, y: usize, color: u8);
     fn set_hires(&mut self, on: bool);
     fn hires(&self) -> bool;
-    // for all pixel scrolling, number is halved when in lowres
-    fn scroll_left(&mut self); // scroll left 4 pixels
-    fn scroll_right(&mut self); // scroll right 4 pixels
-    fn scroll_down(&mut self, n: u8); // scroll down 0-15 pixels
+}
+
+pub struct Planes {
+    mask: u8,
+    plane: u8,
+    num_planes: u8,
+}
+
+impl Iterator for Planes {
+    type Item = u8;
+
+    fn next(&mut self) -> Option<u8> {
+        while self.plane < self.num_planes {
+            let plane = self.plane;
+            self.plane += 1;
+            if self.mask & (1 << plane) != 0 {
+                return Some(plane);
+            }
+        }
+        None
+    }
+}
+
+impl Planes {
+    pub fn count_selected(&self) -> usize {
+        (self.plane..self.num_planes)
+            .filter(|plane| self.mask & (1 << plane) != 0)
+            .count()
+    }
 }
 
 const WIDTH: usize = DISPLAY_WIDTH as usize;
 const HEIGHT: usize = DISPLAY_HEIGHT as usize;
 
+// the plain single plane case, plane_mask() can only ever be 0 or 1
 pub struct BoolDisplay {
     buffer: Vec<Vec<bool>>,
     scale: u32,
     width: usize,
     height: usize,
     hires: bool,
+    plane_mask: u8,
 }
 
 impl BoolDisplay {
@@ -896,6 +1348,7 @@ impl BoolDisplay {
             buffer: vec![vec![false; WIDTH]; HEIGHT],
             scale,
             hires: false,
+            plane_mask: 1,
         }
     }
 
@@ -917,15 +1370,32 @@ impl Display for BoolDisplay {
         self.width
     }
 
+    fn num_planes(&self) -> u8 {
+        1
+    }
+
+    fn plane_mask(&self) -> u8 {
+        self.plane_mask
+    }
+
+    fn set_plane_mask(&mut self, mask: u8) {
+        self.plane_mask = mask & 1;
+    }
+
     fn current_pixel(&self, x: usize, y: usize) -> u8 {
         self.buffer[y][x] as u8
     }
 
-    fn set_pixel(&mut self, x: usize, y: usize, new_pixel: u8) {
-        self.buffer[y][x] = new_pixel != 0;
+    fn set_pixel(&mut self, x: usize, y: usize, color: u8) {
+        self.buffer[y][x] = color & 1 != 0;
     }
 
+    // the rest of these are faster versions of the defaults, which only work with a single plane
+
     fn clear(&mut self) {
+        if self.plane_mask == 0 {
+            return;
+        }
         for row in self.buffer.iter_mut() {
             for pixel in row.iter_mut() {
                 *pixel = false;
@@ -957,7 +1427,11 @@ impl Display for BoolDisplay {
             self.buffer.iter_mut().for_each(|row| row.truncate(WIDTH));
             self.scale *= 2
         }
+        // switching resolution clears every plane, no matter which are selected
+        let plane_mask = self.plane_mask;
+        self.plane_mask = 1;
         self.clear();
+        self.plane_mask = plane_mask;
     }
 
     fn hires(&self) -> bool {
@@ -965,6 +1439,9 @@ impl Display for BoolDisplay {
     }
 
     fn scroll_left(&mut self) {
+        if self.plane_mask == 0 {
+            return;
+        }
         let pixels = if self.hires { 4 } else { 2 };
         let width = self.width;
         // for each row
@@ -977,6 +1454,9 @@ impl Display for BoolDisplay {
     }
 
     fn scroll_right(&mut self) {
+        if self.plane_mask == 0 {
+            return;
+        }
         let pixels = if self.hires { 4 } else { 2 };
         let truncate_to = self.width - pixels;
         let prepend: Vec<bool> = vec![false; pixels];
@@ -990,6 +1470,9 @@ impl Display for BoolDisplay {
     }
 
     fn scroll_down(&mut self, pixels: u8) {
+        if self.plane_mask == 0 {
+            return;
+        }
         let pixels = if self.hires { pixels } else { pixels / 2 } as usize;
         // delete entire rows of pixels at the bottom
         self.buffer.truncate(self.height - pixels);
@@ -999,3 +1482,206 @@ impl Display for BoolDisplay {
         self.buffer.splice(0..0, (0..pixels).map(|_| row.clone()));
     }
 }
+
+// any number of planes, 1 byte per pixel, everything but the basics comes from the Display defaults
+pub struct PlaneDisplay {
+    buffer: Vec<u8>,
+    width: usize,
+    height: usize,
+    hires: bool,
+    num_planes: u8,
+    plane_mask: u8,
+}
+
+impl PlaneDisplay {
+    // num_planes is 1 for chip-8/schip, 2 for XO-CHIP, 8 for MegaChip
+    pub fn new(num_planes: u8) -> PlaneDisplay {
+        assert!((1..=8).contains(&num_planes), "num_planes must be 1-8");
+        PlaneDisplay {
+            buffer: vec![0; WIDTH * HEIGHT],
+            width: WIDTH,
+            height: HEIGHT,
+            hires: false,
+            num_planes,
+            plane_mask: 1,
+        }
+    }
+
+    // rows of color indices, top to bottom
+    pub fn get_buffer(&self) -> std::slice::Chunks<'_, u8> {
+        self.buffer.chunks(self.width)
+    }
+}
+
+impl Display for PlaneDisplay {
+    fn height(&self) -> usize {
+        self.height
+    }
+
+    fn width(&self) -> usize {
+        self.width
+    }
+
+    fn num_planes(&self) -> u8 {
+        self.num_planes
+    }
+
+    fn plane_mask(&self) -> u8 {
+        self.plane_mask
+    }
+
+    fn set_plane_mask(&mut self, mask: u8) {
+        // 8 planes would overflow the shift
+        self.plane_mask = mask & (((1u16 << self.num_planes) - 1) as u8);
+    }
+
+    fn current_pixel(&self, x: usize, y: usize) -> u8 {
+        self.buffer[y * self.width + x]
+    }
+
+    fn set_pixel(&mut self, x: usize, y: usize, color: u8) {
+        self.buffer[y * self.width + x] = color;
+    }
+
+    fn set_hires(&mut self, on: bool) {
+        if self.hires == on {
+            return;
+        }
+        self.hires = on;
+        let scale = if on { 2 } else { 1 };
+        self.width = WIDTH * scale;
+        self.height = HEIGHT * scale;
+        // switching resolution clears every plane, no matter which are selected
+        self.buffer = vec![0; self.width * self.height];
+    }
+
+    fn hires(&self) -> bool {
+        self.hires
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // color indices of the first 8 pixels of row y
+    fn row(display: &impl Display, y: usize) -> Vec<u8> {
+        (0..8).map(|x| display.current_pixel(x, y)).collect()
+    }
+
+    #[test]
+    fn draws_and_collides_per_plane() {
+        let mut display = PlaneDisplay::new(2);
+        display.set_plane_mask(2);
+        assert!(!display.draw(0, 0, &[0xFF]));
+        assert_eq!(row(&display, 0), [2; 8]);
+        display.set_plane_mask(1);
+        // plane 0 was empty so nothing turned off
+        assert!(!display.draw(0, 0, &[0x0F]));
+        assert_eq!(row(&display, 0), [2, 2, 2, 2, 3, 3, 3, 3]);
+        display.set_plane_mask(2);
+        assert!(display.draw(0, 0, &[0x0F]));
+        assert_eq!(row(&display, 0), [2, 2, 2, 2, 1, 1, 1, 1]);
+        // one sprite per plane, lowest plane first
+        display.set_plane_mask(3);
+        assert!(!display.draw(0, 1, &[0xF0, 0x3C]));
+        assert_eq!(row(&display, 1), [1, 1, 3, 3, 2, 2, 0, 0]);
+    }
+
+    #[test]
+    fn clears_and_sets_only_selected_planes() {
+        let mut display = PlaneDisplay::new(2);
+        display.set_plane_mask(3);
+        display.draw(0, 0, &[0xFF, 0xFF]);
+        display.set_plane_mask(1);
+        display.clear();
+        assert_eq!(row(&display, 0), [2; 8]);
+        display.set_selected_planes(0, 0, 0xFF);
+        assert_eq!(display.current_pixel(0, 0), 3);
+        display.set_plane_mask(2);
+        display.set_selected_planes(1, 0, 0);
+        assert_eq!(display.current_pixel(1, 0), 0);
+        display.clear();
+        assert_eq!(row(&display, 0), [1, 0, 0, 0, 0, 0, 0, 0]);
+        // masks past the planes there are get cut off
+        display.set_plane_mask(0xFF);
+        assert_eq!(display.plane_mask(), 3);
+    }
+
+    #[test]
+    fn scrolls_only_selected_planes() {
+        let mut display = PlaneDisplay::new(2);
+        display.set_plane_mask(3);
+        display.draw(0, 4, &[0x18, 0x18]);
+        display.set_plane_mask(1);
+        // lores scrolls half as far
+        display.scroll_up(4);
+        assert_eq!(row(&display, 2), [0, 0, 0, 1, 1, 0, 0, 0]);
+        assert_eq!(row(&display, 4), [0, 0, 0, 2, 2, 0, 0, 0]);
+        display.scroll_down(4);
+        assert_eq!(row(&display, 2), [0; 8]);
+        assert_eq!(row(&display, 4), [0, 0, 0, 3, 3, 0, 0, 0]);
+        display.set_plane_mask(2);
+        display.scroll_left();
+        assert_eq!(row(&display, 4), [0, 2, 2, 1, 1, 0, 0, 0]);
+        display.set_plane_mask(3);
+        display.scroll_right();
+        assert_eq!(row(&display, 4), [0, 0, 0, 2, 2, 1, 1, 0]);
+        display.set_hires(true);
+        display.draw(0, 0, &[0x80, 0x80]);
+        display.scroll_up(1);
+        assert_eq!(display.current_pixel(0, 0), 0);
+        assert_eq!(display.current_pixel(0, 127 - 64), 0);
+    }
+
+    // single plane drawing and scrolling act the same on both displays, with BoolDisplay's fast
+    // versions of the defaults giving what it always has
+    #[test]
+    fn bool_display_unchanged() {
+        let mut bools = BoolDisplay::new(1);
+        let mut planes = PlaneDisplay::new(1);
+        fn run(display: &mut impl Display) -> Vec<bool> {
+            let mut collided = vec![display.draw(0, 0, &[0xF0, 0x90, 0xF0])];
+            collided.push(display.draw(2, 1, &[0xC0]));
+            display.scroll_right();
+            display.scroll_down(2);
+            display.scroll_left();
+            collided
+        }
+        assert_eq!(run(&mut bools), [false, true]);
+        assert_eq!(run(&mut planes), [false, true]);
+        assert_eq!(row(&bools, 0), [0; 8]);
+        assert_eq!(row(&bools, 1), [1, 1, 1, 1, 0, 0, 0, 0]);
+        assert_eq!(row(&bools, 2), [1, 0, 1, 0, 0, 0, 0, 0]);
+        assert_eq!(row(&bools, 3), [1, 1, 1, 1, 0, 0, 0, 0]);
+        for y in 0..4 {
+            assert_eq!(row(&bools, y), row(&planes, y));
+        }
+        assert_eq!(bools.frame_hash(), planes.frame_hash());
+        bools.set_plane_mask(0);
+        bools.clear();
+        assert_eq!(row(&bools, 1), [1, 1, 1, 1, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn drw_reads_a_sprite_per_selected_plane() {
+        const ROM: [u8; 8] = [
+            0xA2, 0x06, // LD I, 0x206
+            0xD0, 0x01, // DRW V0, V0, 1
+            0x12, 0x04, // JP 0x204
+            0xF0, 0x3C, // sprite for plane 0 then plane 1
+        ];
+        let mut cpu = Cpu::new(&ROM, PlaneDisplay::new(2), SeededRand::new(0));
+        cpu.display.set_plane_mask(3);
+        cpu.step();
+        cpu.step();
+        assert_eq!(row(&cpu.display, 0), [1, 1, 3, 3, 2, 2, 0, 0]);
+        assert_eq!(cpu.v[0xF], 0);
+        cpu.display.set_plane_mask(2);
+        cpu.pc = 0x202;
+        cpu.step();
+        // only plane 1, and its sprite is the first byte at I
+        assert_eq!(row(&cpu.display, 0), [3, 3, 1, 1, 2, 2, 0, 0]);
+        assert_eq!(cpu.v[0xF], 1);
+    }
+}
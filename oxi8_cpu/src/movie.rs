@@ -0,0 +1,248 @@
+use core::fmt;
+
+use crate::{Cpu, Display, Key, SeededRand};
+
+// an input movie, everything needed to replay a game from power on exactly as it was played:
+// the random seed, the clock rate, and every input change tagged with the 60hz frame it happened before
+
+const MAGIC: &str = "oxi8 movie 1";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MovieEvent {
+    Key(Key, bool),
+    ClockRate(u32),
+    Reset,
+}
+
+pub struct Movie {
+    pub seed: u64,
+    pub clock_rate_hz: u32,
+    pub frames: u64,
+    events: Vec<(u64, MovieEvent)>,
+}
+
+impl Movie {
+    pub fn new(seed: u64, clock_rate_hz: u32) -> Movie {
+        Movie {
+            seed,
+            clock_rate_hz,
+            frames: 0,
+            events: Vec::new(),
+        }
+    }
+
+    // event happens right before frame is ran, frames must never go backwards
+    pub fn push(&mut self, frame: u64, event: MovieEvent) {
+        self.events.push((frame, event));
+        if frame > self.frames {
+            self.frames = frame;
+        }
+    }
+
+    pub fn events(&self) -> &[(u64, MovieEvent)] {
+        &self.events
+    }
+
+    pub fn parse(movie: &str) -> Result<Movie, String> {
+        let mut lines = movie
+            .lines()
+            .map(|line| line.trim())
+            .filter(|line| !line.is_empty() && !line.starts_with('#'));
+        if lines.next() != Some(MAGIC) {
+            return Err(format!("not an oxi8 movie, first line must be '{}'", MAGIC));
+        }
+        let mut ret = Movie::new(0, 0);
+        for line in lines {
+            let words: Vec<&str> = line.split_whitespace().collect();
+            match words.as_slice() {
+                ["seed", seed] => ret.seed = parse_num(seed, line)?,
+                ["clock", hz] => ret.clock_rate_hz = parse_num(hz, line)?,
+                ["frames", frames] => ret.frames = parse_num(frames, line)?,
+                [frame, event @ ..] => {
+                    let frame = parse_num(frame, line)?;
+                    let event = match event {
+                        ["key", key, state] => {
+                            let key = u8::from_str_radix(key, 16)
+                                .ok()
+                                .and_then(Key::from_u8)
+                                .ok_or_else(|| format!("bad key in line: {}", line))?;
+                            let pressed = match *state {
+                                "down" => true,
+                                "up" => false,
+                                _ => return Err(format!("bad key state in line: {}", line)),
+                            };
+                            MovieEvent::Key(key, pressed)
+                        }
+                        ["clock", hz] => MovieEvent::ClockRate(parse_num(hz, line)?),
+                        ["reset"] => MovieEvent::Reset,
+                        _ => return Err(format!("bad event in line: {}", line)),
+                    };
+                    if frame < ret.events.last().map(|e| e.0).unwrap_or(0) {
+                        return Err(format!("frames go backwards in line: {}", line));
+                    }
+                    ret.push(frame, event);
+                }
+                _ => return Err(format!("bad line: {}", line)),
+            }
+        }
+        if ret.clock_rate_hz == 0 {
+            return Err("movie is missing clock".to_string());
+        }
+        Ok(ret)
+    }
+
+    // replays the whole movie headless, on_frame is called after each 60hz frame is ran
+    pub fn play<T: Display>(
+        &self,
+        rom: &[u8],
+        display: T,
+        on_frame: impl FnMut(&Cpu<T, SeededRand>),
+    ) -> Cpu<T, SeededRand> {
+        let mut cpu = self.new_cpu(rom, display);
+        self.play_on(&mut cpu, on_frame);
+        cpu
+    }
+
+    // a Cpu in the exact state this movie starts from, so it can be set up further before play_on()
+    pub fn new_cpu<T: Display>(&self, rom: &[u8], display: T) -> Cpu<T, SeededRand> {
+        let mut cpu = Cpu::new(rom, display, SeededRand::new(self.seed));
+        cpu.set_clock_rate_hz(self.clock_rate_hz);
+        cpu
+    }
+
+    // cpu must come from new_cpu(), events on frame `frames` happened after the last frame ran
+    // while recording, so they still happen, the cpu just doesn't run a frame after them
+    pub fn play_on<T: Display>(
+        &self,
+        cpu: &mut Cpu<T, SeededRand>,
+        mut on_frame: impl FnMut(&Cpu<T, SeededRand>),
+    ) {
+        let mut events = self.events.iter().peekable();
+        for frame in 0..=self.frames {
+            while let Some((_, event)) = events.peek().filter(|e| e.0 == frame) {
+                match *event {
+                    MovieEvent::Key(key, pressed) => cpu.keyboard.toggle_key(key, pressed),
+                    MovieEvent::ClockRate(hz) => cpu.set_clock_rate_hz(hz),
+                    MovieEvent::Reset => cpu.reset(),
+                }
+                events.next();
+            }
+            if frame == self.frames {
+                break;
+            }
+            cpu.cycle_60hz();
+            on_frame(cpu);
+        }
+    }
+}
+
+fn parse_num<N: std::str::FromStr>(num: &str, line: &str) -> Result<N, String> {
+    num.parse()
+        .map_err(|_| format!("bad number '{}' in line: {}", num, line))
+}
+
+impl fmt::Display for Movie {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{}", MAGIC)?;
+        writeln!(f, "seed {}", self.seed)?;
+        writeln!(f, "clock {}", self.clock_rate_hz)?;
+        writeln!(f, "frames {}", self.frames)?;
+        for (frame, event) in self.events.iter() {
+            match event {
+                MovieEvent::Key(key, pressed) => writeln!(
+                    f,
+                    "{} key {:X} {}",
+                    frame,
+                    *key as u8,
+                    if *pressed { "down" } else { "up" }
+                )?,
+                MovieEvent::ClockRate(hz) => writeln!(f, "{} clock {}", frame, hz)?,
+                MovieEvent::Reset => writeln!(f, "{} reset", frame)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::BoolDisplay;
+
+    // waits for a key and draws its font sprite, then does it again
+    const ROM: [u8; 8] = [
+        0xF0, 0x0A, // LD V0, K
+        0xF0, 0x29, // LD F, V0
+        0xD1, 0x15, // DRW V1, V1, 5
+        0x12, 0x00, // JP 0x200
+    ];
+
+    fn key(key: u8) -> Key {
+        Key::from_u8(key).unwrap()
+    }
+
+    #[test]
+    fn replays_what_was_recorded() {
+        let mut movie = Movie::new(1234, 700);
+        let mut live = movie.new_cpu(&ROM, BoolDisplay::new(1));
+        let mut hashes = Vec::new();
+        for frame in 0..=20 {
+            let event = match frame {
+                3 => Some(MovieEvent::Key(key(5), true)),
+                5 => Some(MovieEvent::Key(key(5), false)),
+                8 => Some(MovieEvent::ClockRate(500)),
+                12 => Some(MovieEvent::Reset),
+                // stopped right after pressing a key
+                20 => Some(MovieEvent::Key(key(0xA), true)),
+                _ => None,
+            };
+            if let Some(event) = event {
+                movie.push(frame, event);
+                match event {
+                    MovieEvent::Key(key, pressed) => live.keyboard.toggle_key(key, pressed),
+                    MovieEvent::ClockRate(hz) => live.set_clock_rate_hz(hz),
+                    MovieEvent::Reset => live.reset(),
+                }
+            }
+            if frame < 20 {
+                live.cycle_60hz();
+                hashes.push(live.display.frame_hash());
+            }
+        }
+        movie.frames = 20;
+
+        let text = movie.to_string();
+        assert!(text.starts_with("oxi8 movie 1\nseed 1234\nclock 700\nframes 20\n3 key 5 down\n"));
+        assert!(text.ends_with("12 reset\n20 key A down\n"));
+        let parsed = Movie::parse(&text).unwrap();
+        assert_eq!(parsed.events(), movie.events());
+        assert_eq!(parsed.to_string(), text);
+
+        let mut replayed = Vec::new();
+        let cpu = parsed.play(&ROM, BoolDisplay::new(1), |cpu| {
+            replayed.push(cpu.display.frame_hash())
+        });
+        assert_eq!(replayed, hashes);
+        assert_eq!(cpu.save_state(), live.save_state());
+        assert!(cpu.keyboard.key_pressed(0xA));
+    }
+
+    #[test]
+    fn errors() {
+        for (movie, error) in [
+            ("movie", "not an oxi8 movie"),
+            ("oxi8 movie 1\nseed 1", "missing clock"),
+            ("oxi8 movie 1\nclock x", "bad number 'x'"),
+            ("oxi8 movie 1\nclock 500\n1 key 10 down", "bad key"),
+            ("oxi8 movie 1\nclock 500\n1 key 1 sideways", "bad key state"),
+            ("oxi8 movie 1\nclock 500\n1 jump", "bad event"),
+            (
+                "oxi8 movie 1\nclock 500\n2 reset\n1 reset",
+                "frames go backwards",
+            ),
+        ] {
+            let err = Movie::parse(movie).err().unwrap();
+            assert!(err.contains(error), "{}: {}", movie, err);
+        }
+    }
+}
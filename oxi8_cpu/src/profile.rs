@@ -0,0 +1,353 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Write,
+};
+
+use crate::{disassemble_with_symbols, Cpu, Display, KeyWait, Rand, DELAY_DECREMENT_HZ, RAM_SIZE};
+
+// counts every instruction run while on, per address and per subroutine, to find where a rom
+// spends its instruction budget
+// a subroutine's inclusive count is every instruction run while it was anywhere on the call
+// stack, its exclusive count only those run directly in it, calls are to the 2nnn target
+// instructions spent waiting are counted separately too: Fx0A running again and again until a
+// key is pressed, and delay timer polling loops, a short loop back that only reads the delay
+// timer and skips on it, like
+//   loop: LD V0, DT / SE V0, 0x00 / JP loop
+// which is time a rom could be using for something else
+// rewinding doesn't take counts back, the instructions did run after all
+
+// the longest polling loop recognised, in instructions
+const POLL_LOOP_MAX: u16 = 6;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct SubroutineProfile {
+    pub calls: u64,
+    pub inclusive: u64,
+    pub exclusive: u64,
+}
+
+#[derive(Default)]
+pub struct Profiler {
+    active: bool,
+    total: u64,
+    counts: Vec<u64>, // per address
+    subroutines: BTreeMap<u16, SubroutineProfile>,
+    stacks: HashMap<Vec<u16>, u64>, // subroutine targets outermost first, to exclusive counts
+    key_wait: u64,
+    polling: u64,
+}
+
+impl Profiler {
+    pub fn start(&mut self) {
+        self.active = true;
+        self.counts.resize(RAM_SIZE, 0);
+    }
+
+    // counts stay until clear()
+    pub fn stop(&mut self) {
+        self.active = false;
+    }
+
+    pub fn clear(&mut self) {
+        let active = self.active;
+        *self = Profiler::default();
+        if active {
+            self.start();
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.active
+    }
+
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+
+    pub fn count(&self, addr: u16) -> u64 {
+        self.counts.get(addr as usize).cloned().unwrap_or(0)
+    }
+
+    pub fn subroutines(&self) -> &BTreeMap<u16, SubroutineProfile> {
+        &self.subroutines
+    }
+
+    // instructions spent on Fx0A with no key pressed yet
+    pub fn key_wait(&self) -> u64 {
+        self.key_wait
+    }
+
+    // instructions spent in delay timer polling loops
+    pub fn polling(&self) -> u64 {
+        self.polling
+    }
+}
+
+impl<T: Display, R: Rand> Cpu<T, R> {
+    // called right before the instruction at pc runs, so calls count for the caller and
+    // returns for the subroutine
+    pub(crate) fn profile_step(&mut self) {
+        let pc = self.pc;
+        let opcode = self.opcode_at(pc);
+        let key_wait =
+            opcode & 0xF0FF == 0xF00A && !matches!(self.keyboard.keywait, KeyWait::Pressed(_));
+        let polling = self.polling_loop(pc, opcode);
+        let stack = &self.stack.targets[..self.stack.sp];
+        let profiler = &mut self.profiler;
+        profiler.total += 1;
+        if let Some(count) = profiler.counts.get_mut(pc as usize) {
+            *count += 1;
+        }
+        if key_wait {
+            profiler.key_wait += 1;
+        }
+        profiler.polling += polling;
+        if opcode & 0xF000 == 0x2000 {
+            profiler
+                .subroutines
+                .entry(opcode & 0xFFF)
+                .or_default()
+                .calls += 1;
+        }
+        for (n, target) in stack.iter().enumerate() {
+            // recursion only counts once
+            if !stack[..n].contains(target) {
+                profiler.subroutines.entry(*target).or_default().inclusive += 1;
+            }
+        }
+        if let Some(target) = stack.last() {
+            profiler.subroutines.entry(*target).or_default().exclusive += 1;
+        }
+        match profiler.stacks.get_mut(stack) {
+            Some(count) => *count += 1,
+            None => {
+                profiler.stacks.insert(stack.to_vec(), 1);
+            }
+        }
+    }
+
+    // for a 1nnn closing a polling loop, how many instructions one time around it took
+    fn polling_loop(&self, pc: u16, opcode: u16) -> u64 {
+        let target = opcode & 0xFFF;
+        if opcode & 0xF000 != 0x1000 || target >= pc || pc - target > POLL_LOOP_MAX * 2 {
+            return 0;
+        }
+        let body: Vec<u16> = (target..pc)
+            .step_by(2)
+            .map(|addr| self.opcode_at(addr))
+            .collect();
+        let reads_delay = body.iter().any(|opcode| opcode & 0xF0FF == 0xF007);
+        let only_polls = body.iter().all(|opcode| match opcode >> 12 {
+            0x3 | 0x4 => true,
+            0x5 | 0x9 => opcode & 0xF == 0,
+            0xF => opcode & 0xFF == 0x07,
+            _ => false,
+        });
+        if reads_delay && only_polls {
+            body.len() as u64 + 1
+        } else {
+            0
+        }
+    }
+
+    // a sorted text report, with the top hottest addresses and every subroutine
+    pub fn profile_report(&self, top: usize) -> String {
+        let profiler = &self.profiler;
+        let total = profiler.total.max(1);
+        let percent = |count: u64| count as f64 * 100.0 / total as f64;
+        // the rate instructions really run at, a whole number of them every 60hz frame
+        let per_second = DELAY_DECREMENT_HZ * self.num_instructions_per_decrement.max(1);
+        let mut report = String::new();
+        let _ = writeln!(
+            report,
+            "{} instructions, {:.1} seconds at {} per second",
+            profiler.total,
+            profiler.total as f64 / per_second as f64,
+            per_second
+        );
+        let _ = writeln!(
+            report,
+            "waiting for a key (Fx0A): {} ({:.1}%)",
+            profiler.key_wait,
+            percent(profiler.key_wait)
+        );
+        let _ = writeln!(
+            report,
+            "polling the delay timer: {} ({:.1}%)",
+            profiler.polling,
+            percent(profiler.polling)
+        );
+
+        let mut hottest: Vec<(u16, u64)> = profiler
+            .counts
+            .iter()
+            .enumerate()
+            .filter(|(_, count)| **count > 0)
+            .map(|(addr, count)| (addr as u16, *count))
+            .collect();
+        hottest.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        let _ = writeln!(
+            report,
+            "\nhottest addresses:\n{:>10} {:>6}  address",
+            "count", "%"
+        );
+        for (addr, count) in hottest.into_iter().take(top) {
+            let opcode = self.opcode_at(addr);
+            let _ = writeln!(
+                report,
+                "{:>10} {:>5.1}%  {}  {:04X}  {}",
+                count,
+                percent(count),
+                self.symbols.format(addr),
+                opcode,
+                disassemble_with_symbols(opcode, &self.symbols)
+            );
+        }
+
+        let mut subroutines: Vec<(&u16, &SubroutineProfile)> =
+            profiler.subroutines.iter().collect();
+        subroutines.sort_by(|a, b| b.1.inclusive.cmp(&a.1.inclusive).then(a.0.cmp(b.0)));
+        let _ = writeln!(
+            report,
+            "\nsubroutines:\n{:>8} {:>10} {:>6} {:>10} {:>6}  subroutine",
+            "calls", "inclusive", "%", "exclusive", "%"
+        );
+        for (target, sub) in subroutines {
+            let _ = writeln!(
+                report,
+                "{:>8} {:>10} {:>5.1}% {:>10} {:>5.1}%  {}",
+                sub.calls,
+                sub.inclusive,
+                percent(sub.inclusive),
+                sub.exclusive,
+                percent(sub.exclusive),
+                self.symbols.format(*target)
+            );
+        }
+        report
+    }
+
+    // one 'main;outer;inner count' line per call stack seen, the collapsed format flame graph
+    // tools like flamegraph.pl and inferno read, subroutines named by label where there is one
+    pub fn profile_collapsed(&self) -> String {
+        let name = |target: &u16| match self.symbols.name(*target) {
+            Some(name) => name.to_string(),
+            None => format!("0x{:03X}", target),
+        };
+        let mut lines: Vec<String> = self
+            .profiler
+            .stacks
+            .iter()
+            .map(|(stack, count)| {
+                let mut line = "main".to_string();
+                for target in stack {
+                    line.push(';');
+                    line.push_str(&name(target));
+                }
+                format!("{} {}", line, count)
+            })
+            .collect();
+        lines.sort();
+        lines.iter().map(|line| format!("{}\n", line)).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{BoolDisplay, SeededRand};
+
+    // f counts v0 down calling itself, then calls g on the way back out of each call
+    const CALLS: [u8; 18] = [
+        0x60, 0x02, // LD V0, 2
+        0x22, 0x06, // CALL f
+        0x12, 0x04, // JP 0x204
+        0x70, 0xFF, // f: ADD V0, -1
+        0x30, 0x00, // SE V0, 0
+        0x22, 0x06, // CALL f
+        0x22, 0x10, // CALL g
+        0x00, 0xEE, // RET
+        0x00, 0xEE, // g: RET
+    ];
+
+    fn profiled(rom: &[u8]) -> Cpu<BoolDisplay, SeededRand> {
+        let mut cpu = Cpu::new(rom, BoolDisplay::new(1), SeededRand::new(0));
+        cpu.profiler.start();
+        cpu
+    }
+
+    #[test]
+    fn counts_subroutines() {
+        let mut cpu = profiled(&CALLS);
+        for _ in 0..15 {
+            cpu.step();
+        }
+        let profiler = &cpu.profiler;
+        assert_eq!(profiler.total(), 15);
+        assert_eq!(profiler.count(0x204), 2);
+        assert_eq!(profiler.count(0x210), 2);
+        // the recursive call only counts once towards inclusive
+        let f = SubroutineProfile {
+            calls: 2,
+            inclusive: 11,
+            exclusive: 9,
+        };
+        let g = SubroutineProfile {
+            calls: 2,
+            inclusive: 2,
+            exclusive: 2,
+        };
+        assert_eq!(profiler.subroutines()[&0x206], f);
+        assert_eq!(profiler.subroutines()[&0x210], g);
+        // jumping to itself isn't polling
+        assert_eq!(profiler.polling(), 0);
+        assert_eq!(profiler.key_wait(), 0);
+    }
+
+    #[test]
+    fn collapses_stacks() {
+        let mut cpu = profiled(&CALLS);
+        cpu.symbols.insert("f", 0x206);
+        for _ in 0..15 {
+            cpu.step();
+        }
+        assert_eq!(
+            cpu.profile_collapsed(),
+            "main 4\nmain;f 5\nmain;f;0x210 1\nmain;f;f 4\nmain;f;f;0x210 1\n"
+        );
+        let report = cpu.profile_report(1);
+        assert!(report.starts_with("15 instructions"), "{}", report);
+        assert!(
+            report.contains("  2  13.3%  0x204  1204  JP 0x204\n"),
+            "{}",
+            report
+        );
+        assert!(
+            report.contains("  2         11  73.3%          9  60.0%  0x206 <f>\n"),
+            "{}",
+            report
+        );
+    }
+
+    #[test]
+    fn counts_waiting() {
+        let rom = [
+            0x60, 0x03, // LD V0, 3
+            0xF0, 0x15, // LD DT, V0
+            0xF1, 0x07, // LD V1, DT
+            0x31, 0x00, // SE V1, 0
+            0x12, 0x04, // JP 0x204
+            0xF2, 0x0A, // LD V2, K
+        ];
+        let mut cpu = profiled(&rom);
+        for _ in 0..10 {
+            cpu.cycle_60hz();
+        }
+        let profiler = &cpu.profiler;
+        // each time around the loop is 3 instructions, counted when it jumps back
+        assert!(profiler.count(0x208) > 0);
+        assert_eq!(profiler.polling(), 3 * profiler.count(0x208));
+        assert!(profiler.key_wait() > 0);
+        assert_eq!(profiler.key_wait(), profiler.count(0x20A));
+    }
+}
@@ -0,0 +1,239 @@
+#[cfg(feature = "recorder")]
+use std::io::{self, Write};
+
+use crate::{Cpu, Display, Rand, RAM_SIZE};
+
+// ram drawn as a 1 bit bitmap, each byte a row of 8 pixels, high bit on the left, the way Dxyn
+// draws it, so sprites and fonts show up as the pictures they are
+// bytes run down columns of COLUMN_ROWS rows, column_width bytes side by side per row, 1 for
+// regular sprites, 2 for 16x16 SCHIP ones, and columns go left to right with a gap between
+//   0x000 0x100 0x200 ...
+//   0x001 0x101 0x201
+//   ...
+// the 2 bytes of the instruction at pc, the byte at I and the rest of the sprite the next Dxyn
+// would draw from I are tinted, so it's easy to see what the rom is looking at
+
+const COLUMN_ROWS: usize = 256;
+const GAP: usize = 2; // pixels between columns
+
+// RGBA, off then on
+const PLAIN: [[u8; 4]; 2] = [[0x00, 0x00, 0x00, 0xFF], [0xFF, 0xFF, 0xFF, 0xFF]];
+const PC: [[u8; 4]; 2] = [[0x00, 0x50, 0x00, 0xFF], [0x40, 0xFF, 0x40, 0xFF]];
+const I: [[u8; 4]; 2] = [[0x60, 0x00, 0x00, 0xFF], [0xFF, 0x40, 0x40, 0xFF]];
+const SPRITE: [[u8; 4]; 2] = [[0x00, 0x20, 0x60, 0xFF], [0x60, 0xA0, 0xFF, 0xFF]];
+const BACKGROUND: [u8; 4] = [0x30, 0x30, 0x30, 0xFF];
+
+// an RGBA image, 4 bytes per pixel, rows top to bottom
+#[derive(Clone, Debug, PartialEq)]
+pub struct RamImage {
+    pub width: usize,
+    pub height: usize,
+    pub rgba: Vec<u8>,
+}
+
+impl RamImage {
+    fn new(width: usize, height: usize, color: [u8; 4]) -> RamImage {
+        RamImage {
+            width,
+            height,
+            rgba: color.repeat(width * height),
+        }
+    }
+
+    fn set(&mut self, x: usize, y: usize, color: [u8; 4]) {
+        let at = (y * self.width + x) * 4;
+        self.rgba[at..at + 4].copy_from_slice(&color);
+    }
+
+    // each pixel becomes a factor x factor square, for frontends without a scaling blit
+    pub fn scale(&self, factor: usize) -> RamImage {
+        let factor = factor.max(1);
+        let mut scaled = RamImage::new(self.width * factor, self.height * factor, BACKGROUND);
+        for y in 0..scaled.height {
+            for x in 0..scaled.width {
+                let at = ((y / factor) * self.width + x / factor) * 4;
+                let mut color = [0; 4];
+                color.copy_from_slice(&self.rgba[at..at + 4]);
+                scaled.set(x, y, color);
+            }
+        }
+        scaled
+    }
+
+    // PNG encoding comes with the recorder feature
+    #[cfg(feature = "recorder")]
+    pub fn write_png<W: Write>(&self, w: W) -> io::Result<()> {
+        let mut encoder = png::Encoder::new(w, self.width as u32, self.height as u32);
+        encoder.set_color(png::ColorType::Rgba);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header()?;
+        writer.write_image_data(&self.rgba)?;
+        writer.finish()?;
+        Ok(())
+    }
+}
+
+// the sprite the next Dxyn would draw, at its real size
+#[derive(Clone, Debug, PartialEq)]
+pub struct SpritePreview {
+    pub addr: u16, // of the Dxyn
+    pub opcode: u16,
+    pub i: u16, // where it would draw from, as I is now
+    pub image: RamImage,
+}
+
+// how far ahead of pc to look for a Dxyn, in instructions
+const SPRITE_LOOKAHEAD: u16 = 32;
+
+impl<T: Display, R: Rand> Cpu<T, R> {
+    // column_width is in bytes, at least 1
+    pub fn ram_image(&self, column_width: usize) -> RamImage {
+        let column_width = column_width.max(1);
+        let column_bytes = COLUMN_ROWS * column_width;
+        let columns = RAM_SIZE.div_ceil(column_bytes);
+        let column_pixels = column_width * 8;
+        let mut image = RamImage::new(
+            columns * (column_pixels + GAP) - GAP,
+            COLUMN_ROWS,
+            BACKGROUND,
+        );
+        let pc = self.pc as usize..self.pc as usize + 2;
+        let i = self.i as usize;
+        let sprite = self
+            .next_draw()
+            .and_then(|(_, opcode)| self.sprite_range(opcode))
+            .unwrap_or(0..0);
+        for (addr, byte) in self.ram.iter().enumerate() {
+            let colors = if pc.contains(&addr) {
+                PC
+            } else if addr == i {
+                I
+            } else if sprite.contains(&addr) {
+                SPRITE
+            } else {
+                PLAIN
+            };
+            let column = addr / column_bytes;
+            let row = (addr % column_bytes) / column_width;
+            let x = column * (column_pixels + GAP) + (addr % column_width) * 8;
+            for bit in 0..8 {
+                let on = byte & (0x80 >> bit) != 0;
+                image.set(x + bit, row, colors[on as usize]);
+            }
+        }
+        image
+    }
+
+    // the first Dxyn at or after pc, looking straight ahead, with the bytes from I it would draw,
+    // one sprite per selected plane side by side, None if there isn't one close by, or if a jump,
+    // call, return or change to I comes first, since then it might not draw from I as it is now
+    pub fn sprite_preview(&self) -> Option<SpritePreview> {
+        let (addr, opcode) = self.next_draw()?;
+        let range = self.sprite_range(opcode)?;
+        // Dxy0 is 16x16, 2 bytes a row
+        let (width, rows) = match opcode & 0xF {
+            0 => (2, 16),
+            n => (1, n as usize),
+        };
+        let planes = range.len() / (width * rows);
+        let mut image = RamImage::new(planes * (width * 8 + GAP) - GAP, rows, BACKGROUND);
+        for plane in 0..planes {
+            let start = range.start + plane * width * rows;
+            for n in 0..width * rows {
+                let byte = self.ram.get(start + n).cloned().unwrap_or(0);
+                let x = plane * (width * 8 + GAP) + (n % width) * 8;
+                for bit in 0..8 {
+                    let on = byte & (0x80 >> bit) != 0;
+                    image.set(x + bit, n / width, PLAIN[on as usize]);
+                }
+            }
+        }
+        Some(SpritePreview {
+            addr,
+            opcode,
+            i: self.i,
+            image,
+        })
+    }
+
+    fn next_draw(&self) -> Option<(u16, u16)> {
+        for n in 0..SPRITE_LOOKAHEAD {
+            let addr = self.pc.wrapping_add(n * 2);
+            if addr as usize >= RAM_SIZE {
+                return None;
+            }
+            let opcode = self.opcode_at(addr);
+            // skips are stepped over, whichever way they go the Dxyn after is close
+            let stops = match opcode >> 12 {
+                0xD => return Some((addr, opcode)),
+                0x0 => matches!(opcode, 0x0000 | 0x00EE | 0x00FD),
+                0x1 | 0x2 | 0xA | 0xB => true,
+                0xF => matches!(opcode & 0xFF, 0x1E | 0x29 | 0x30),
+                _ => false,
+            };
+            if stops {
+                return None;
+            }
+        }
+        None
+    }
+
+    // what Dxyn reads from I, same as memory_access() for it
+    fn sprite_range(&self, opcode: u16) -> Option<std::ops::Range<usize>> {
+        let planes = self.display.selected_planes().count_selected();
+        if planes == 0 {
+            return None;
+        }
+        let len = match opcode & 0xF {
+            0 => 32,
+            n => n as usize,
+        };
+        let start = self.i as usize;
+        Some(start..start + len * planes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{BoolDisplay, SeededRand};
+
+    fn preview(rom: &[u8]) -> Option<SpritePreview> {
+        let mut cpu = Cpu::new(rom, BoolDisplay::new(1), SeededRand::new(0));
+        cpu.execute_next_instruction();
+        cpu.sprite_preview()
+    }
+
+    #[test]
+    fn looks_past_skips() {
+        let preview = preview(&[
+            0xA0, 0x00, // LD I, 0x000
+            0x30, 0x00, // SE V0, 0
+            0x70, 0x01, // ADD V0, 1
+            0xD0, 0x15, // DRW V0, V1, 5
+        ])
+        .unwrap();
+        assert_eq!(
+            (preview.addr, preview.opcode, preview.i),
+            (0x206, 0xD015, 0)
+        );
+        assert_eq!((preview.image.width, preview.image.height), (8, 5));
+    }
+
+    #[test]
+    fn stops_where_i_or_pc_change() {
+        for stop in [
+            [0x12, 0x00], // JP 0x200
+            [0x22, 0x00], // CALL 0x200
+            [0x00, 0xEE], // RET
+            [0xB2, 0x00], // JP V0, 0x200
+            [0xA0, 0x05], // LD I, 0x005
+            [0xF0, 0x1E], // ADD I, V0
+            [0xF0, 0x29], // LD F, V0
+            [0xF0, 0x30], // LD HF, V0
+        ] {
+            let rom = [0xA0, 0x00, stop[0], stop[1], 0xD0, 0x15];
+            assert_eq!(preview(&rom), None, "{:02X}{:02X}", stop[0], stop[1]);
+        }
+    }
+}
@@ -0,0 +1,182 @@
+use std::io::{self, Write};
+
+use gif::SetParameter;
+
+use crate::{Display, DISPLAY_HEIGHT, DISPLAY_WIDTH};
+
+// every frame is recorded at the hires size so lores and hires frames can share one animation
+const WIDTH: usize = DISPLAY_WIDTH as usize * 2;
+const HEIGHT: usize = DISPLAY_HEIGHT as usize * 2;
+
+const FRAMES_PER_SEC: u32 = 60; // capture() is called at the same rate timers decrement
+
+// black off, white on, same as the frontends draw
+const PALETTE: [u8; 6] = [0x00, 0x00, 0x00, 0xFF, 0xFF, 0xFF];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RecordFormat {
+    Gif,
+    Apng,
+}
+
+impl RecordFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            RecordFormat::Gif => "gif",
+            RecordFormat::Apng => "png",
+        }
+    }
+}
+
+struct Frame {
+    pixels: Vec<u8>, // WIDTH * HEIGHT, 1 for on 0 for off
+    ticks: u32,      // how many 60hz frames this was on screen for
+}
+
+// captures a Display once per 60hz frame, only keeping frames that differ from the last one
+pub struct Recorder {
+    frames: Vec<Frame>,
+}
+
+impl Recorder {
+    pub fn new() -> Recorder {
+        Recorder { frames: Vec::new() }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    // this MUST be called once per 60hz frame, right after Cpu::cycle_60hz
+    pub fn capture<T: Display>(&mut self, display: &T) {
+        let scale_x = WIDTH / display.width();
+        let scale_y = HEIGHT / display.height();
+        let mut pixels = vec![0; WIDTH * HEIGHT];
+        for (index, pixel) in pixels.iter_mut().enumerate() {
+            let x = (index % WIDTH) / scale_x;
+            let y = (index / WIDTH) / scale_y;
+            *pixel = (display.current_pixel(x, y) != 0) as u8;
+        }
+        match self.frames.last_mut() {
+            Some(last) if last.pixels == pixels => last.ticks += 1,
+            _ => self.frames.push(Frame { pixels, ticks: 1 }),
+        }
+    }
+
+    pub fn encode<W: Write>(&self, format: RecordFormat, scale: u32, w: W) -> io::Result<()> {
+        if self.frames.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "no frames recorded",
+            ));
+        }
+        let scale = scale.max(1) as usize;
+        match format {
+            RecordFormat::Gif => self.encode_gif(scale, w),
+            RecordFormat::Apng => self.encode_apng(scale, w),
+        }
+    }
+
+    fn encode_gif<W: Write>(&self, scale: usize, w: W) -> io::Result<()> {
+        let (width, height) = (WIDTH * scale, HEIGHT * scale);
+        let mut encoder = gif::Encoder::new(w, width as u16, height as u16, &PALETTE)?;
+        encoder.set(gif::Repeat::Infinite)?;
+        // gif delays are in 1/100ths of a second, so round against the total elapsed time
+        // instead of per frame, otherwise a long recording drifts away from the 60hz clock
+        let mut elapsed_ticks = 0;
+        for frame in self.frames.iter() {
+            let start = elapsed_ticks * 100 / FRAMES_PER_SEC;
+            elapsed_ticks += frame.ticks;
+            let end = elapsed_ticks * 100 / FRAMES_PER_SEC;
+            let mut gif_frame = gif::Frame::from_indexed_pixels(
+                width as u16,
+                height as u16,
+                &scale_pixels(&frame.pixels, scale),
+                None,
+            );
+            gif_frame.delay = (end - start).min(u16::MAX as u32) as u16;
+            encoder.write_frame(&gif_frame)?;
+        }
+        Ok(())
+    }
+
+    fn encode_apng<W: Write>(&self, scale: usize, w: W) -> io::Result<()> {
+        let mut encoder = png::Encoder::new(w, (WIDTH * scale) as u32, (HEIGHT * scale) as u32);
+        encoder.set_color(png::ColorType::Indexed);
+        encoder.set_depth(png::BitDepth::Eight);
+        encoder.set_palette(&PALETTE[..]);
+        encoder.set_animated(self.frames.len() as u32, 0)?;
+        let mut writer = encoder.write_header()?;
+        for frame in self.frames.iter() {
+            // apng can express 1/60ths of a second exactly
+            let ticks = frame.ticks.min(u16::MAX as u32) as u16;
+            writer.set_frame_delay(ticks, FRAMES_PER_SEC as u16)?;
+            writer.write_image_data(&scale_pixels(&frame.pixels, scale))?;
+        }
+        writer.finish()?;
+        Ok(())
+    }
+}
+
+impl Default for Recorder {
+    fn default() -> Self {
+        Recorder::new()
+    }
+}
+
+fn scale_pixels(pixels: &[u8], scale: usize) -> Vec<u8> {
+    if scale == 1 {
+        return pixels.to_vec();
+    }
+    let mut scaled = Vec::with_capacity(pixels.len() * scale * scale);
+    for row in pixels.chunks(WIDTH) {
+        let scaled_row: Vec<u8> = row
+            .iter()
+            .flat_map(|pixel| std::iter::repeat(*pixel).take(scale))
+            .collect();
+        for _ in 0..scale {
+            scaled.extend_from_slice(&scaled_row);
+        }
+    }
+    scaled
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::BoolDisplay;
+
+    #[test]
+    fn keeps_only_changed_frames() {
+        let mut recorder = Recorder::new();
+        let mut display = BoolDisplay::new(1);
+        assert!(recorder.encode(RecordFormat::Gif, 1, Vec::new()).is_err());
+        recorder.capture(&display);
+        recorder.capture(&display);
+        display.draw(0, 0, &[0x80]);
+        recorder.capture(&display);
+        // a lores pixel is 2x2 at the hires size
+        assert_eq!(recorder.len(), 2);
+        assert_eq!(recorder.frames[0].ticks, 2);
+        assert_eq!(recorder.frames[1].pixels[..3], [1, 1, 0]);
+        assert_eq!(recorder.frames[1].pixels[WIDTH..WIDTH + 3], [1, 1, 0]);
+        display.set_hires(true);
+        display.draw(0, 0, &[0x80]);
+        recorder.capture(&display);
+        assert_eq!(recorder.frames[2].pixels[..2], [1, 0]);
+
+        let mut gif = Vec::new();
+        recorder.encode(RecordFormat::Gif, 2, &mut gif).unwrap();
+        assert!(gif.starts_with(b"GIF89a"));
+        // logical screen size, little endian
+        assert_eq!(gif[6..10], [0, 1, 128, 0]);
+        let mut apng = Vec::new();
+        recorder.encode(RecordFormat::Apng, 1, &mut apng).unwrap();
+        assert!(apng.starts_with(b"\x89PNG\r\n\x1a\n"));
+        assert!(apng.windows(4).any(|chunk| chunk == b"acTL"));
+    }
+}
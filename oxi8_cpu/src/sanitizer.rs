@@ -0,0 +1,422 @@
+use core::fmt;
+use std::collections::HashSet;
+
+use crate::{Cpu, Display, Rand, StopReason, TimelineEvent, RAM_SIZE, SPRITE_LEN};
+
+// strict mode: checks every instruction right before it runs for things a working rom has no
+// business doing, which on real hardware or other interpreters tend to be the bugs that only
+// show up sometimes
+//   running code outside the rom, like after falling off its end or jumping through a bad table
+//   jumping into the middle of an instruction that already ran, a 2 byte misaligned pc
+//   reading V0-VF or I before anything set them, other interpreters don't all start them at 0
+//   writing into the font at 0x000-0x0EF
+//   I running past the end of ram in Fx1E, or an access from I, like Fx55/Fx65, going past it
+//   drawing a sprite from ram that was never loaded or written
+//   00EE with nothing to return to, and calling from a call site that is still waiting on a
+//   return, which is a subroutine that left with a jump instead of 00EE, unless the routine the
+//   call site is in has been called again since, which is recursion
+// each problem is reported once per address, in warn mode as findings to take, in stop mode also
+// by step() and cycle_60hz() stopping right before the instruction, which then runs when resumed
+// findings also go on the timeline while it's on
+// like the profiler, rewinding doesn't take anything back
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum SanitizerMode {
+    #[default]
+    Off,
+    Warn,
+    Stop,
+}
+
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Problem {
+    OutsideProgram,
+    Misaligned,
+    UnsetRegister(u8), // 0-F for V0-VF, 0x10 for I
+    FontWrite(u16),    // the first font address written
+    IndexPastRam(u16), // I
+    UnsetSprite(u16),  // the first sprite address never loaded or written
+    ReturnWithoutCall,
+    CallNotReturned(u16), // the subroutine that never returned
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Problem::OutsideProgram => write!(f, "running code outside the rom"),
+            Problem::Misaligned => write!(f, "running from the middle of an instruction"),
+            Problem::UnsetRegister(I_BIT) => write!(f, "reading I before anything set it"),
+            Problem::UnsetRegister(x) => write!(f, "reading V{:X} before anything set it", x),
+            Problem::FontWrite(addr) => write!(f, "writing the font at 0x{:03X}", addr),
+            Problem::IndexPastRam(i) => write!(f, "I 0x{:03X} going past the end of ram", i),
+            Problem::UnsetSprite(addr) => write!(
+                f,
+                "drawing a sprite from 0x{:03X}, never loaded or written",
+                addr
+            ),
+            Problem::ReturnWithoutCall => write!(f, "returning with nothing on the stack"),
+            Problem::CallNotReturned(target) => write!(
+                f,
+                "calling again from a call to 0x{:03X} that never returned",
+                target
+            ),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Finding {
+    pub cycle: u64, // Cpu::cycles() right before the instruction
+    pub pc: u16,
+    pub opcode: u16,
+    pub problem: Problem,
+}
+
+impl fmt::Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "0x{:03X} {:04X} at cycle {}: {}",
+            self.pc, self.opcode, self.cycle, self.problem
+        )
+    }
+}
+
+// register bits, 1 << x for Vx
+const I_BIT: u8 = 0x10;
+const ALL_REGISTERS: u32 = 0x1FFFF;
+
+// per byte of ram
+const INITIALIZED: u8 = 1;
+const INSTRUCTION: u8 = 2; // first byte of an instruction that ran
+const OPERAND: u8 = 4; // second byte of one
+
+#[derive(Default)]
+pub struct Sanitizer {
+    mode: SanitizerMode,
+    set: u32, // registers written, I_BIT for I
+    flags: Vec<u8>,
+    seen: HashSet<(u16, Problem)>,
+    findings: Vec<Finding>,
+    checked: Option<u64>, // cycles when last checked, stop mode checks in step() before running
+}
+
+impl Sanitizer {
+    pub fn mode(&self) -> SanitizerMode {
+        self.mode
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.mode != SanitizerMode::Off
+    }
+
+    // everything found since the last take, oldest first
+    pub fn take_findings(&mut self) -> Vec<Finding> {
+        std::mem::take(&mut self.findings)
+    }
+}
+
+impl<T: Display, R: Rand> Cpu<T, R> {
+    // turned on at power on, registers count as unset and only the font and rom as loaded,
+    // turned on later everything counts as set already, since what happened before is unknown
+    // changing between warn and stop keeps what was found
+    pub fn sanitize(&mut self, mode: SanitizerMode) {
+        let rom = self.rom_range();
+        let sanitizer = &mut self.sanitizer;
+        let was_active = sanitizer.is_active();
+        sanitizer.mode = mode;
+        if mode == SanitizerMode::Off {
+            *sanitizer = Sanitizer::default();
+            return;
+        }
+        if was_active {
+            return;
+        }
+        if self.cycles == 0 {
+            sanitizer.set = 0;
+            sanitizer.flags = vec![0; RAM_SIZE];
+            sanitizer.flags[..SPRITE_LEN].fill(INITIALIZED);
+            sanitizer.flags[rom.start as usize..rom.end as usize].fill(INITIALIZED);
+        } else {
+            sanitizer.set = ALL_REGISTERS;
+            sanitizer.flags = vec![INITIALIZED; RAM_SIZE];
+        }
+    }
+
+    // in stop mode, the first new problem with the next instruction, which is then left to run
+    pub(crate) fn sanitize_stop(&mut self) -> Option<StopReason> {
+        if self.sanitizer.mode != SanitizerMode::Stop {
+            return None;
+        }
+        let finding = self.sanitize_step()?;
+        Some(self.stopped(StopReason::Sanitizer(finding)))
+    }
+
+    // checks the instruction at pc and notes what it sets, the first new finding if any
+    // an instruction is only checked once, so running it again for the same one finds nothing
+    pub(crate) fn sanitize_step(&mut self) -> Option<Finding> {
+        if self.sanitizer.checked.replace(self.cycles) == Some(self.cycles) {
+            return None;
+        }
+        let pc = self.pc;
+        let opcode = self.opcode_at(pc);
+        let access = self.memory_access();
+        let (reads, writes) = register_use(opcode);
+        let mut problems = Vec::new();
+
+        if !self.rom_range().contains(&pc) {
+            problems.push(Problem::OutsideProgram);
+        }
+        let flags = &self.sanitizer.flags;
+        let at = |addr: u16| flags.get(addr as usize).cloned().unwrap_or(0);
+        if at(pc) & OPERAND != 0 || at(pc.wrapping_add(1)) & INSTRUCTION != 0 {
+            problems.push(Problem::Misaligned);
+        }
+        let unset = reads & !self.sanitizer.set;
+        for bit in 0..=I_BIT {
+            if unset & (1 << bit) != 0 {
+                problems.push(Problem::UnsetRegister(bit));
+            }
+        }
+        let past_ram = match opcode & 0xF0FF {
+            0xF01E => self.i as usize + self.v[(opcode as usize >> 8) & 0xF] as usize >= RAM_SIZE,
+            _ => false,
+        } || access
+            .read
+            .iter()
+            .chain(access.write.iter())
+            .any(|range| range.end as usize > RAM_SIZE);
+        if past_ram {
+            problems.push(Problem::IndexPastRam(self.i));
+        }
+        if let Some(addr) = access
+            .write
+            .clone()
+            .and_then(|mut write| write.find(|addr| (*addr as usize) < SPRITE_LEN))
+        {
+            problems.push(Problem::FontWrite(addr));
+        }
+        if opcode & 0xF000 == 0xD000 {
+            if let Some(addr) = access
+                .read
+                .clone()
+                .and_then(|mut read| read.find(|addr| at(*addr) & INITIALIZED == 0))
+            {
+                problems.push(Problem::UnsetSprite(addr));
+            }
+        }
+        let sp = self.stack.sp;
+        if opcode == 0x00EE && sp == 0 {
+            problems.push(Problem::ReturnWithoutCall);
+        }
+        if opcode & 0xF000 == 0x2000 {
+            let stack = &self.stack;
+            if let Some(n) = stack.stack[..sp].iter().rposition(|from| *from == pc) {
+                // the frame under n is the routine pc is in, none for the main program
+                let recursed = n > 0 && stack.targets[n..sp].contains(&stack.targets[n - 1]);
+                if !recursed {
+                    problems.push(Problem::CallNotReturned(stack.targets[n]));
+                }
+            }
+        }
+
+        let sanitizer = &mut self.sanitizer;
+        sanitizer.set |= writes;
+        for (addr, flag) in [(pc, INSTRUCTION), (pc.wrapping_add(1), OPERAND)].iter() {
+            if let Some(flags) = sanitizer.flags.get_mut(*addr as usize) {
+                *flags |= flag;
+            }
+        }
+        for addr in access.write.unwrap_or(0..0) {
+            if let Some(flags) = sanitizer.flags.get_mut(addr as usize) {
+                *flags |= INITIALIZED;
+            }
+        }
+        let mut found = Vec::new();
+        for problem in problems {
+            if sanitizer.seen.insert((pc, problem)) {
+                found.push(Finding {
+                    cycle: self.cycles,
+                    pc,
+                    opcode,
+                    problem,
+                });
+            }
+        }
+        sanitizer.findings.extend(found.iter().cloned());
+        if self.timeline.is_active() {
+            for finding in found.iter() {
+                self.timeline_push(TimelineEvent::Error(finding.problem.to_string()));
+            }
+        }
+        found.first().cloned()
+    }
+}
+
+// the registers an opcode reads and writes, as bits
+// a register whose value can't change the result isn't read, like 8xx3 which always gives 0
+fn register_use(opcode: u16) -> (u32, u32) {
+    let vx = 1 << ((opcode >> 8) & 0xF);
+    let vy = 1 << ((opcode >> 4) & 0xF);
+    let vf = 1 << 0xF;
+    let i = 1 << I_BIT;
+    let up_to_vx = (vx << 1) - 1;
+    match opcode >> 12 {
+        0x3 | 0x4 | 0xE => (vx, 0),
+        0x5 | 0x9 => (vx | vy, 0),
+        0x6 | 0xC => (0, vx),
+        0x7 => (vx, vx),
+        0x8 => match opcode & 0xF {
+            0x0 => (vy, vx),
+            0x3 | 0x5 | 0x7 if vx == vy => (0, vx | vf),
+            0x1..=0x3 => (vx | vy, vx),
+            0x4 | 0x5 | 0x7 => (vx | vy, vx | vf),
+            0x6 | 0xE => (vx, vx | vf),
+            _ => (0, 0),
+        },
+        0xA => (0, i),
+        0xB => (1, 0),
+        0xD => (vx | vy | i, vf),
+        0xF => match opcode & 0xFF {
+            0x07 | 0x0A => (0, vx),
+            0x15 | 0x18 => (vx, 0),
+            0x1E => (vx | i, i),
+            0x29 | 0x30 => (vx, i),
+            0x33 => (vx | i, 0),
+            0x55 => (up_to_vx | i, 0),
+            0x65 => (i, up_to_vx),
+            0x75 => (up_to_vx, 0),
+            0x85 => (0, up_to_vx),
+            _ => (0, 0),
+        },
+        _ => (0, 0),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{BoolDisplay, SeededRand};
+
+    fn findings(rom: &[u8], instructions: usize) -> Vec<Problem> {
+        let mut cpu = Cpu::new(rom, BoolDisplay::new(1), SeededRand::new(0));
+        cpu.sanitize(SanitizerMode::Warn);
+        for _ in 0..instructions {
+            cpu.step();
+        }
+        let findings = cpu.sanitizer.take_findings();
+        findings.iter().map(|finding| finding.problem).collect()
+    }
+
+    #[test]
+    fn recursion_is_not_a_leak() {
+        // f counts v0 down, calling g, which calls f, until it gets to 0
+        let rom = [
+            0x60, 0x04, // LD V0, 4
+            0x22, 0x06, // CALL f
+            0x12, 0x04, // JP 0x204
+            0x70, 0xFF, // f: ADD V0, -1
+            0x30, 0x00, // SE V0, 0
+            0x22, 0x0E, // CALL g
+            0x00, 0xEE, // RET
+            0x22, 0x06, // g: CALL f
+            0x00, 0xEE, // RET
+        ];
+        assert_eq!(findings(&rom, 100), []);
+    }
+
+    #[test]
+    fn jumping_out_of_a_call_is_a_leak() {
+        let rom = [
+            0x22, 0x04, // CALL 0x204
+            0x00, 0x00, // never runs
+            0x12, 0x00, // JP 0x200, instead of returning
+        ];
+        assert_eq!(findings(&rom, 4), [Problem::CallNotReturned(0x204)]);
+    }
+
+    #[test]
+    fn running_outside_the_rom() {
+        let rom = [
+            0x60, 0x12, // LD V0, 0x12
+            0x61, 0x0A, // LD V1, 0x0A
+            0xA2, 0x0A, // LD I, 0x20A
+            0xF1, 0x55, // LD [I], V1, writing JP 0x20A right after the rom
+            0x12, 0x0A, // JP 0x20A
+        ];
+        assert_eq!(findings(&rom, 10), [Problem::OutsideProgram]);
+    }
+
+    #[test]
+    fn misaligned() {
+        let rom = [
+            0x60, 0x12, // LD V0, 0x12
+            0x12, 0x01, // JP 0x201, which is 1212, JP 0x212
+        ];
+        assert_eq!(findings(&rom, 3), [Problem::Misaligned]);
+    }
+
+    #[test]
+    fn unset_registers() {
+        let rom = [
+            0x70, 0x01, // ADD V0, 1
+            0xF0, 0x1E, // ADD I, V0
+            0x12, 0x04, // JP 0x204
+        ];
+        assert_eq!(
+            findings(&rom, 3),
+            [Problem::UnsetRegister(0), Problem::UnsetRegister(I_BIT)]
+        );
+    }
+
+    #[test]
+    fn font_writes() {
+        let rom = [
+            0xA0, 0x05, // LD I, 0x005
+            0x60, 0x00, // LD V0, 0
+            0xF0, 0x55, // LD [I], V0
+            0x12, 0x06, // JP 0x206
+        ];
+        assert_eq!(findings(&rom, 4), [Problem::FontWrite(0x005)]);
+    }
+
+    #[test]
+    fn index_past_ram() {
+        let rom = [
+            0xAF, 0xFF, // LD I, 0xFFF
+            0x60, 0x01, // LD V0, 1
+            0xF0, 0x1E, // ADD I, V0
+            0x12, 0x06, // JP 0x206
+        ];
+        assert_eq!(findings(&rom, 4), [Problem::IndexPastRam(0xFFF)]);
+    }
+
+    #[test]
+    fn unset_sprites() {
+        let rom = [
+            0xA3, 0x00, // LD I, 0x300
+            0x60, 0x00, // LD V0, 0
+            0xD0, 0x01, // DRW V0, V0, 1
+            0x12, 0x06, // JP 0x206
+        ];
+        assert_eq!(findings(&rom, 4), [Problem::UnsetSprite(0x300)]);
+    }
+
+    #[test]
+    fn stops_before_returning_without_a_call() {
+        let mut cpu = Cpu::new(&[0x00, 0xEE], BoolDisplay::new(1), SeededRand::new(0));
+        cpu.sanitize(SanitizerMode::Stop);
+        let stop = cpu.step();
+        let finding = match stop {
+            Some(StopReason::Sanitizer(finding)) => finding,
+            _ => panic!("didn't stop: {:?}", stop),
+        };
+        assert_eq!(finding.problem, Problem::ReturnWithoutCall);
+        assert_eq!(
+            (finding.pc, finding.opcode, finding.cycle),
+            (0x200, 0x00EE, 0)
+        );
+        assert_eq!(cpu.pc, 0x200);
+        assert_eq!(cpu.sanitizer.take_findings(), [finding]);
+    }
+}
@@ -0,0 +1,383 @@
+use core::fmt;
+
+use crate::{
+    Cpu, Display, KeyWait, Rand, NUM_FLAG_REGISTERS, NUM_KEYS, NUM_REGISTERS, RAM_SIZE, STACK_SIZE,
+};
+
+// a save state, everything a program can observe plus what the emulator needs to carry on exactly
+// where it left off, the random generator included, so a loaded state replays the same as the
+// original did, audio, wall clock timers and the debugger are left out on purpose
+// saved as text like movies, one 'name values' per line, ram and pixels as rows of hex
+
+const MAGIC: &str = "oxi8 state 1";
+
+const RAM_ROW: usize = 64; // bytes per ram line
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct State {
+    i: u16,
+    v: [u8; NUM_REGISTERS],
+    flag: [u8; NUM_FLAG_REGISTERS],
+    delay: u8,
+    sound: u8,
+    ram: Vec<u8>,
+    pc: u16,
+    stack: Vec<u16>,
+    keys: [bool; NUM_KEYS],
+    keywait: KeyWait,
+    clock_rate_hz: u32,
+    rand: u64,
+    cycles: u64,
+    frame: u64,
+    frame_cycle: u32,
+    hires: bool,
+    plane_mask: u8,
+    width: usize,
+    pixels: Vec<u8>, // color indices, rows of width
+}
+
+impl State {
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    pub fn frame(&self) -> u64 {
+        self.frame
+    }
+
+    pub fn parse(state: &str) -> Result<State, String> {
+        let mut lines = state
+            .lines()
+            .map(|line| line.trim())
+            .filter(|line| !line.is_empty() && !line.starts_with('#'));
+        if lines.next() != Some(MAGIC) {
+            return Err(format!("not an oxi8 state, first line must be '{}'", MAGIC));
+        }
+        let mut ret = State {
+            i: 0,
+            v: [0; NUM_REGISTERS],
+            flag: [0; NUM_FLAG_REGISTERS],
+            delay: 0,
+            sound: 0,
+            ram: vec![0; RAM_SIZE],
+            pc: 0,
+            stack: Vec::new(),
+            keys: [false; NUM_KEYS],
+            keywait: KeyWait::None,
+            clock_rate_hz: 0,
+            rand: 0,
+            cycles: 0,
+            frame: 0,
+            frame_cycle: 0,
+            hires: false,
+            plane_mask: 1,
+            width: 0,
+            pixels: Vec::new(),
+        };
+        for line in lines {
+            let words: Vec<&str> = line.split_whitespace().collect();
+            match words.as_slice() {
+                ["i", i] => ret.i = parse_hex(i, line)? as u16,
+                ["pc", pc] => ret.pc = parse_hex(pc, line)? as u16,
+                ["v", v @ ..] => parse_bytes(v, &mut ret.v, line)?,
+                ["flag", flag @ ..] => parse_bytes(flag, &mut ret.flag, line)?,
+                ["delay", delay] => ret.delay = parse_num(delay, line)?,
+                ["sound", sound] => ret.sound = parse_num(sound, line)?,
+                ["stack", stack @ ..] => {
+                    if stack.len() > STACK_SIZE {
+                        return Err(format!("stack too deep in line: {}", line));
+                    }
+                    ret.stack = stack
+                        .iter()
+                        .map(|addr| parse_hex(addr, line).map(|addr| addr as u16))
+                        .collect::<Result<_, _>>()?;
+                }
+                ["keys", keys] => {
+                    if keys.len() != NUM_KEYS {
+                        return Err(format!("expected {} keys in line: {}", NUM_KEYS, line));
+                    }
+                    for (key, state) in ret.keys.iter_mut().zip(keys.chars()) {
+                        *key = state == '1';
+                    }
+                }
+                ["keywait", "none"] => ret.keywait = KeyWait::None,
+                ["keywait", "wait"] => ret.keywait = KeyWait::Wait,
+                ["keywait", "pressed", key] => {
+                    ret.keywait = KeyWait::Pressed(parse_hex(key, line)? as u8)
+                }
+                ["clock", hz] => ret.clock_rate_hz = parse_num(hz, line)?,
+                ["rand", rand] => ret.rand = parse_hex(rand, line)?,
+                ["cycles", cycles] => ret.cycles = parse_num(cycles, line)?,
+                ["frame", frame] => ret.frame = parse_num(frame, line)?,
+                ["frame_cycle", frame_cycle] => ret.frame_cycle = parse_num(frame_cycle, line)?,
+                ["hires", hires] => ret.hires = parse_num::<u8>(hires, line)? != 0,
+                ["plane_mask", mask] => ret.plane_mask = parse_hex(mask, line)? as u8,
+                ["ram", addr, bytes] => {
+                    let addr = parse_hex(addr, line)? as usize;
+                    let bytes = parse_hex_bytes(bytes, line)?;
+                    if addr + bytes.len() > RAM_SIZE {
+                        return Err(format!("ram out of bounds in line: {}", line));
+                    }
+                    ret.ram[addr..addr + bytes.len()].copy_from_slice(&bytes);
+                }
+                ["pixels", row] => {
+                    let row = parse_hex_bytes(row, line)?;
+                    if ret.width != 0 && ret.width != row.len() {
+                        return Err(format!("pixel rows differ in width in line: {}", line));
+                    }
+                    ret.width = row.len();
+                    ret.pixels.extend(row);
+                }
+                _ => return Err(format!("bad line: {}", line)),
+            }
+        }
+        if ret.clock_rate_hz == 0 {
+            return Err("state is missing clock".to_string());
+        }
+        Ok(ret)
+    }
+}
+
+fn parse_num<N: std::str::FromStr>(num: &str, line: &str) -> Result<N, String> {
+    num.parse()
+        .map_err(|_| format!("bad number '{}' in line: {}", num, line))
+}
+
+fn parse_hex(num: &str, line: &str) -> Result<u64, String> {
+    u64::from_str_radix(num, 16).map_err(|_| format!("bad hex '{}' in line: {}", num, line))
+}
+
+fn parse_bytes(words: &[&str], out: &mut [u8], line: &str) -> Result<(), String> {
+    if words.len() != out.len() {
+        return Err(format!("expected {} values in line: {}", out.len(), line));
+    }
+    for (out, word) in out.iter_mut().zip(words.iter()) {
+        *out = parse_hex(word, line)? as u8;
+    }
+    Ok(())
+}
+
+fn parse_hex_bytes(hex: &str, line: &str) -> Result<Vec<u8>, String> {
+    if hex.len() % 2 != 0 || !hex.is_ascii() {
+        return Err(format!("bad hex in line: {}", line));
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|n| parse_hex(&hex[n..n + 2], line).map(|byte| byte as u8))
+        .collect()
+}
+
+fn write_hex_bytes(f: &mut fmt::Formatter, bytes: &[u8]) -> fmt::Result {
+    for byte in bytes.iter() {
+        write!(f, "{:02X}", byte)?;
+    }
+    writeln!(f)
+}
+
+impl fmt::Display for State {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{}", MAGIC)?;
+        writeln!(f, "clock {}", self.clock_rate_hz)?;
+        writeln!(f, "cycles {}", self.cycles)?;
+        writeln!(f, "frame {}", self.frame)?;
+        writeln!(f, "frame_cycle {}", self.frame_cycle)?;
+        writeln!(f, "rand {:X}", self.rand)?;
+        writeln!(f, "pc {:03X}", self.pc)?;
+        writeln!(f, "i {:03X}", self.i)?;
+        write!(f, "v")?;
+        for v in self.v.iter() {
+            write!(f, " {:02X}", v)?;
+        }
+        write!(f, "\nflag")?;
+        for flag in self.flag.iter() {
+            write!(f, " {:02X}", flag)?;
+        }
+        writeln!(f)?;
+        writeln!(f, "delay {}", self.delay)?;
+        writeln!(f, "sound {}", self.sound)?;
+        write!(f, "stack")?;
+        for addr in self.stack.iter() {
+            write!(f, " {:03X}", addr)?;
+        }
+        writeln!(f)?;
+        write!(f, "keys ")?;
+        for key in self.keys.iter() {
+            write!(f, "{}", *key as u8)?;
+        }
+        writeln!(f)?;
+        match self.keywait {
+            KeyWait::None => writeln!(f, "keywait none")?,
+            KeyWait::Wait => writeln!(f, "keywait wait")?,
+            KeyWait::Pressed(key) => writeln!(f, "keywait pressed {:X}", key)?,
+        }
+        writeln!(f, "hires {}", self.hires as u8)?;
+        writeln!(f, "plane_mask {:X}", self.plane_mask)?;
+        for (row, bytes) in self.ram.chunks(RAM_ROW).enumerate() {
+            // most of ram is usually 0, which is what parse() starts with anyway
+            if bytes.iter().any(|byte| *byte != 0) {
+                write!(f, "ram {:03X} ", row * RAM_ROW)?;
+                write_hex_bytes(f, bytes)?;
+            }
+        }
+        for row in self.pixels.chunks(self.width.max(1)) {
+            write!(f, "pixels ")?;
+            write_hex_bytes(f, row)?;
+        }
+        Ok(())
+    }
+}
+
+impl<T: Display, R: Rand> Cpu<T, R> {
+    pub fn save_state(&self) -> State {
+        let mut pixels = Vec::with_capacity(self.display.width() * self.display.height());
+        for y in 0..self.display.height() {
+            for x in 0..self.display.width() {
+                pixels.push(self.display.current_pixel(x, y));
+            }
+        }
+        State {
+            i: self.i,
+            v: self.v,
+            flag: self.flag,
+            delay: self.delay,
+            sound: self.sound,
+            ram: self.ram.to_vec(),
+            pc: self.pc,
+            stack: self.stack().to_vec(),
+            keys: self.keyboard.keys,
+            keywait: self.keyboard.keywait,
+            clock_rate_hz: self.clock_rate_hz,
+            rand: self.rand.state(),
+            cycles: self.cycles,
+            frame: self.frame,
+            frame_cycle: self.frame_cycle,
+            hires: self.display.hires(),
+            plane_mask: self.display.plane_mask(),
+            width: self.display.width(),
+            pixels,
+        }
+    }
+
+    // only fails if the state's screen doesn't fit this Cpu's display, which means it was saved
+    // from a different kind of display, any step in progress is cancelled since the stack it
+    // was counting on is gone, and recorded history is forgotten since it led somewhere else,
+    // cheats that are on stay on
+    pub fn load_state(&mut self, state: &State) -> Result<(), String> {
+        self.restore_state(state)?;
+        self.resync_cheats();
+        self.history.clear();
+        Ok(())
+    }
+
+    pub(crate) fn restore_state(&mut self, state: &State) -> Result<(), String> {
+        // checked before switching resolution, which clears the screen, so a state that doesn't
+        // fit leaves everything as it was, hires is always twice the size each way
+        let (mut width, mut height) = (self.display.width(), self.display.height());
+        match (self.display.hires(), state.hires) {
+            (false, true) => {
+                width *= 2;
+                height *= 2;
+            }
+            (true, false) => {
+                width /= 2;
+                height /= 2;
+            }
+            _ => (),
+        }
+        if state.width != width || state.pixels.len() != width * height {
+            return Err(format!(
+                "state has a {}x{} screen, display is {}x{}",
+                state.width,
+                state.pixels.len() / state.width.max(1),
+                width,
+                height
+            ));
+        }
+        self.display.set_hires(state.hires);
+        for (y, row) in state.pixels.chunks(width).enumerate() {
+            for (x, color) in row.iter().enumerate() {
+                self.display.set_pixel(x, y, *color);
+            }
+        }
+        self.display.set_plane_mask(state.plane_mask);
+        self.i = state.i;
+        self.v = state.v;
+        self.flag = state.flag;
+        self.delay = state.delay;
+        self.sound = state.sound;
+        self.ram.copy_from_slice(&state.ram);
+        self.pc = state.pc;
+        self.stack.clear();
+        for addr in state.stack.iter() {
+            // only call sites are saved, the 2nnn at each one says what it called, unless the
+            // program has since rewritten it, which is rare enough not to be worth saving
+            let target = self.opcode_at(*addr) & 0xFFF;
+            self.stack.push(*addr, target);
+        }
+        self.keyboard.keys = state.keys;
+        self.keyboard.keywait = state.keywait;
+        self.set_clock_rate_hz(state.clock_rate_hz);
+        self.rand.set_state(state.rand);
+        self.cycles = state.cycles;
+        self.frame = state.frame;
+        self.frame_cycle = state.frame_cycle;
+        self.debugger.cancel_step();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{BoolDisplay, Cheat, SeededRand};
+
+    fn cpu() -> Cpu<BoolDisplay, SeededRand> {
+        Cpu::new(&[], BoolDisplay::new(1), SeededRand::new(0))
+    }
+
+    #[test]
+    fn switches_resolution() {
+        let mut hires = cpu();
+        hires.display.set_hires(true);
+        hires.display.set_pixel(127, 63, 1);
+        let state = hires.save_state();
+        let mut cpu = cpu();
+        cpu.load_state(&state).unwrap();
+        assert!(cpu.display.hires());
+        assert_eq!(cpu.display.current_pixel(127, 63), 1);
+        assert_eq!(State::parse(&state.to_string()).unwrap(), state);
+    }
+
+    #[test]
+    fn bad_screen_changes_nothing() {
+        let mut hires = cpu();
+        hires.display.set_hires(true);
+        let mut state = hires.save_state();
+        state.pixels.truncate(64 * 32);
+        let mut cpu = cpu();
+        cpu.display.set_pixel(1, 1, 1);
+        let err = cpu.load_state(&state).unwrap_err();
+        assert!(err.contains("display is 128x64"), "{}", err);
+        assert!(!cpu.display.hires());
+        assert_eq!(cpu.display.current_pixel(1, 1), 1);
+    }
+
+    #[test]
+    fn load_keeps_cheats_on() {
+        let mut cpu = cpu();
+        let before = cpu.save_state();
+        cpu.add_cheat(Cheat::parse("on x: patch 0x300 1 2; freeze V3 7").unwrap());
+        cpu.load_state(&before).unwrap();
+        assert_eq!(cpu.ram[0x300..0x302], [1, 2]);
+        assert_eq!(cpu.v[3], 7);
+        cpu.enable_cheat(0, false);
+        assert_eq!(cpu.ram[0x300..0x302], [0, 0]);
+
+        // saved with the patch on, what it replaced is still known
+        cpu.enable_cheat(0, true);
+        let patched = cpu.save_state();
+        cpu.load_state(&patched).unwrap();
+        cpu.enable_cheat(0, false);
+        assert_eq!(cpu.ram[0x300..0x302], [0, 0]);
+    }
+}
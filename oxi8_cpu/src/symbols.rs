@@ -0,0 +1,164 @@
+use std::collections::{BTreeMap, HashMap};
+
+use crate::Register;
+
+// labels for addresses, so debuggers can show and take names instead of raw numbers
+// one label per line, # starts a comment, addresses are 0x hex or decimal:
+//   score = 0x2D4    the simple format
+//   0x2D4 score      label dumps from Octo and other assemblers, either way around,
+//   : score 0x2D4    with or without Octo's leading ':'
+// names are case sensitive like Octo's, if an address has several labels the first is shown
+// names expressions already use, registers, frame, cycles and ram in any case, can't be labels
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SymbolMap {
+    names: BTreeMap<u16, String>,
+    addrs: HashMap<String, u16>,
+}
+
+impl SymbolMap {
+    pub fn parse(symbols: &str) -> Result<SymbolMap, String> {
+        let mut ret = SymbolMap::default();
+        for line in symbols.lines() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let (name, addr) = if let Some(at) = line.find('=') {
+                (line[..at].trim(), line[at + 1..].trim())
+            } else {
+                let line = line.strip_prefix(':').unwrap_or(line);
+                let words: Vec<&str> = line.split_whitespace().collect();
+                match words.as_slice() {
+                    [first, second] if parse_addr(first).is_some() => (*second, *first),
+                    [first, second] => (*first, *second),
+                    _ => return Err(format!("expected a name and an address: {}", line)),
+                }
+            };
+            let addr =
+                parse_addr(addr).ok_or_else(|| format!("bad address '{}' in: {}", addr, line))?;
+            if name.is_empty() || name.contains(char::is_whitespace) {
+                return Err(format!("bad name '{}' in: {}", name, line));
+            }
+            if is_builtin(name) {
+                return Err(format!(
+                    "'{}' is taken by expressions, rename it in: {}",
+                    name, line
+                ));
+            }
+            ret.insert(name, addr);
+        }
+        Ok(ret)
+    }
+
+    pub fn insert(&mut self, name: &str, addr: u16) {
+        self.names.entry(addr).or_insert_with(|| name.to_string());
+        self.addrs.insert(name.to_string(), addr);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.addrs.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.addrs.len()
+    }
+
+    pub fn addr(&self, name: &str) -> Option<u16> {
+        self.addrs.get(name).cloned()
+    }
+
+    // only a label exactly at addr
+    pub fn name(&self, addr: u16) -> Option<&str> {
+        self.names.get(&addr).map(String::as_str)
+    }
+
+    // the closest label at or before addr, and how far past it addr is
+    pub fn nearest(&self, addr: u16) -> Option<(&str, u16)> {
+        self.names
+            .range(..=addr)
+            .next_back()
+            .map(|(at, name)| (name.as_str(), addr - at))
+    }
+
+    // score+2 for 2 bytes past score, None with no label at or before addr
+    pub fn label(&self, addr: u16) -> Option<String> {
+        self.nearest(addr).map(|(name, offset)| match offset {
+            0 => name.to_string(),
+            _ => format!("{}+{}", name, offset),
+        })
+    }
+
+    // 0x2D6 <score+2>, or just 0x2D6 with no label at or before it
+    pub fn format(&self, addr: u16) -> String {
+        match self.label(addr) {
+            Some(label) => format!("0x{:03X} <{}>", addr, label),
+            None => format!("0x{:03X}", addr),
+        }
+    }
+}
+
+fn is_builtin(name: &str) -> bool {
+    let name = name.to_lowercase();
+    Register::from_name(&name).is_some() || ["frame", "cycles", "ram"].contains(&name.as_str())
+}
+
+fn parse_addr(addr: &str) -> Option<u16> {
+    match addr.strip_prefix("0x").or_else(|| addr.strip_prefix("0X")) {
+        Some(hex) => u16::from_str_radix(hex, 16).ok(),
+        None if addr.starts_with(|c: char| c.is_ascii_digit()) => addr.parse().ok(),
+        None => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats() {
+        let symbols = SymbolMap::parse(
+            "# a comment\n\nscore = 0x2D4\n0x300 sprite  # trailing\n: main 512\nloop 0X20A\n",
+        )
+        .unwrap();
+        assert_eq!(symbols.len(), 4);
+        assert_eq!(symbols.addr("score"), Some(0x2D4));
+        assert_eq!(symbols.addr("sprite"), Some(0x300));
+        assert_eq!(symbols.addr("main"), Some(0x200));
+        assert_eq!(symbols.addr("loop"), Some(0x20A));
+        assert_eq!(symbols.addr("Score"), None);
+        let symbols = SymbolMap::parse("v10 = 0x200\nframes = 0x202\nvg = 0x204\n").unwrap();
+        assert_eq!(symbols.len(), 3);
+    }
+
+    #[test]
+    fn first_label_is_shown() {
+        let symbols = SymbolMap::parse("a = 0x200\nb = 0x200\n").unwrap();
+        assert_eq!(symbols.name(0x200), Some("a"));
+        assert_eq!(symbols.addr("b"), Some(0x200));
+        assert_eq!(symbols.format(0x203), "0x203 <a+3>");
+        assert_eq!(symbols.format(0x100), "0x100");
+    }
+
+    #[test]
+    fn errors() {
+        for (symbols, error) in [
+            ("score", "expected a name and an address"),
+            ("a b c", "expected a name and an address"),
+            ("score = here", "bad address 'here'"),
+            ("score = 0x10000", "bad address"),
+            ("= 0x200", "bad name ''"),
+            ("two words = 0x200", "bad name 'two words'"),
+            ("i = 0x200", "'i' is taken"),
+            ("0x200 PC", "'PC' is taken"),
+            (": vA 0x200", "'vA' is taken"),
+            ("delay = 0x200", "'delay' is taken"),
+            ("Frame = 0x200", "'Frame' is taken"),
+            ("cycles = 0x200", "'cycles' is taken"),
+            ("ram = 0x200", "'ram' is taken"),
+        ] {
+            let err = SymbolMap::parse(symbols).unwrap_err();
+            assert!(err.contains(error), "{}: {}", symbols, err);
+        }
+    }
+}
@@ -0,0 +1,272 @@
+use std::{env, fs, path::Path};
+
+use crate::Display;
+
+// set this to anything to (re)write golden snapshot files instead of comparing against them,
+// missing ones included, so a snapshot never gets written by a run that was meant to check it
+const UPDATE_SNAPSHOTS_VAR: &str = "OXI8_UPDATE_SNAPSHOTS";
+
+const BRAILLE_BLANK: u32 = 0x2800;
+// braille dot bits for a 2 wide by 4 tall cell, indexed [y][x]
+const BRAILLE_DOTS: [[u32; 2]; 4] = [[0x01, 0x08], [0x02, 0x10], [0x04, 0x20], [0x40, 0x80]];
+
+// xterm 256 color codes for color indices 0-3, black, white, then XO-CHIP's plane 2 and both
+// planes colors, MegaChip's other indices are used as 256 color codes as they are
+const ANSI_COLORS: [u8; 4] = [16, 231, 160, 226];
+const ANSI_RESET: &str = "\x1b[0m";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TextStyle {
+    Ascii,     // 1 character per pixel, '#' on, '.' off
+    HalfBlock, // 1 character per 1x2 pixels
+    Braille,   // 1 character per 2x4 pixels
+    Ansi,      // 1 character per 1x2 pixels like HalfBlock, in color with ANSI escape codes
+}
+
+// renders a Display as text, one line per row of characters, each line ending in '\n'
+pub fn render_text<T: Display>(display: &T, style: TextStyle) -> String {
+    let (cell_width, cell_height) = match style {
+        TextStyle::Ascii => (1, 1),
+        TextStyle::HalfBlock => (1, 2),
+        TextStyle::Braille => (2, 4),
+        TextStyle::Ansi => return render_ansi(display),
+    };
+    let (width, height) = (display.width(), display.height());
+    let on = |x: usize, y: usize| x < width && y < height && display.current_pixel(x, y) != 0;
+    let mut text = String::new();
+    for y in (0..height).step_by(cell_height) {
+        for x in (0..width).step_by(cell_width) {
+            text.push(match style {
+                TextStyle::Ascii => {
+                    if on(x, y) {
+                        '#'
+                    } else {
+                        '.'
+                    }
+                }
+                TextStyle::HalfBlock => match (on(x, y), on(x, y + 1)) {
+                    (false, false) => ' ',
+                    (true, false) => '▀',
+                    (false, true) => '▄',
+                    (true, true) => '█',
+                },
+                TextStyle::Braille => {
+                    let mut dots = BRAILLE_BLANK;
+                    for (dy, row) in BRAILLE_DOTS.iter().enumerate() {
+                        for (dx, dot) in row.iter().enumerate() {
+                            if on(x + dx, y + dy) {
+                                dots |= dot;
+                            }
+                        }
+                    }
+                    std::char::from_u32(dots).unwrap_or(' ')
+                }
+                TextStyle::Ansi => unreachable!(),
+            });
+        }
+        text.push('\n');
+    }
+    text
+}
+
+// the top pixel of each cell is the foreground of a '▀', the bottom one the background, colors
+// are only set when they change, and every line resets them before its '\n'
+fn render_ansi<T: Display>(display: &T) -> String {
+    let (width, height) = (display.width(), display.height());
+    let color = |x: usize, y: usize| {
+        let index = if y < height {
+            display.current_pixel(x, y)
+        } else {
+            0
+        };
+        ANSI_COLORS.get(index as usize).cloned().unwrap_or(index)
+    };
+    let mut text = String::new();
+    for y in (0..height).step_by(2) {
+        let mut last = None;
+        for x in 0..width {
+            let colors = (color(x, y), color(x, y + 1));
+            if last != Some(colors) {
+                text.push_str(&format!("\x1b[38;5;{};48;5;{}m", colors.0, colors.1));
+                last = Some(colors);
+            }
+            text.push('▀');
+        }
+        text.push_str(ANSI_RESET);
+        text.push('\n');
+    }
+    text
+}
+
+// compares display rendered as style against the golden file at path, returning a readable
+// diff on mismatch, a missing file is a mismatch too, with OXI8_UPDATE_SNAPSHOTS set the file is
+// written instead
+pub fn check_snapshot<T: Display, P: AsRef<Path>>(
+    display: &T,
+    style: TextStyle,
+    path: P,
+) -> Result<(), String> {
+    let path = path.as_ref();
+    let actual = render_text(display, style);
+    if env::var_os(UPDATE_SNAPSHOTS_VAR).is_some() {
+        return fs::write(path, &actual)
+            .map_err(|e| format!("unable to write snapshot {}: {}", path.display(), e));
+    }
+    let expected = fs::read_to_string(path).map_err(|e| {
+        format!(
+            "unable to read snapshot {}: {}, rerun with {}=1 to write it",
+            path.display(),
+            e,
+            UPDATE_SNAPSHOTS_VAR
+        )
+    })?;
+    if expected == actual {
+        Ok(())
+    } else {
+        Err(format!(
+            "snapshot {} does not match, rerun with {}=1 to accept\n{}",
+            path.display(),
+            UPDATE_SNAPSHOTS_VAR,
+            diff_text(&expected, &actual)
+        ))
+    }
+}
+
+// panicking version of check_snapshot for use in tests
+#[cfg(test)]
+pub fn assert_snapshot<T: Display, P: AsRef<Path>>(display: &T, style: TextStyle, path: P) {
+    if let Err(diff) = check_snapshot(display, style, path) {
+        panic!("{}", diff);
+    }
+}
+
+// line by line diff, '-' is expected, '+' is actual, with '^' under every column that differs
+pub fn diff_text(expected: &str, actual: &str) -> String {
+    let expected: Vec<&str> = expected.lines().collect();
+    let actual: Vec<&str> = actual.lines().collect();
+    let mut diff = String::new();
+    for row in 0..expected.len().max(actual.len()) {
+        let (e, a) = (expected.get(row), actual.get(row));
+        if e == a {
+            diff.push_str(&format!("{:3}   {}\n", row, e.unwrap_or(&"")));
+            continue;
+        }
+        if let Some(e) = e {
+            diff.push_str(&format!("{:3} - {}\n", row, e));
+        }
+        if let Some(a) = a {
+            diff.push_str(&format!("{:3} + {}\n", row, a));
+        }
+        if let (Some(e), Some(a)) = (e, a) {
+            let (e, a): (Vec<char>, Vec<char>) = (e.chars().collect(), a.chars().collect());
+            let marker: String = (0..e.len().max(a.len()))
+                .map(|col| if e.get(col) == a.get(col) { ' ' } else { '^' })
+                .collect();
+            diff.push_str(&format!("      {}\n", marker.trim_end()));
+        }
+    }
+    diff
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{BoolDisplay, Cpu, PlaneDisplay, SeededRand};
+
+    // draws the font's 0-3 side by side then loops forever
+    const DIGITS: [u8; 22] = [
+        0x00, 0xE0, // CLS
+        0x60, 0x00, // LD V0, 0
+        0x61, 0x00, // LD V1, 0
+        0x62, 0x00, // LD V2, 0
+        0xF0, 0x29, // LD F, V0
+        0xD1, 0x25, // DRW V1, V2, 5
+        0x70, 0x01, // ADD V0, 1
+        0x71, 0x05, // ADD V1, 5
+        0x30, 0x04, // SE V0, 4
+        0x12, 0x08, // JP 0x208
+        0x12, 0x14, // JP 0x214
+    ];
+
+    fn snapshot(name: &str) -> String {
+        format!("{}/tests/snapshots/{}", env!("CARGO_MANIFEST_DIR"), name)
+    }
+
+    fn digits() -> BoolDisplay {
+        let mut cpu = Cpu::new(&DIGITS, BoolDisplay::new(1), SeededRand::new(0));
+        for _ in 0..100 {
+            cpu.execute_next_instruction();
+        }
+        cpu.display
+    }
+
+    #[test]
+    fn ascii() {
+        assert_snapshot(&digits(), TextStyle::Ascii, snapshot("digits_ascii.txt"));
+    }
+
+    #[test]
+    fn half_block() {
+        assert_snapshot(&digits(), TextStyle::HalfBlock, snapshot("digits_half.txt"));
+    }
+
+    #[test]
+    fn braille() {
+        assert_snapshot(
+            &digits(),
+            TextStyle::Braille,
+            snapshot("digits_braille.txt"),
+        );
+    }
+
+    #[test]
+    fn ansi_colors() {
+        let mut display = PlaneDisplay::new(2);
+        for x in 0..4 {
+            display.set_pixel(x, 0, x as u8);
+            display.set_pixel(x, 1, 3 - x as u8);
+        }
+        let text = render_text(&display, TextStyle::Ansi);
+        let first = text.lines().next().unwrap();
+        assert!(first.starts_with(
+            "\x1b[38;5;16;48;5;226m▀\x1b[38;5;231;48;5;160m▀\x1b[38;5;160;48;5;231m▀\x1b[38;5;226;48;5;16m▀\x1b[38;5;16;48;5;16m▀▀"
+        ));
+        assert!(first.ends_with("▀\x1b[0m"));
+        assert_eq!(text.lines().count(), display.height() / 2);
+        assert_snapshot(&digits(), TextStyle::Ansi, snapshot("digits_ansi.txt"));
+    }
+
+    #[test]
+    fn missing_snapshot_fails() {
+        if env::var_os(UPDATE_SNAPSHOTS_VAR).is_some() {
+            return;
+        }
+        let path = snapshot("does_not_exist.txt");
+        let err = check_snapshot(&digits(), TextStyle::Ascii, &path).unwrap_err();
+        assert!(err.contains(UPDATE_SNAPSHOTS_VAR), "{}", err);
+        assert!(!Path::new(&path).exists());
+    }
+
+    #[test]
+    fn mismatch_shows_diff() {
+        if env::var_os(UPDATE_SNAPSHOTS_VAR).is_some() {
+            return;
+        }
+        let mut display = digits();
+        display.set_pixel(63, 31, 1);
+        let err = check_snapshot(&display, TextStyle::Ascii, snapshot("digits_ascii.txt"));
+        let err = err.unwrap_err();
+        assert!(err.contains(" 31 - ...................."), "{}", err);
+        assert!(err.contains("#\n"), "{}", err);
+        assert!(err.trim_end().ends_with('^'), "{}", err);
+    }
+
+    #[test]
+    fn diff_marks_columns() {
+        assert_eq!(
+            diff_text("ab\ncd\n", "ab\nxd\n"),
+            "  0   ab\n  1 - cd\n  1 + xd\n      ^\n"
+        );
+        assert_eq!(diff_text("a\n", "a\nb\n"), "  0   a\n  1 + b\n");
+    }
+}
@@ -0,0 +1,481 @@
+use core::fmt;
+use std::fmt::Write;
+
+use crate::{Cpu, Display, KeyWait, Rand, DELAY_DECREMENT_HZ, NUM_KEYS};
+
+// while on, notable things the machine does are recorded with the frame and cycle they happened
+// at, the big picture a trace is too detailed to show, like a game stuttering because it clears
+// the screen twice a frame, or going silent because the sound timer is never set again
+// most are noticed right before an instruction runs, so key presses and the sound timer running
+// out show up at the first instruction after they happened
+// exported as plain JSON, or as Chrome's trace event format which chrome://tracing and Perfetto
+// draw as a timeline, with sound, key waits and keys held down as spans on their own tracks
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum TimelineEvent {
+    Clear,           // 00E0
+    Hires,           // 00FF switching from lores
+    Lores,           // 00FE switching from hires
+    ScrollDown(u8),  // 00Cn, by n
+    ScrollLeft,      // 00FC
+    ScrollRight,     // 00FB
+    SoundOn(u8),     // the sound timer started, at this
+    SoundOff,        // the sound timer ran out or was set to 0
+    KeyWait,         // Fx0A started waiting
+    KeyWaitDone(u8), // Fx0A got this key
+    KeyDown(u8),
+    KeyUp(u8),
+    SaveFlags(u8), // Fx75, the number of registers saved
+    LoadFlags(u8), // Fx85, the number of registers loaded
+    Error(String), // a sanitizer finding or a frontend reporting a crash
+}
+
+impl TimelineEvent {
+    // for exports, a fixed name plus what varies, if anything
+    fn name(&self) -> &'static str {
+        match self {
+            TimelineEvent::Clear => "clear",
+            TimelineEvent::Hires => "hires",
+            TimelineEvent::Lores => "lores",
+            TimelineEvent::ScrollDown(_) => "scroll_down",
+            TimelineEvent::ScrollLeft => "scroll_left",
+            TimelineEvent::ScrollRight => "scroll_right",
+            TimelineEvent::SoundOn(_) => "sound_on",
+            TimelineEvent::SoundOff => "sound_off",
+            TimelineEvent::KeyWait => "key_wait",
+            TimelineEvent::KeyWaitDone(_) => "key_wait_done",
+            TimelineEvent::KeyDown(_) => "key_down",
+            TimelineEvent::KeyUp(_) => "key_up",
+            TimelineEvent::SaveFlags(_) => "save_flags",
+            TimelineEvent::LoadFlags(_) => "load_flags",
+            TimelineEvent::Error(_) => "error",
+        }
+    }
+
+    fn arg(&self) -> Option<(&'static str, String)> {
+        match self {
+            TimelineEvent::ScrollDown(n) => Some(("lines", n.to_string())),
+            TimelineEvent::SoundOn(timer) => Some(("timer", timer.to_string())),
+            TimelineEvent::KeyWaitDone(key)
+            | TimelineEvent::KeyDown(key)
+            | TimelineEvent::KeyUp(key) => Some(("key", key.to_string())),
+            TimelineEvent::SaveFlags(count) | TimelineEvent::LoadFlags(count) => {
+                Some(("registers", count.to_string()))
+            }
+            TimelineEvent::Error(message) => Some(("message", json_string(message))),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for TimelineEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TimelineEvent::Clear => write!(f, "clear screen"),
+            TimelineEvent::Hires => write!(f, "hires"),
+            TimelineEvent::Lores => write!(f, "lores"),
+            TimelineEvent::ScrollDown(n) => write!(f, "scroll down {}", n),
+            TimelineEvent::ScrollLeft => write!(f, "scroll left"),
+            TimelineEvent::ScrollRight => write!(f, "scroll right"),
+            TimelineEvent::SoundOn(timer) => write!(f, "sound on for {} frames", timer),
+            TimelineEvent::SoundOff => write!(f, "sound off"),
+            TimelineEvent::KeyWait => write!(f, "waiting for a key"),
+            TimelineEvent::KeyWaitDone(key) => write!(f, "got key {:X}", key),
+            TimelineEvent::KeyDown(key) => write!(f, "key {:X} down", key),
+            TimelineEvent::KeyUp(key) => write!(f, "key {:X} up", key),
+            TimelineEvent::SaveFlags(count) => write!(f, "save V0-V{:X} to flags", count - 1),
+            TimelineEvent::LoadFlags(count) => write!(f, "load V0-V{:X} from flags", count - 1),
+            TimelineEvent::Error(message) => write!(f, "error: {}", message),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct TimelineEntry {
+    pub frame: u64, // Cpu::frame()
+    pub cycle: u64, // Cpu::cycles(), right before the instruction at pc ran
+    pub pc: u16,
+    pub event: TimelineEvent,
+}
+
+impl fmt::Display for TimelineEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "frame {:>6} cycle {:>8} 0x{:03X}  {}",
+            self.frame, self.cycle, self.pc, self.event
+        )
+    }
+}
+
+#[derive(Default)]
+pub struct Timeline {
+    active: bool,
+    entries: Vec<TimelineEntry>,
+    keys: [bool; NUM_KEYS], // as of the last step, to notice changes
+    sound_on: bool,
+}
+
+impl Timeline {
+    pub fn start(&mut self) {
+        self.active = true;
+    }
+
+    // entries stay until clear()
+    pub fn stop(&mut self) {
+        self.active = false;
+    }
+
+    pub fn clear(&mut self) {
+        let active = self.active;
+        *self = Timeline::default();
+        self.active = active;
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.active
+    }
+
+    // oldest first
+    pub fn entries(&self) -> &[TimelineEntry] {
+        &self.entries
+    }
+
+    // rewinding makes entries at or after cycle a future that never happened
+    pub(crate) fn forget_from(&mut self, cycle: u64) {
+        while self
+            .entries
+            .last()
+            .is_some_and(|entry| entry.cycle >= cycle)
+        {
+            self.entries.pop();
+        }
+    }
+}
+
+impl<T: Display, R: Rand> Cpu<T, R> {
+    // called right before the instruction at pc runs
+    pub(crate) fn timeline_step(&mut self) {
+        let opcode = self.opcode_at(self.pc);
+        let keys = self.keyboard.keys;
+        let sound_on = self.sound > 0;
+        let mut events = Vec::new();
+        for (key, (was, is)) in self.timeline.keys.iter().zip(keys.iter()).enumerate() {
+            match (was, is) {
+                (false, true) => events.push(TimelineEvent::KeyDown(key as u8)),
+                (true, false) => events.push(TimelineEvent::KeyUp(key as u8)),
+                _ => (),
+            }
+        }
+        match (self.timeline.sound_on, sound_on) {
+            (false, true) => events.push(TimelineEvent::SoundOn(self.sound)),
+            (true, false) => events.push(TimelineEvent::SoundOff),
+            _ => (),
+        }
+        let x = ((opcode >> 8) & 0xF) as u8;
+        let hires = self.display.hires();
+        match opcode {
+            0x00E0 => events.push(TimelineEvent::Clear),
+            0x00FB => events.push(TimelineEvent::ScrollRight),
+            0x00FC => events.push(TimelineEvent::ScrollLeft),
+            0x00FE if hires => events.push(TimelineEvent::Lores),
+            0x00FF if !hires => events.push(TimelineEvent::Hires),
+            _ if opcode & 0xFFF0 == 0x00C0 => {
+                events.push(TimelineEvent::ScrollDown((opcode & 0xF) as u8))
+            }
+            _ if opcode & 0xF0FF == 0xF00A => match self.keyboard.keywait {
+                KeyWait::None => events.push(TimelineEvent::KeyWait),
+                KeyWait::Pressed(key) => events.push(TimelineEvent::KeyWaitDone(key)),
+                KeyWait::Wait => (),
+            },
+            _ if opcode & 0xF0FF == 0xF075 => events.push(TimelineEvent::SaveFlags(x + 1)),
+            _ if opcode & 0xF0FF == 0xF085 => events.push(TimelineEvent::LoadFlags(x + 1)),
+            _ => (),
+        }
+        self.timeline.keys = keys;
+        self.timeline.sound_on = sound_on;
+        for event in events {
+            self.timeline_push(event);
+        }
+    }
+
+    pub(crate) fn timeline_push(&mut self, event: TimelineEvent) {
+        let entry = TimelineEntry {
+            frame: self.frame,
+            cycle: self.cycles,
+            pc: self.pc,
+            event,
+        };
+        self.timeline.entries.push(entry);
+    }
+
+    // for frontends to put something like a crash on the timeline, even while it's off
+    pub fn timeline_error(&mut self, message: &str) {
+        self.timeline_push(TimelineEvent::Error(message.to_string()));
+    }
+
+    // {"frames": N, "cycles": N, "events": [{"frame": 1, "cycle": 8, "pc": 512, "event": "key_down", "key": 5}, ...]}
+    pub fn timeline_json(&self) -> String {
+        let mut json = format!(
+            "{{\"frames\": {}, \"cycles\": {}, \"events\": [",
+            self.frame, self.cycles
+        );
+        for (n, entry) in self.timeline.entries.iter().enumerate() {
+            let _ = write!(
+                json,
+                "{}\n  {{\"frame\": {}, \"cycle\": {}, \"pc\": {}, \"event\": \"{}\"",
+                if n == 0 { "" } else { "," },
+                entry.frame,
+                entry.cycle,
+                entry.pc,
+                entry.event.name()
+            );
+            if let Some((name, value)) = entry.event.arg() {
+                let _ = write!(json, ", \"{}\": {}", name, value);
+            }
+            json.push('}');
+        }
+        json.push_str("\n]}\n");
+        json
+    }
+
+    // Chrome's trace event format, timestamps are microseconds of emulated time
+    // spans still going, like a key held down, end at the current cycle
+    pub fn timeline_chrome_trace(&self) -> String {
+        // the rate instructions really run at, a whole number of them every 60hz frame
+        let per_second = (DELAY_DECREMENT_HZ * self.num_instructions_per_decrement.max(1)) as f64;
+        let ts = |cycle: u64| cycle as f64 * 1_000_000.0 / per_second;
+        let mut events = Vec::new();
+        for (tid, name) in [(1, "display"), (2, "sound"), (3, "keys"), (4, "cpu")].iter() {
+            events.push(format!(
+                "{{\"ph\": \"M\", \"pid\": 1, \"tid\": {}, \"name\": \"thread_name\", \"args\": {{\"name\": \"{}\"}}}}",
+                tid, name
+            ));
+        }
+        let instant = |tid: u32, scope: &str, entry: &TimelineEntry| {
+            let mut args = format!(
+                "\"frame\": {}, \"cycle\": {}, \"pc\": {}",
+                entry.frame, entry.cycle, entry.pc
+            );
+            if let Some((name, value)) = entry.event.arg() {
+                let _ = write!(args, ", \"{}\": {}", name, value);
+            }
+            format!(
+                "{{\"ph\": \"i\", \"s\": \"{}\", \"pid\": 1, \"tid\": {}, \"ts\": {:.1}, \"name\": {}, \"args\": {{{}}}}}",
+                scope,
+                tid,
+                ts(entry.cycle),
+                json_string(&entry.event.to_string()),
+                args
+            )
+        };
+        let span = |tid: u32, name: &str, start: &TimelineEntry, end: u64| {
+            format!(
+                "{{\"ph\": \"X\", \"pid\": 1, \"tid\": {}, \"ts\": {:.1}, \"dur\": {:.1}, \"name\": {}, \"args\": {{\"frame\": {}, \"cycle\": {}, \"pc\": {}}}}}",
+                tid,
+                ts(start.cycle),
+                ts(end) - ts(start.cycle),
+                json_string(name),
+                start.frame,
+                start.cycle,
+                start.pc
+            )
+        };
+        // where each open span started
+        let mut sound = None;
+        let mut key_wait = None;
+        let mut keys: [Option<&TimelineEntry>; NUM_KEYS] = [None; NUM_KEYS];
+        for entry in self.timeline.entries.iter() {
+            match &entry.event {
+                TimelineEvent::SoundOn(_) => sound = Some(entry),
+                TimelineEvent::SoundOff => {
+                    if let Some(start) = sound.take() {
+                        events.push(span(2, &start.event.to_string(), start, entry.cycle));
+                    }
+                }
+                TimelineEvent::KeyWait => key_wait = Some(entry),
+                TimelineEvent::KeyWaitDone(key) => {
+                    if let Some(start) = key_wait.take() {
+                        let name = format!("Fx0A waiting, got key {:X}", key);
+                        events.push(span(4, &name, start, entry.cycle));
+                    }
+                }
+                TimelineEvent::KeyDown(key) => keys[*key as usize] = Some(entry),
+                TimelineEvent::KeyUp(key) => {
+                    if let Some(start) = keys[*key as usize].take() {
+                        let name = format!("key {:X}", key);
+                        events.push(span(3, &name, start, entry.cycle));
+                    }
+                }
+                TimelineEvent::SaveFlags(_) | TimelineEvent::LoadFlags(_) => {
+                    events.push(instant(4, "t", entry))
+                }
+                // errors draw a line across every track
+                TimelineEvent::Error(_) => events.push(instant(4, "g", entry)),
+                _ => events.push(instant(1, "t", entry)),
+            }
+        }
+        if let Some(start) = sound {
+            events.push(span(2, &start.event.to_string(), start, self.cycles));
+        }
+        if let Some(start) = key_wait {
+            events.push(span(4, "Fx0A waiting", start, self.cycles));
+        }
+        for (key, start) in keys.iter().enumerate() {
+            if let Some(start) = start {
+                events.push(span(3, &format!("key {:X}", key), start, self.cycles));
+            }
+        }
+        format!("{{\"traceEvents\": [\n{}\n]}}\n", events.join(",\n"))
+    }
+}
+
+fn json_string(s: &str) -> String {
+    let mut json = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(json, "\\u{:04x}", c as u32);
+            }
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{BoolDisplay, Key, SeededRand};
+
+    // clears, beeps for 2 frames and waits for a key
+    const ROM: [u8; 10] = [
+        0x00, 0xE0, // CLS
+        0x60, 0x02, // LD V0, 2
+        0xF0, 0x18, // LD ST, V0
+        0xF1, 0x0A, // LD V1, K
+        0x12, 0x08, // JP 0x208
+    ];
+
+    fn timeline() -> Cpu<BoolDisplay, SeededRand> {
+        let mut cpu = Cpu::new(&ROM, BoolDisplay::new(1), SeededRand::new(0));
+        // 2 instructions a frame
+        cpu.set_clock_rate_hz(120);
+        cpu.timeline.start();
+        for _ in 0..10 {
+            cpu.step();
+        }
+        cpu.keyboard.toggle_key(Key::K7, true);
+        cpu.step();
+        cpu.step();
+        cpu.keyboard.toggle_key(Key::K7, false);
+        cpu.step();
+        cpu
+    }
+
+    fn entry(frame: u64, cycle: u64, pc: u16, event: TimelineEvent) -> TimelineEntry {
+        TimelineEntry {
+            frame,
+            cycle,
+            pc,
+            event,
+        }
+    }
+
+    #[test]
+    fn records_events() {
+        let cpu = timeline();
+        // the sound timer and the key wait show up at the instruction after they started
+        assert_eq!(
+            cpu.timeline.entries(),
+            &[
+                entry(1, 0, 0x200, TimelineEvent::Clear),
+                entry(2, 3, 0x206, TimelineEvent::SoundOn(2)),
+                entry(2, 3, 0x206, TimelineEvent::KeyWait),
+                entry(4, 6, 0x206, TimelineEvent::SoundOff),
+                entry(6, 10, 0x206, TimelineEvent::KeyDown(7)),
+                entry(6, 10, 0x206, TimelineEvent::KeyWaitDone(7)),
+                entry(7, 12, 0x208, TimelineEvent::KeyUp(7)),
+            ][..]
+        );
+        assert_eq!(
+            cpu.timeline.entries()[1].to_string(),
+            "frame      2 cycle        3 0x206  sound on for 2 frames"
+        );
+    }
+
+    #[test]
+    fn forgets_from_a_cycle() {
+        let mut cpu = timeline();
+        cpu.timeline.forget_from(10);
+        assert_eq!(cpu.timeline.entries().len(), 4);
+        cpu.timeline.forget_from(3);
+        assert_eq!(cpu.timeline.entries().len(), 1);
+        cpu.timeline.forget_from(0);
+        assert!(cpu.timeline.entries().is_empty());
+    }
+
+    #[test]
+    fn exports_json() {
+        let mut cpu = timeline();
+        cpu.timeline_error("bad \"pc\"");
+        assert_eq!(
+            cpu.timeline_json(),
+            r#"{"frames": 7, "cycles": 13, "events": [
+  {"frame": 1, "cycle": 0, "pc": 512, "event": "clear"},
+  {"frame": 2, "cycle": 3, "pc": 518, "event": "sound_on", "timer": 2},
+  {"frame": 2, "cycle": 3, "pc": 518, "event": "key_wait"},
+  {"frame": 4, "cycle": 6, "pc": 518, "event": "sound_off"},
+  {"frame": 6, "cycle": 10, "pc": 518, "event": "key_down", "key": 7},
+  {"frame": 6, "cycle": 10, "pc": 518, "event": "key_wait_done", "key": 7},
+  {"frame": 7, "cycle": 12, "pc": 520, "event": "key_up", "key": 7},
+  {"frame": 7, "cycle": 13, "pc": 520, "event": "error", "message": "bad \"pc\""}
+]}
+"#
+        );
+    }
+
+    #[test]
+    fn exports_chrome_trace() {
+        let cpu = timeline();
+        let trace = cpu.timeline_chrome_trace();
+        // 120 instructions a second, so a cycle is 8333.3 microseconds
+        for event in [
+            r#"{"ph": "M", "pid": 1, "tid": 2, "name": "thread_name", "args": {"name": "sound"}}"#,
+            r#"{"ph": "i", "s": "t", "pid": 1, "tid": 1, "ts": 0.0, "name": "clear screen", "args": {"frame": 1, "cycle": 0, "pc": 512}}"#,
+            r#"{"ph": "X", "pid": 1, "tid": 2, "ts": 25000.0, "dur": 25000.0, "name": "sound on for 2 frames", "args": {"frame": 2, "cycle": 3, "pc": 518}}"#,
+            r#"{"ph": "X", "pid": 1, "tid": 4, "ts": 25000.0, "dur": 58333.3, "name": "Fx0A waiting, got key 7", "args": {"frame": 2, "cycle": 3, "pc": 518}}"#,
+            r#"{"ph": "X", "pid": 1, "tid": 3, "ts": 83333.3, "dur": 16666.7, "name": "key 7", "args": {"frame": 6, "cycle": 10, "pc": 518}}"#,
+        ]
+        .iter()
+        {
+            assert!(trace.contains(event), "{} not in {}", event, trace);
+        }
+        assert_eq!(trace.matches("\"ph\": ").count(), 8);
+    }
+
+    #[test]
+    fn open_spans_end_now() {
+        let mut cpu = Cpu::new(&ROM, BoolDisplay::new(1), SeededRand::new(0));
+        cpu.set_clock_rate_hz(120);
+        cpu.timeline.start();
+        for _ in 0..5 {
+            cpu.step();
+        }
+        let trace = cpu.timeline_chrome_trace();
+        assert!(
+            trace.contains(r#""ts": 25000.0, "dur": 16666.7, "name": "sound on for 2 frames""#),
+            "{}",
+            trace
+        );
+        assert!(
+            trace.contains(r#""ts": 25000.0, "dur": 16666.7, "name": "Fx0A waiting""#),
+            "{}",
+            trace
+        );
+    }
+}
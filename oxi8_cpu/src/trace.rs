@@ -0,0 +1,320 @@
+use core::fmt;
+use std::{
+    collections::VecDeque,
+    io::{self, BufWriter, Write},
+    ops::Range,
+};
+
+use crate::{disassemble_with_symbols, Cpu, Display, OpcodeClass, Rand, Register};
+
+// instruction tracing: while on, every instruction that passes the filters is recorded with
+// what it changed, the newest ones kept in a ring buffer and/or streamed out one per line:
+//     1234 22A A2EA  LD I, 0x2EA <paddle> ; I=2EA
+//     1240 2DA F233  LD B, V2 ; [2F0]=01 [2F1]=02 [2F2]=08
+// left of the ';' is the cycle it ran at (Cpu::cycles() before it), pc, opcode and disassembly,
+// right of it every register it changed and every byte of ram it wrote, all in hex
+// pc changing isn't worth listing, and timers only show up when an instruction sets them
+// records are started before their instruction runs and finished after, so if it crashes the Cpu
+// the trace still ends with it, just without the right of the ';'
+
+// every register an instruction can change besides pc
+const TRACED_REGISTERS: [Register; 20] = [
+    Register::V(0x0),
+    Register::V(0x1),
+    Register::V(0x2),
+    Register::V(0x3),
+    Register::V(0x4),
+    Register::V(0x5),
+    Register::V(0x6),
+    Register::V(0x7),
+    Register::V(0x8),
+    Register::V(0x9),
+    Register::V(0xA),
+    Register::V(0xB),
+    Register::V(0xC),
+    Register::V(0xD),
+    Register::V(0xE),
+    Register::V(0xF),
+    Register::I,
+    Register::Sp,
+    Register::Delay,
+    Register::Sound,
+];
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct TraceRecord {
+    pub cycle: u64,
+    pub pc: u16,
+    pub opcode: u16,
+    pub disasm: String,
+    pub changed: Vec<(Register, u16, u16)>, // the register, its old value and its new value
+    pub writes: Vec<(u16, u8)>,             // address and the byte written, even if unchanged
+}
+
+impl fmt::Display for TraceRecord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:>8} {:03X} {:04X}  {}",
+            self.cycle, self.pc, self.opcode, self.disasm
+        )?;
+        write!(f, "{}", Changes(&self.changed, &self.writes))
+    }
+}
+
+// the ';' and everything right of it, nothing when nothing changed
+struct Changes<'a>(&'a [(Register, u16, u16)], &'a [(u16, u8)]);
+
+impl fmt::Display for Changes<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let Changes(changed, writes) = self;
+        if changed.is_empty() && writes.is_empty() {
+            return Ok(());
+        }
+        write!(f, " ;")?;
+        for (register, _, new) in changed.iter() {
+            match register {
+                Register::I => write!(f, " I={:03X}", new)?,
+                _ => write!(f, " {}={:02X}", register, new)?,
+            }
+        }
+        for (addr, byte) in writes.iter() {
+            write!(f, " [{:03X}]={:02X}", addr, byte)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+pub struct Tracer {
+    capacity: usize, // records kept, 0 keeps none
+    records: VecDeque<TraceRecord>,
+    out: Option<BufWriter<Box<dyn Write + Send>>>,
+    error: Option<String>,
+    ranges: Vec<Range<u16>>,
+    classes: Vec<OpcodeClass>,
+    open: bool, // the last record's instruction is still running, or crashed
+}
+
+impl Tracer {
+    pub fn is_active(&self) -> bool {
+        self.capacity > 0 || self.out.is_some()
+    }
+
+    // keeps the last capacity records, dropping the oldest, 0 stops keeping any
+    pub fn keep(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        while self.records.len() > capacity {
+            self.records.pop_front();
+        }
+    }
+
+    // writes every record to out as it happens, buffered, replacing any earlier stream
+    // if writing fails streaming stops and error() says why, the ring buffer carries on
+    pub fn stream_to(&mut self, out: impl Write + Send + 'static) {
+        self.stop_streaming();
+        self.out = Some(BufWriter::new(Box::new(out)));
+        self.error = None;
+    }
+
+    pub fn stop_streaming(&mut self) {
+        self.end_line();
+        if let Some(mut out) = self.out.take() {
+            if let Err(e) = out.flush() {
+                self.error = Some(e.to_string());
+            }
+        }
+    }
+
+    pub fn error(&self) -> Option<&str> {
+        self.error.as_deref()
+    }
+
+    // stops keeping and streaming, filters stay
+    pub fn stop(&mut self) {
+        self.keep(0);
+        self.stop_streaming();
+    }
+
+    // oldest first
+    pub fn records(&self) -> impl Iterator<Item = &TraceRecord> {
+        self.records.iter()
+    }
+
+    pub fn clear(&mut self) {
+        self.records.clear();
+    }
+
+    // with no filters every instruction is traced, with address ranges only instructions in
+    // one of them, with opcode classes only opcodes in one of them, and with both only those
+    // in a range and a class
+    pub fn add_range(&mut self, range: Range<u16>) {
+        self.ranges.push(range);
+    }
+
+    pub fn add_class(&mut self, class: OpcodeClass) {
+        self.classes.push(class);
+    }
+
+    pub fn clear_filters(&mut self) {
+        self.ranges.clear();
+        self.classes.clear();
+    }
+
+    pub fn ranges(&self) -> &[Range<u16>] {
+        &self.ranges
+    }
+
+    pub fn classes(&self) -> &[OpcodeClass] {
+        &self.classes
+    }
+
+    // writes every kept record, oldest first, for dumping what led up to a crash
+    pub fn dump(&self, out: &mut impl Write) -> io::Result<()> {
+        for record in self.records.iter() {
+            writeln!(out, "{}", record)?;
+        }
+        out.flush()
+    }
+
+    // rewinding makes records at or after cycle a future that never happened, so they go,
+    // anything already streamed out stays though
+    pub(crate) fn forget_from(&mut self, cycle: u64) {
+        while self
+            .records
+            .back()
+            .is_some_and(|record| record.cycle >= cycle)
+        {
+            self.records.pop_back();
+        }
+    }
+
+    fn wants(&self, pc: u16, opcode: u16) -> bool {
+        self.is_active()
+            && (self.ranges.is_empty() || self.ranges.iter().any(|range| range.contains(&pc)))
+            && (self.classes.is_empty() || self.classes.iter().any(|c| c.matches(opcode)))
+    }
+
+    // streams out everything left of the ';' and keeps the record, the rest comes in finish()
+    fn start(&mut self, record: TraceRecord) {
+        self.end_line();
+        self.write_out(format_args!("{}", record));
+        if self.capacity > 0 {
+            if self.records.len() == self.capacity {
+                self.records.pop_front();
+            }
+            self.records.push_back(record);
+        }
+        self.open = true;
+    }
+
+    fn finish(&mut self, changed: Vec<(Register, u16, u16)>, writes: Vec<(u16, u8)>) {
+        if !self.open {
+            return;
+        }
+        self.open = false;
+        self.write_out(format_args!("{}\n", Changes(&changed, &writes)));
+        if self.capacity > 0 {
+            if let Some(record) = self.records.back_mut() {
+                record.changed = changed;
+                record.writes = writes;
+            }
+        }
+    }
+
+    // ends the line of a record that never got finished since its instruction crashed
+    fn end_line(&mut self) {
+        if self.open {
+            self.open = false;
+            self.write_out(format_args!("\n"));
+        }
+    }
+
+    fn write_out(&mut self, text: fmt::Arguments) {
+        if let Some(out) = self.out.as_mut() {
+            if let Err(e) = out.write_fmt(text) {
+                self.out = None;
+                self.error = Some(e.to_string());
+            }
+        }
+    }
+}
+
+// what an instruction is about to do, taken right before it runs
+pub(crate) struct TraceStart {
+    before: [u16; TRACED_REGISTERS.len()],
+    write: Option<Range<u16>>,
+}
+
+impl<T: Display, R: Rand> Cpu<T, R> {
+    // None when the next instruction isn't being traced
+    pub(crate) fn trace_start(&mut self) -> Option<TraceStart> {
+        let opcode = self.opcode_at(self.pc);
+        if !self.tracer.wants(self.pc, opcode) {
+            return None;
+        }
+        let mut before = [0; TRACED_REGISTERS.len()];
+        for (value, register) in before.iter_mut().zip(TRACED_REGISTERS.iter()) {
+            *value = self.register(*register);
+        }
+        self.tracer.start(TraceRecord {
+            cycle: self.cycles,
+            pc: self.pc,
+            opcode,
+            disasm: disassemble_with_symbols(opcode, &self.symbols),
+            changed: Vec::new(),
+            writes: Vec::new(),
+        });
+        Some(TraceStart {
+            before,
+            write: self.memory_access().write,
+        })
+    }
+
+    pub(crate) fn trace_end(&mut self, start: TraceStart) {
+        let changed = TRACED_REGISTERS
+            .iter()
+            .zip(start.before.iter())
+            .map(|(register, old)| (*register, *old, self.register(*register)))
+            .filter(|(_, old, new)| old != new)
+            .collect();
+        let writes = start
+            .write
+            .unwrap_or(0..0)
+            .filter_map(|addr| self.ram.get(addr as usize).map(|byte| (addr, *byte)))
+            .collect();
+        self.tracer.finish(changed, writes);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{BoolDisplay, SeededRand};
+    use std::panic::{self, AssertUnwindSafe};
+
+    const CRASH: [u8; 4] = [
+        0xA2, 0xEA, // LD I, 0x2EA
+        0x00, 0xEE, // RET with nothing to return to
+    ];
+
+    #[test]
+    fn keeps_the_instruction_that_crashed() {
+        let mut cpu = Cpu::new(&CRASH, BoolDisplay::new(1), SeededRand::new(0));
+        cpu.tracer.keep(10);
+        let crashed = panic::catch_unwind(AssertUnwindSafe(|| {
+            cpu.execute_next_instruction();
+            cpu.execute_next_instruction();
+        }));
+        let message = crate::panic_message(&*crashed.unwrap_err());
+        assert_eq!(message, "returning with no value on stack?");
+        let records: Vec<String> = cpu.tracer.records().map(|r| r.to_string()).collect();
+        assert_eq!(
+            records,
+            [
+                "       0 200 A2EA  LD I, 0x2EA ; I=2EA",
+                "       1 202 00EE  RET"
+            ]
+        );
+    }
+}
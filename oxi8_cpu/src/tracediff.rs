@@ -0,0 +1,284 @@
+use core::fmt;
+
+use crate::{Register, NUM_REGISTERS};
+
+// comparing an oxi8 trace, as Tracer writes it, against one from another interpreter running
+// the same rom with the same inputs, to find where they start to behave differently
+// the other trace is one line per instruction with the registers as they were right before it ran:
+//   PC OPCODE V0 V1 ... VF I
+// 19 hex numbers separated by spaces or commas, each optionally with a 0x prefix or a label like
+// PC:0200 or V3=1F in front, blank lines and anything after a # are skipped
+// both traces have to start at the same instruction and leave none out, so trace everything
+
+// one instruction, with registers as they were right before it ran, None where unknown
+#[derive(Clone, Debug, PartialEq)]
+pub struct TraceStep {
+    pub line: usize, // 1 based, in the trace it came from
+    pub pc: u16,
+    pub opcode: u16,
+    pub v: [Option<u8>; NUM_REGISTERS],
+    pub i: Option<u16>,
+}
+
+impl TraceStep {
+    // registers only get known as instructions change them, unless the trace starts at cycle 0
+    // where the Cpu starts them all at 0
+    pub fn parse_oxi8(trace: &str) -> Result<Vec<TraceStep>, String> {
+        let mut steps = Vec::new();
+        let mut v = [None; NUM_REGISTERS];
+        let mut i = None;
+        let mut last_cycle: Option<u64> = None;
+        for (n, line) in trace.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let error = |what: &str| format!("{} on line {}: {}", what, n + 1, line);
+            let (instruction, changes) = match line.find(';') {
+                Some(at) => (&line[..at], &line[at + 1..]),
+                None => (line, ""),
+            };
+            let mut words = instruction.split_whitespace();
+            let cycle: u64 = words
+                .next()
+                .and_then(|cycle| cycle.parse().ok())
+                .ok_or_else(|| error("bad cycle"))?;
+            let pc = words
+                .next()
+                .and_then(|pc| u16::from_str_radix(pc, 16).ok())
+                .ok_or_else(|| error("bad pc"))?;
+            let opcode = words
+                .next()
+                .and_then(|opcode| u16::from_str_radix(opcode, 16).ok())
+                .ok_or_else(|| error("bad opcode"))?;
+            match last_cycle {
+                None if cycle == 0 => {
+                    v = [Some(0); NUM_REGISTERS];
+                    i = Some(0);
+                }
+                Some(last) if cycle != last + 1 => {
+                    return Err(error(&format!(
+                        "skipped from cycle {} to {}, filtered traces can't be compared",
+                        last, cycle
+                    )))
+                }
+                _ => (),
+            }
+            last_cycle = Some(cycle);
+            steps.push(TraceStep {
+                line: n + 1,
+                pc,
+                opcode,
+                v,
+                i,
+            });
+            for change in changes.split_whitespace() {
+                // ram writes don't matter here
+                if change.starts_with('[') {
+                    continue;
+                }
+                let mut parts = change.splitn(2, '=');
+                let register = parts.next().and_then(Register::from_name);
+                let value = parts.next().and_then(|v| u16::from_str_radix(v, 16).ok());
+                match (register, value) {
+                    (Some(Register::V(x)), Some(value)) => v[x as usize] = Some(value as u8),
+                    (Some(Register::I), Some(value)) => i = Some(value),
+                    (Some(_), Some(_)) => (),
+                    _ => return Err(error(&format!("bad change '{}'", change))),
+                }
+            }
+        }
+        Ok(steps)
+    }
+
+    pub fn parse_reference(trace: &str) -> Result<Vec<TraceStep>, String> {
+        let mut steps = Vec::new();
+        for (n, line) in trace.lines().enumerate() {
+            let content = line.split('#').next().unwrap_or("");
+            let numbers = content
+                .split(|c: char| c == ',' || c.is_whitespace())
+                .map(|word| word.rsplit([':', '=']).next().unwrap_or(""))
+                .filter(|word| !word.is_empty())
+                .map(|word| {
+                    let hex = word
+                        .strip_prefix("0x")
+                        .or_else(|| word.strip_prefix("0X"))
+                        .unwrap_or(word);
+                    u16::from_str_radix(hex, 16)
+                        .map_err(|_| format!("bad number '{}' on line {}: {}", word, n + 1, line))
+                })
+                .collect::<Result<Vec<u16>, String>>()?;
+            if numbers.is_empty() {
+                continue;
+            }
+            if numbers.len() != NUM_REGISTERS + 3 {
+                return Err(format!(
+                    "expected PC OPCODE V0-VF I, {} numbers, got {} on line {}: {}",
+                    NUM_REGISTERS + 3,
+                    numbers.len(),
+                    n + 1,
+                    line
+                ));
+            }
+            let mut v = [None; NUM_REGISTERS];
+            for (v, value) in v.iter_mut().zip(numbers[2..].iter()) {
+                *v = Some(*value as u8);
+            }
+            steps.push(TraceStep {
+                line: n + 1,
+                pc: numbers[0],
+                opcode: numbers[1],
+                v,
+                i: Some(numbers[NUM_REGISTERS + 2]),
+            });
+        }
+        Ok(steps)
+    }
+}
+
+// in the reference format, with ?? for anything unknown
+impl fmt::Display for TraceStep {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:03X} {:04X}", self.pc, self.opcode)?;
+        for v in self.v.iter() {
+            match v {
+                Some(v) => write!(f, " {:02X}", v)?,
+                None => write!(f, " ??")?,
+            }
+        }
+        match self.i {
+            Some(i) => write!(f, " {:03X}", i),
+            None => write!(f, " ???"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Divergence {
+    pub index: usize, // of the first step that differs, in both traces
+    pub differences: Vec<String>,
+}
+
+impl Divergence {
+    // the first step where pc, opcode or any register known to both differs, None if they agree
+    // for as long as both go
+    pub fn find(ours: &[TraceStep], theirs: &[TraceStep]) -> Option<Divergence> {
+        ours.iter()
+            .zip(theirs.iter())
+            .enumerate()
+            .find_map(|(index, (ours, theirs))| {
+                let differences = differences(ours, theirs);
+                if differences.is_empty() {
+                    None
+                } else {
+                    Some(Divergence { index, differences })
+                }
+            })
+    }
+}
+
+fn differences(ours: &TraceStep, theirs: &TraceStep) -> Vec<String> {
+    let mut differences = Vec::new();
+    if ours.pc != theirs.pc {
+        differences.push(format!("PC 0x{:03X} vs 0x{:03X}", ours.pc, theirs.pc));
+    } else if ours.opcode != theirs.opcode {
+        // the same pc holding different opcodes is ram being written differently
+        differences.push(format!(
+            "opcode {:04X} vs {:04X}",
+            ours.opcode, theirs.opcode
+        ));
+    }
+    for (x, (ours, theirs)) in ours.v.iter().zip(theirs.v.iter()).enumerate() {
+        if let (Some(ours), Some(theirs)) = (ours, theirs) {
+            if ours != theirs {
+                differences.push(format!("V{:X} 0x{:02X} vs 0x{:02X}", x, ours, theirs));
+            }
+        }
+    }
+    if let (Some(ours), Some(theirs)) = (ours.i, theirs.i) {
+        if ours != theirs {
+            differences.push(format!("I 0x{:03X} vs 0x{:03X}", ours, theirs));
+        }
+    }
+    differences
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{BoolDisplay, Cpu, SeededRand};
+
+    const ROM: [u8; 8] = [
+        0x60, 0x05, // LD V0, 5
+        0xA3, 0x00, // LD I, 0x300
+        0x70, 0x01, // ADD V0, 1
+        0x12, 0x04, // JP 0x204
+    ];
+
+    #[test]
+    fn oxi8_traces() {
+        let mut cpu = Cpu::new(&ROM, BoolDisplay::new(1), SeededRand::new(0));
+        cpu.tracer.keep(100);
+        for _ in 0..4 {
+            cpu.execute_next_instruction();
+        }
+        let mut trace = Vec::new();
+        cpu.tracer.dump(&mut trace).unwrap();
+        let steps = TraceStep::parse_oxi8(&String::from_utf8(trace).unwrap()).unwrap();
+        assert_eq!(steps.len(), 4);
+        assert_eq!((steps[3].pc, steps[3].opcode), (0x206, 0x1204));
+        assert_eq!(steps[3].v[0], Some(6));
+        assert_eq!(steps[3].i, Some(0x300));
+        assert_eq!(steps[0].v[0], Some(0));
+    }
+
+    #[test]
+    fn oxi8_traces_mid_run() {
+        let steps = TraceStep::parse_oxi8(
+            "  10 204 7001  ADD V0, 0x01 ; V0=06\n\n  11 206 1204  JP 0x204\n",
+        )
+        .unwrap();
+        assert_eq!(steps[0].v[0], None);
+        assert_eq!(steps[1].v[0], Some(6));
+        assert_eq!(steps[1].line, 3);
+        assert!(TraceStep::parse_oxi8("1 200 6005\n3 202 A300\n")
+            .unwrap_err()
+            .contains("skipped from cycle 1 to 3"));
+        assert!(TraceStep::parse_oxi8("x 200 6005")
+            .unwrap_err()
+            .contains("bad cycle"));
+        assert!(TraceStep::parse_oxi8("0 200 6005 ; Q=1")
+            .unwrap_err()
+            .contains("bad change 'Q=1'"));
+    }
+
+    #[test]
+    fn reference_traces() {
+        let line = "PC:0x0200 6005 V0=00 0 0 0 0 0 0 0 0 0 0 0 0 0 0 0x0F, I:0 # start";
+        let steps = TraceStep::parse_reference(&format!("# header\n{}\n", line)).unwrap();
+        assert_eq!(steps.len(), 1);
+        assert_eq!(
+            (steps[0].line, steps[0].pc, steps[0].opcode),
+            (2, 0x200, 0x6005)
+        );
+        assert_eq!(steps[0].v[15], Some(0xF));
+        assert!(TraceStep::parse_reference("200 6005 0")
+            .unwrap_err()
+            .contains("got 3"));
+        assert!(TraceStep::parse_reference("200 60G5")
+            .unwrap_err()
+            .contains("bad number '60G5'"));
+    }
+
+    #[test]
+    fn reference_round_trips() {
+        let text = "200 6005 00 01 02 03 04 05 06 07 08 09 0A 0B 0C 0D 0E 0F 300";
+        let steps = TraceStep::parse_reference(text).unwrap();
+        assert_eq!(steps[0].to_string(), text);
+        let mut unknown = steps[0].clone();
+        unknown.v[1] = None;
+        unknown.i = None;
+        assert!(unknown
+            .to_string()
+            .ends_with("00 ?? 02 03 04 05 06 07 08 09 0A 0B 0C 0D 0E 0F ???"));
+    }
+}
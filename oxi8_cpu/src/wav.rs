@@ -0,0 +1,82 @@
+use std::io::{self, Write};
+
+const BITS_PER_SAMPLE: u16 = 16;
+const CHANNELS: u16 = 1;
+
+// writes mono samples in -1.0 to 1.0 as a 16-bit PCM WAV file
+pub fn write_wav<W: Write>(samples: &[f32], sample_rate: u32, mut w: W) -> io::Result<()> {
+    let block_align = CHANNELS * BITS_PER_SAMPLE / 8;
+    let data_len = samples.len() as u32 * block_align as u32;
+
+    w.write_all(b"RIFF")?;
+    w.write_all(&(36 + data_len).to_le_bytes())?;
+    w.write_all(b"WAVE")?;
+
+    w.write_all(b"fmt ")?;
+    w.write_all(&16u32.to_le_bytes())?; // size of the rest of this chunk
+    w.write_all(&1u16.to_le_bytes())?; // PCM
+    w.write_all(&CHANNELS.to_le_bytes())?;
+    w.write_all(&sample_rate.to_le_bytes())?;
+    w.write_all(&(sample_rate * block_align as u32).to_le_bytes())?; // bytes per second
+    w.write_all(&block_align.to_le_bytes())?;
+    w.write_all(&BITS_PER_SAMPLE.to_le_bytes())?;
+
+    w.write_all(b"data")?;
+    w.write_all(&data_len.to_le_bytes())?;
+    let mut data = Vec::with_capacity(data_len as usize);
+    for sample in samples.iter() {
+        let sample = (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+        data.extend_from_slice(&sample.to_le_bytes());
+    }
+    w.write_all(&data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn u16_at(wav: &[u8], at: usize) -> u16 {
+        u16::from_le_bytes([wav[at], wav[at + 1]])
+    }
+
+    fn u32_at(wav: &[u8], at: usize) -> u32 {
+        u32::from_le_bytes([wav[at], wav[at + 1], wav[at + 2], wav[at + 3]])
+    }
+
+    #[test]
+    fn writes_headers_and_samples() {
+        let mut wav = Vec::new();
+        write_wav(&[0.0, 1.0, -1.0, 2.0, 0.5], 44100, &mut wav).unwrap();
+        assert_eq!(wav.len(), 44 + 10);
+        assert_eq!(&wav[0..4], b"RIFF");
+        assert_eq!(u32_at(&wav, 4), wav.len() as u32 - 8);
+        assert_eq!(&wav[8..12], b"WAVE");
+
+        assert_eq!(&wav[12..16], b"fmt ");
+        assert_eq!(u32_at(&wav, 16), 16);
+        assert_eq!(u16_at(&wav, 20), 1); // PCM
+        assert_eq!(u16_at(&wav, 22), 1); // mono
+        assert_eq!(u32_at(&wav, 24), 44100);
+        assert_eq!(u32_at(&wav, 28), 44100 * 2);
+        assert_eq!(u16_at(&wav, 32), 2);
+        assert_eq!(u16_at(&wav, 34), 16);
+
+        assert_eq!(&wav[36..40], b"data");
+        assert_eq!(u32_at(&wav, 40), 10);
+        let samples: Vec<i16> = wav[44..]
+            .chunks(2)
+            .map(|sample| i16::from_le_bytes([sample[0], sample[1]]))
+            .collect();
+        // out of range samples are clamped
+        assert_eq!(samples, [0, 32767, -32767, 32767, 16383]);
+    }
+
+    #[test]
+    fn writes_no_samples() {
+        let mut wav = Vec::new();
+        write_wav(&[], 8000, &mut wav).unwrap();
+        assert_eq!(wav.len(), 44);
+        assert_eq!(u32_at(&wav, 4), 36);
+        assert_eq!(u32_at(&wav, 40), 0);
+    }
+}
@@ -0,0 +1,614 @@
+use std::{
+    env, fs,
+    io::{self, BufRead, BufReader, Read, Write},
+    net::TcpListener,
+    panic::{self, AssertUnwindSafe},
+    sync::mpsc::{self, Receiver, RecvTimeoutError},
+    thread,
+    time::{Duration, Instant},
+};
+
+use serde_json::{json, Value};
+
+use oxi8_cpu::{
+    panic_message, BoolDisplay, Breakpoint, CheatFile, Cpu, Expr, Key, LogMessage, Register,
+    SanitizerMode, SeededRand, StopReason, SymbolMap,
+};
+
+// a Debug Adapter Protocol server, so editors like VS Code can debug CHIP-8 roms headless
+// speaks DAP on stdin/stdout, or with --port N on localhost:N for a single session
+//
+// launch arguments:
+//   program      the rom to debug
+//   lineMap      optional Octo line map, one 'LINE = 0xADDR' per line, # starts a comment,
+//                lets breakpoints be set on source lines and stack frames show them
+//   source       optional path of the .8o the line map is for, shown in stack frames
+//   symbols      optional label file, see SymbolMap, names subroutines in stack frames and can
+//                be used in expressions and as instruction breakpoint references
+//   seed         optional random seed, defaults to 0 so every session runs the same
+//   stopOnEntry  optional, stop before the first instruction runs
+//   sanitize     optional "warn" or "stop", reports things a working rom shouldn't do, like
+//                reading registers nothing set, as console output, and with "stop" also stops
+//                right before them as an exception
+//   cheats       optional cheat file, see CheatFile, the cheats in it for this rom are used as
+//                they are, freezes and all
+//
+// while running, frames run at 60hz like the real thing, roms waiting on keys get them from the
+// debug console with 'key K down' and 'key K up', K being 0-F, anything else typed there is an
+// expression, and a crash in the Cpu stops it as an exception
+
+const THREAD_ID: i64 = 1;
+const REGISTERS_REF: i64 = 1;
+const TIMERS_REF: i64 = 2;
+
+const FRAME: Duration = Duration::from_nanos(1_000_000_000 / 60);
+
+const REGISTERS: [Register; 19] = [
+    Register::V(0x0),
+    Register::V(0x1),
+    Register::V(0x2),
+    Register::V(0x3),
+    Register::V(0x4),
+    Register::V(0x5),
+    Register::V(0x6),
+    Register::V(0x7),
+    Register::V(0x8),
+    Register::V(0x9),
+    Register::V(0xA),
+    Register::V(0xB),
+    Register::V(0xC),
+    Register::V(0xD),
+    Register::V(0xE),
+    Register::V(0xF),
+    Register::I,
+    Register::Pc,
+    Register::Sp,
+];
+
+struct Session {
+    out: Box<dyn Write>,
+    seq: i64,
+    cpu: Option<Cpu<BoolDisplay, SeededRand>>,
+    line_map: Vec<(u32, u16)>, // source line and the address it assembled to, sorted by line
+    source: Option<String>,
+    // set by line, replaced as a whole by every setBreakpoints, the debugger has one breakpoint
+    // per address so whichever kind was set last at an address is the one in effect
+    source_breakpoints: Vec<Breakpoint>,
+    instruction_breakpoints: Vec<Breakpoint>, // same, but from setInstructionBreakpoints
+    stop_on_entry: bool,
+    running: bool,
+}
+
+impl Session {
+    fn send(&mut self, mut message: Value) {
+        self.seq += 1;
+        message["seq"] = json!(self.seq);
+        let body = message.to_string();
+        // nothing to be done if the editor went away, the read side will notice and exit
+        let _ = write!(self.out, "Content-Length: {}\r\n\r\n{}", body.len(), body);
+        let _ = self.out.flush();
+    }
+
+    fn respond(&mut self, request: &Value, result: Result<Value, String>) {
+        let mut response = json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": request["command"],
+            "success": result.is_ok(),
+        });
+        match result {
+            Ok(body) => response["body"] = body,
+            Err(message) => response["message"] = json!(message),
+        }
+        self.send(response);
+    }
+
+    fn event(&mut self, event: &str, body: Value) {
+        self.send(json!({ "type": "event", "event": event, "body": body }));
+    }
+
+    fn stopped(&mut self, reason: &str, description: String) {
+        self.running = false;
+        self.event(
+            "stopped",
+            json!({
+                "reason": reason,
+                "description": description,
+                "threadId": THREAD_ID,
+                "allThreadsStopped": true,
+            }),
+        );
+    }
+
+    fn cpu(&mut self) -> Result<&mut Cpu<BoolDisplay, SeededRand>, String> {
+        self.cpu
+            .as_mut()
+            .ok_or_else(|| "no program launched".to_string())
+    }
+
+    // returns false once the session is over
+    fn handle(&mut self, request: Value) -> bool {
+        let command = request["command"].as_str().unwrap_or("").to_string();
+        let args = &request["arguments"];
+        let result = match command.as_str() {
+            "initialize" => Ok(json!({
+                "supportsConfigurationDoneRequest": true,
+                "supportsConditionalBreakpoints": true,
+                "supportsHitConditionalBreakpoints": true,
+                "supportsLogPoints": true,
+                "supportsInstructionBreakpoints": true,
+                "supportsReadMemoryRequest": true,
+                "supportsEvaluateForHovers": true,
+                "supportsTerminateRequest": true,
+            })),
+            "launch" => self.launch(args),
+            "setBreakpoints" => self.set_breakpoints(args),
+            "setInstructionBreakpoints" => self.set_instruction_breakpoints(args),
+            "setExceptionBreakpoints" => Ok(json!({ "breakpoints": [] })),
+            "configurationDone" => Ok(json!({})),
+            "threads" => Ok(json!({ "threads": [{ "id": THREAD_ID, "name": "CHIP-8" }] })),
+            "stackTrace" => self.stack_trace(),
+            "scopes" => Ok(json!({ "scopes": [
+                { "name": "Registers", "variablesReference": REGISTERS_REF, "expensive": false },
+                { "name": "Timers", "variablesReference": TIMERS_REF, "expensive": false },
+            ]})),
+            "variables" => self.variables(args),
+            "readMemory" => self.read_memory(args),
+            "evaluate" => self.evaluate(args),
+            "continue" => self
+                .resume(|_| ())
+                .map(|_| json!({ "allThreadsContinued": true })),
+            "next" => self.resume(Cpu::step_over),
+            "stepIn" => self.resume(Cpu::step_into),
+            "stepOut" => self.resume(Cpu::step_out),
+            "pause" => Ok(json!({})),
+            "disconnect" | "terminate" => {
+                self.respond(&request, Ok(json!({})));
+                self.event("terminated", json!({}));
+                return false;
+            }
+            _ => Err(format!("unsupported request: {}", command)),
+        };
+        self.respond(&request, result);
+
+        // events that must come after their response
+        match command.as_str() {
+            // after launch rather than initialize, so breakpoints can't arrive before the rom
+            "launch" => self.event("initialized", json!({})),
+            "configurationDone" if self.stop_on_entry => self.stopped("entry", "entry".to_string()),
+            "configurationDone" => self.running = true,
+            "pause" if self.running => self.stopped("pause", "paused".to_string()),
+            _ => (),
+        }
+        true
+    }
+
+    fn launch(&mut self, args: &Value) -> Result<Value, String> {
+        let program = args["program"].as_str().ok_or("launch needs a program")?;
+        let rom = fs::read(program).map_err(|e| format!("unable to read {}: {}", program, e))?;
+        if let Some(line_map) = args["lineMap"].as_str() {
+            let line_map = fs::read_to_string(line_map)
+                .map_err(|e| format!("unable to read {}: {}", line_map, e))?;
+            self.line_map = parse_line_map(&line_map)?;
+        }
+        self.source = args["source"].as_str().map(str::to_string);
+        self.stop_on_entry = args["stopOnEntry"].as_bool().unwrap_or(false);
+        let seed = args["seed"].as_u64().unwrap_or(0);
+        let mut cpu = Cpu::new(&rom, BoolDisplay::new(1), SeededRand::new(seed));
+        if let Some(symbols) = args["symbols"].as_str() {
+            let symbols = fs::read_to_string(symbols)
+                .map_err(|e| format!("unable to read {}: {}", symbols, e))?;
+            cpu.symbols = SymbolMap::parse(&symbols)?;
+        }
+        match args["sanitize"].as_str() {
+            None | Some("off") => (),
+            Some("warn") => cpu.sanitize(SanitizerMode::Warn),
+            Some("stop") => cpu.sanitize(SanitizerMode::Stop),
+            Some(mode) => return Err(format!("sanitize is warn, stop or off, not {}", mode)),
+        }
+        if let Some(cheats) = args["cheats"].as_str() {
+            let cheats = fs::read_to_string(cheats)
+                .map_err(|e| format!("unable to read {}: {}", cheats, e))?;
+            let cheats = CheatFile::parse(&cheats)?.cheats_for(cpu.rom_hash());
+            cpu.load_cheats(cheats);
+        }
+        self.cpu = Some(cpu);
+        Ok(json!({}))
+    }
+
+    // takes out breakpoints no longer wanted, putting back one of the other kind at the same
+    // address if there is one
+    fn remove_breakpoints(&mut self, removed: Vec<Breakpoint>) -> Result<(), String> {
+        for breakpoint in removed {
+            let other = self
+                .source_breakpoints
+                .iter()
+                .chain(&self.instruction_breakpoints)
+                .find(|other| other.addr == breakpoint.addr)
+                .cloned();
+            let debugger = &mut self.cpu()?.debugger;
+            match other {
+                Some(other) => debugger.set_breakpoint(other),
+                None => debugger.remove_breakpoint(breakpoint.addr),
+            }
+        }
+        Ok(())
+    }
+
+    fn set_breakpoints(&mut self, args: &Value) -> Result<Value, String> {
+        let removed = std::mem::take(&mut self.source_breakpoints);
+        self.remove_breakpoints(removed)?;
+        let mut verified = Vec::new();
+        for requested in args["breakpoints"].as_array().cloned().unwrap_or_default() {
+            let line = requested["line"].as_u64().unwrap_or(0) as u32;
+            // lines with no code move down to the next line that has some, like most debuggers
+            match self.line_map.iter().find(|(l, _)| *l >= line).cloned() {
+                Some((line, addr)) => {
+                    let breakpoint = new_breakpoint(addr, &requested, &self.cpu()?.symbols)?;
+                    self.cpu()?.debugger.set_breakpoint(breakpoint.clone());
+                    self.source_breakpoints.push(breakpoint);
+                    verified.push(json!({ "verified": true, "line": line }));
+                }
+                None => verified.push(json!({
+                    "verified": false,
+                    "line": line,
+                    "message": "no code at or after this line in the line map",
+                })),
+            }
+        }
+        Ok(json!({ "breakpoints": verified }))
+    }
+
+    fn set_instruction_breakpoints(&mut self, args: &Value) -> Result<Value, String> {
+        let removed = std::mem::take(&mut self.instruction_breakpoints);
+        self.remove_breakpoints(removed)?;
+        let mut verified = Vec::new();
+        for requested in args["breakpoints"].as_array().cloned().unwrap_or_default() {
+            let reference = requested["instructionReference"].as_str().unwrap_or("");
+            let offset = requested["offset"].as_i64().unwrap_or(0);
+            let symbols = &self.cpu()?.symbols;
+            let addr = parse_addr(reference).or_else(|| symbols.addr(reference.trim()));
+            match addr.map(|addr| addr as i64 + offset) {
+                Some(addr) if (0..=0xFFF).contains(&addr) => {
+                    let breakpoint = new_breakpoint(addr as u16, &requested, symbols)?;
+                    self.cpu()?.debugger.set_breakpoint(breakpoint.clone());
+                    self.instruction_breakpoints.push(breakpoint);
+                    verified.push(json!({
+                        "verified": true,
+                        "instructionReference": format!("0x{:03X}", addr),
+                    }));
+                }
+                _ => verified.push(json!({ "verified": false, "message": "bad address" })),
+            }
+        }
+        Ok(json!({ "breakpoints": verified }))
+    }
+
+    fn stack_trace(&mut self) -> Result<Value, String> {
+        let cpu = self.cpu.as_ref().ok_or("no program launched")?;
+        // innermost first, each frame is named for the subroutine it's in and is at the pc it
+        // will continue from, with main, outside of any subroutine, last
+        let mut frames: Vec<(String, u16)> = cpu
+            .call_stack()
+            .iter()
+            .map(|frame| {
+                let name = match cpu.symbols.name(frame.target) {
+                    Some(name) => name.to_string(),
+                    None => format!("sub 0x{:03X}", frame.target),
+                };
+                (name, frame.pc)
+            })
+            .collect();
+        let main_pc = cpu
+            .call_stack()
+            .last()
+            .map_or(cpu.register(Register::Pc), |frame| frame.call_site);
+        frames.push(("main".to_string(), main_pc));
+        let mut stack_frames = Vec::new();
+        for (id, (name, pc)) in frames.iter().enumerate() {
+            let mut frame = json!({
+                "id": id,
+                "name": name,
+                "line": 0,
+                "column": 0,
+                "instructionPointerReference": format!("0x{:03X}", pc),
+            });
+            if let (Some(source), Some(line)) = (self.source.as_ref(), self.line_of(*pc)) {
+                frame["source"] = json!({ "path": source });
+                frame["line"] = json!(line);
+                frame["column"] = json!(1);
+            }
+            stack_frames.push(frame);
+        }
+        Ok(json!({ "stackFrames": stack_frames, "totalFrames": stack_frames.len() }))
+    }
+
+    // the line of the closest address at or before addr
+    fn line_of(&self, addr: u16) -> Option<u32> {
+        self.line_map
+            .iter()
+            .filter(|(_, a)| *a <= addr)
+            .max_by_key(|(_, a)| *a)
+            .map(|(line, _)| *line)
+    }
+
+    fn variables(&mut self, args: &Value) -> Result<Value, String> {
+        let cpu = self.cpu.as_ref().ok_or("no program launched")?;
+        let variable = |name: String, value: String| json!({ "name": name, "value": value, "variablesReference": 0 });
+        let variables: Vec<Value> = match args["variablesReference"].as_i64() {
+            Some(REGISTERS_REF) => REGISTERS
+                .iter()
+                .map(|register| {
+                    let value = cpu.register(*register);
+                    let mut variable = variable(register.to_string(), format!("0x{:02X}", value));
+                    if *register == Register::I || *register == Register::Pc {
+                        variable["memoryReference"] = json!(format!("0x{:03X}", value));
+                    }
+                    variable
+                })
+                .collect(),
+            Some(TIMERS_REF) => vec![
+                variable("DT".into(), cpu.register(Register::Delay).to_string()),
+                variable("ST".into(), cpu.register(Register::Sound).to_string()),
+                variable("frame".into(), cpu.frame().to_string()),
+                variable("cycles".into(), cpu.cycles().to_string()),
+            ],
+            _ => Vec::new(),
+        };
+        Ok(json!({ "variables": variables }))
+    }
+
+    fn read_memory(&mut self, args: &Value) -> Result<Value, String> {
+        let cpu = self.cpu.as_ref().ok_or("no program launched")?;
+        let reference = args["memoryReference"].as_str().unwrap_or("0");
+        let addr = parse_addr(reference).ok_or("bad memoryReference")? as i64
+            + args["offset"].as_i64().unwrap_or(0);
+        let count = args["count"].as_u64().unwrap_or(0) as usize;
+        let ram = cpu.ram();
+        if addr < 0 || addr as usize >= ram.len() {
+            return Ok(json!({ "address": format!("0x{:03X}", addr), "unreadableBytes": count }));
+        }
+        let start = addr as usize;
+        let end = (start + count).min(ram.len());
+        Ok(json!({
+            "address": format!("0x{:03X}", start),
+            "data": base64::encode(&ram[start..end]),
+            "unreadableBytes": count - (end - start),
+        }))
+    }
+
+    fn evaluate(&mut self, args: &Value) -> Result<Value, String> {
+        let cpu = self.cpu.as_mut().ok_or("no program launched")?;
+        let expression = args["expression"].as_str().unwrap_or("");
+        if args["context"] == "repl" {
+            if let Some(key) = expression.trim().strip_prefix("key ") {
+                return press_key(cpu, key);
+            }
+        }
+        let expr = Expr::parse_with_symbols(expression, &cpu.symbols)?;
+        let value = expr.eval(cpu);
+        Ok(json!({
+            "result": format!("{} (0x{:X})", value, value),
+            "variablesReference": 0,
+        }))
+    }
+
+    fn resume(&mut self, goal: fn(&mut Cpu<BoolDisplay, SeededRand>)) -> Result<Value, String> {
+        goal(self.cpu()?);
+        self.running = true;
+        Ok(json!({}))
+    }
+
+    // runs one 60hz frame, sending tracepoint output and reporting any stop
+    fn run_frame(&mut self) {
+        let cpu = match self.cpu.as_mut() {
+            Some(cpu) => cpu,
+            None => return,
+        };
+        // the state is left as it was when it crashed, for looking around in
+        let stop = match panic::catch_unwind(AssertUnwindSafe(|| cpu.cycle_60hz())) {
+            Ok(stop) => stop,
+            Err(e) => {
+                let message = format!("cpu crashed: {}", panic_message(&*e));
+                self.stopped("exception", message);
+                return;
+            }
+        };
+        let mut logs = cpu.debugger.take_logs();
+        // a finding that stopped it is the stop's description instead
+        logs.extend(
+            cpu.sanitizer
+                .take_findings()
+                .into_iter()
+                .filter(|finding| stop != Some(StopReason::Sanitizer(*finding)))
+                .map(|finding| format!("sanitizer: {}", finding)),
+        );
+        for log in logs {
+            self.event(
+                "output",
+                json!({ "category": "console", "output": log + "\n" }),
+            );
+        }
+        if let Some(stop) = stop {
+            let reason = match stop {
+                StopReason::Step | StopReason::StepOver | StopReason::StepOut => "step",
+                StopReason::RunTo(_) => "goto",
+                StopReason::Breakpoint(_) => "breakpoint",
+                StopReason::Watchpoint(..) | StopReason::Register(..) => "data breakpoint",
+                StopReason::Opcode(..) => "instruction breakpoint",
+                StopReason::Sanitizer(_) => "exception",
+            };
+            self.stopped(reason, stop.to_string());
+        }
+    }
+}
+
+// K down or K up, like oxi8_debug's key command
+fn press_key(cpu: &mut Cpu<BoolDisplay, SeededRand>, args: &str) -> Result<Value, String> {
+    let mut words = args.split_whitespace();
+    let key = words
+        .next()
+        .and_then(|key| u8::from_str_radix(key, 16).ok())
+        .and_then(Key::from_u8)
+        .ok_or("key needs a key 0-F")?;
+    let pressed = match words.next() {
+        Some("down") => true,
+        Some("up") => false,
+        _ => return Err("key needs down or up".to_string()),
+    };
+    cpu.keyboard.toggle_key(key, pressed);
+    let state = if pressed { "down" } else { "up" };
+    Ok(json!({
+        "result": format!("key {:X} {}", key as u8, state),
+        "variablesReference": 0,
+    }))
+}
+
+// condition, hitCondition and logMessage are all optional on both kinds of breakpoint
+fn new_breakpoint(addr: u16, requested: &Value, symbols: &SymbolMap) -> Result<Breakpoint, String> {
+    let mut breakpoint = Breakpoint::new(addr);
+    if let Some(condition) = requested["condition"].as_str().filter(|c| !c.is_empty()) {
+        breakpoint.condition = Some(Expr::parse_with_symbols(condition, symbols)?);
+    }
+    if let Some(hit_condition) = requested["hitCondition"].as_str() {
+        // only 'N' or '>= N', stopping from the Nth hit on, is what the debugger can count
+        let count = hit_condition.trim();
+        let count = count.strip_prefix(">=").unwrap_or(count);
+        breakpoint.hit_count = count
+            .trim()
+            .parse()
+            .map_err(|_| format!("unsupported hit condition: {}", hit_condition))?;
+    }
+    if let Some(log) = requested["logMessage"].as_str() {
+        breakpoint.log = Some(LogMessage::parse_with_symbols(log, symbols)?);
+    }
+    Ok(breakpoint)
+}
+
+fn parse_addr(addr: &str) -> Option<u16> {
+    let addr = addr.trim();
+    match addr.strip_prefix("0x").or_else(|| addr.strip_prefix("0X")) {
+        Some(hex) => u16::from_str_radix(hex, 16).ok(),
+        None => addr.parse().ok(),
+    }
+}
+
+fn parse_line_map(line_map: &str) -> Result<Vec<(u32, u16)>, String> {
+    let mut lines = Vec::new();
+    for line in line_map.lines() {
+        let line = line.split('#').next().unwrap_or("").trim();
+        if line.is_empty() {
+            continue;
+        }
+        let mut parts = line.splitn(2, '=').map(str::trim);
+        match (
+            parts.next().and_then(|l| l.parse().ok()),
+            parts.next().and_then(parse_addr),
+        ) {
+            (Some(source_line), Some(addr)) => lines.push((source_line, addr)),
+            _ => return Err(format!("expected 'LINE = 0xADDR' in line map: {}", line)),
+        }
+    }
+    lines.sort();
+    Ok(lines)
+}
+
+// reads DAP messages on their own thread, so the Cpu can keep running between them
+fn read_messages(input: Box<dyn Read + Send>) -> Receiver<Value> {
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        let mut input = BufReader::new(input);
+        loop {
+            let mut len = None;
+            loop {
+                let mut header = String::new();
+                match input.read_line(&mut header) {
+                    Ok(0) | Err(_) => return,
+                    Ok(_) => (),
+                }
+                let header = header.trim();
+                if header.is_empty() {
+                    break;
+                }
+                if let Some(value) = header.strip_prefix("Content-Length:") {
+                    len = value.trim().parse::<usize>().ok();
+                }
+            }
+            let mut body = vec![0; len.unwrap_or(0)];
+            if input.read_exact(&mut body).is_err() {
+                return;
+            }
+            match serde_json::from_slice(&body) {
+                Ok(message) => {
+                    if tx.send(message).is_err() {
+                        return;
+                    }
+                }
+                Err(e) => eprintln!("ignoring bad DAP message: {}", e),
+            }
+        }
+    });
+    rx
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    let (input, out): (Box<dyn Read + Send>, Box<dyn Write>) = match args.get(1).map(String::as_str)
+    {
+        Some("--port") => {
+            let port = args
+                .get(2)
+                .and_then(|p| p.parse::<u16>().ok())
+                .unwrap_or_else(|| {
+                    eprintln!("usage: {} [--port PORT]", args[0]);
+                    std::process::exit(1);
+                });
+            let listener = TcpListener::bind(("127.0.0.1", port)).expect("Unable to listen");
+            eprintln!("DAP server listening on 127.0.0.1:{}", port);
+            let (client, _) = listener.accept().expect("Unable to accept connection");
+            let out = client.try_clone().expect("Unable to clone connection");
+            (Box::new(client), Box::new(out))
+        }
+        _ => (Box::new(io::stdin()), Box::new(io::stdout())),
+    };
+
+    let messages = read_messages(input);
+    let mut session = Session {
+        out,
+        seq: 0,
+        cpu: None,
+        line_map: Vec::new(),
+        source: None,
+        source_breakpoints: Vec::new(),
+        instruction_breakpoints: Vec::new(),
+        stop_on_entry: false,
+        running: false,
+    };
+    let mut next_frame = Instant::now();
+    loop {
+        let message = if session.running {
+            match messages.recv_timeout(next_frame.saturating_duration_since(Instant::now())) {
+                Ok(message) => Some(message),
+                Err(RecvTimeoutError::Timeout) => None,
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+        } else {
+            match messages.recv() {
+                Ok(message) => Some(message),
+                Err(_) => break,
+            }
+        };
+        match message {
+            Some(message) => {
+                if !session.handle(message) {
+                    break;
+                }
+            }
+            None => {
+                session.run_frame();
+                // falling behind skips frames rather than rushing through them to catch up
+                next_frame = (next_frame + FRAME).max(Instant::now());
+            }
+        }
+    }
+}
@@ -0,0 +1,1052 @@
+use std::{
+    env, fs,
+    ops::Range,
+    panic::{self, AssertUnwindSafe},
+    path::PathBuf,
+};
+
+use rustyline::{error::ReadlineError, DefaultEditor};
+
+use oxi8_cpu::{
+    disassemble_with_symbols, panic_message, render_text, Access, BoolDisplay, Breakpoint, Cheat,
+    CheatFile, CheatFilter, CheatSearch, CheatTarget, Coverage, Cpu, Expr, Key, OpcodeClass,
+    RamImage, Register, SanitizerMode, SeededRand, State, StopReason, SymbolMap, TextStyle,
+};
+
+// a gdb style command line debugger over a headless Cpu:
+//   oxi8_debug ROM [--seed N] [--clock HZ] [--symbols FILE] [--sanitize warn|stop]
+//                  [--cheats FILE]
+// type help at the prompt for the commands, an empty line repeats the last one like gdb
+
+const HISTORY_FILE: &str = ".oxi8_debug_history"; // in $HOME
+
+// headless there is no ctrl+c to break in with, so continue gives up after this many frames
+const CONTINUE_MAX_FRAMES: u64 = 60 * 60 * 10; // 10 emulated minutes
+
+const DISAS_COUNT: usize = 10;
+
+// the last this many instructions are always traced, to show what led up to a crash
+const TRACE_RECORDS: usize = 10_000;
+const TRACE_SHOW: usize = 20;
+
+const PROFILE_TOP: usize = 20;
+
+const TIMELINE_SHOW: usize = 20;
+
+const CHEAT_SHOW: usize = 20;
+
+// ram images are written bigger than 1 pixel a bit, they're hard to see otherwise
+const IMAGE_SCALE: usize = 4;
+
+// reverse execution snapshots every HISTORY_EVERY cycles, keeping about an hour at 1000hz
+const HISTORY_EVERY: u64 = 1000;
+const HISTORY_SNAPSHOTS: usize = 3600;
+
+const HELP: &str = "\
+step [N]              run N instructions, into subroutines (s)
+next [N]              run N instructions, over subroutines (n)
+finish                run until the current subroutine returns
+continue [FRAMES]     run until something stops it (c)
+frame [N]             run N 60hz frames, default 1, stopping early like continue
+until ADDR            run until pc reaches ADDR
+step-back [N]         go back N instructions (rs)
+reverse-continue      go back to the last time a breakpoint would have stopped (rc)
+who-wrote ADDR        find the last instruction that wrote the byte at ADDR
+break ADDR [if EXPR]  stop before ADDR runs, when EXPR is true if given (b)
+break                 list breakpoints, watchpoints and opcode breaks
+delete ADDR           remove the breakpoint at ADDR
+watch ADDR[-END] [r|w|rw]
+                      stop after RAM from ADDR to END (inclusive) is written (default),
+                      read, or either
+watch REGISTER        stop after a register changes, like watch v3 or watch i
+watch opcode CLASS    stop before an opcode matching CLASS runs, like watch opcode Dxyn
+unwatch ...           remove a watch, same arguments as watch
+regs                  print registers and timers
+print EXPR            evaluate an expression, like print ram[i] + v3 (p)
+set REGISTER EXPR     change a register, like set v3 0x10
+x/NFU ADDR            examine N units of RAM, F is x (hex) or d (decimal),
+                      U is b (bytes) or h (2 byte words), like x/16xb i
+disas [ADDR] [N]      disassemble N instructions at ADDR, default pc
+stack                 print the call stack
+trace [N]             print the last N traced instructions, default 20
+trace keep N          keep the last N traced instructions, 0 to stop, default 10000
+trace file FILE       also write every traced instruction to FILE
+trace file            stop writing to a file
+trace only ADDR[-END] only trace instructions from ADDR to END
+trace only opcode CLASS
+                      only trace opcodes in CLASS, like Dxyn
+trace all             trace every instruction again
+trace save FILE       write the kept traced instructions to FILE
+profile start|stop    count instructions per address and subroutine, or stop counting
+profile [N]           print the profile with the N hottest addresses, default 20
+profile clear         forget everything counted so far
+profile flame FILE    write the profile's call stacks to FILE for flame graph tools
+coverage start|stop   note which bytes of ram run, are read and are written, or stop
+coverage              print how much of the rom was covered, also makes disas show
+                      bytes only ever read as data
+coverage clear        forget everything covered so far
+coverage save FILE    write the coverage map to FILE
+coverage load FILE    add a coverage map from an earlier run to this one
+sanitize warn|stop    report things a working rom shouldn't do, like reading registers
+                      nothing set or running outside the rom, or also stop right before
+                      them, registers and ram only count as unset from the start
+sanitize off          stop checking
+timeline start|stop   note screen clears, mode switches, scrolls, sound, key waits, keys,
+                      flag saves and errors per frame, or stop
+timeline [N]          print the last N timeline events, default 20
+timeline clear        forget the timeline so far
+timeline json FILE    write the timeline to FILE as JSON
+timeline chrome FILE  write the timeline to FILE in Chrome's trace event format, for
+                      chrome://tracing or Perfetto
+ramview FILE [WIDTH]  write ram to FILE as a PNG bitmap, 8 pixels a byte, WIDTH bytes a row
+                      in each column, default 1, 2 suits 16x16 sprites, with pc, I and the
+                      next sprite drawn tinted
+sprite [FILE]         print the sprite the next Dxyn would draw from I, or write it to FILE
+                      as a PNG
+cheat start           search for where the rom keeps something like lives, starting with
+                      all of ram and V0-VF as candidates
+cheat same|changed|up|down
+                      keep the candidates that stayed the same, changed, went up or went
+                      down since the last filter
+cheat N               keep the candidates equal to N now
+cheat [list [N]]      print up to N candidates, default 20
+cheat set TARGET N    write N to TARGET, a ram address or V register, like cheat set v3 9
+cheats load FILE      use this rom's cheats from the cheat file FILE, replacing any in use
+cheats                list the cheats in use and whether they're on
+cheats on|off N       turn cheat N on or off, patches are written or put back right away
+cheats add NAME: ACTION; ...
+                      add a cheat, turned on, ACTION is freeze TARGET N to keep TARGET at N
+                      or patch ADDR N... to write bytes over the rom, like
+                      cheats add lives: freeze 0x2F0 3
+cheats delete N       turn cheat N off and forget it
+cheats save FILE      write the cheats in use to FILE, keeping other roms' cheats in it
+screen [ascii|half|braille|ansi]
+                      print the display as text
+key K down|up         press or release key K, 0-F
+save FILE             save the whole machine state to FILE
+load FILE             restore the machine state from FILE
+symbols FILE          load labels from FILE, replacing any loaded before
+quit                  exit (q)
+addresses and counts are expressions, so 0x2EA, i, pc + 4 and loaded labels all work";
+
+struct Debugger {
+    cpu: Cpu<BoolDisplay, SeededRand>,
+    rom_name: String, // for cheat files
+    cheat_search: Option<CheatSearch>,
+}
+
+fn main() {
+    let mut args = env::args().skip(1);
+    let mut rom_path = None;
+    let mut seed = 0;
+    let mut clock_rate_hz = None;
+    let mut symbols_path = None;
+    let mut cheats_path = None;
+    let mut sanitize = SanitizerMode::Off;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--seed" => seed = number_arg(&arg, args.next()),
+            "--clock" => clock_rate_hz = Some(number_arg(&arg, args.next()) as u32),
+            "--symbols" => symbols_path = Some(args.next().unwrap_or_else(|| usage())),
+            "--cheats" => cheats_path = Some(args.next().unwrap_or_else(|| usage())),
+            "--sanitize" => {
+                sanitize = args
+                    .next()
+                    .and_then(|mode| sanitizer_mode(&mode))
+                    .unwrap_or_else(|| usage())
+            }
+            _ if rom_path.is_none() => rom_path = Some(arg),
+            _ => usage(),
+        }
+    }
+    let rom_path = rom_path.unwrap_or_else(|| usage());
+    let rom = fs::read(&rom_path).unwrap_or_else(|e| {
+        eprintln!("can't read {}: {}", rom_path, e);
+        std::process::exit(1);
+    });
+    let mut cpu = Cpu::new(&rom, BoolDisplay::new(1), SeededRand::new(seed));
+    if let Some(clock_rate_hz) = clock_rate_hz {
+        cpu.set_clock_rate_hz(clock_rate_hz);
+    }
+    cpu.record_history(HISTORY_EVERY, HISTORY_SNAPSHOTS);
+    cpu.tracer.keep(TRACE_RECORDS);
+    cpu.sanitize(sanitize);
+    let rom_name = PathBuf::from(&rom_path)
+        .file_stem()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let mut debugger = Debugger {
+        cpu,
+        rom_name,
+        cheat_search: None,
+    };
+    if let Some(symbols_path) = symbols_path {
+        if let Err(e) = debugger.load_symbols(&symbols_path) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    }
+    if let Some(cheats_path) = cheats_path {
+        if let Err(e) = debugger.load_cheats(&cheats_path) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    }
+
+    let mut editor = DefaultEditor::new().expect("can't set up the terminal");
+    let history = env::var_os("HOME").map(|home| PathBuf::from(home).join(HISTORY_FILE));
+    if let Some(history) = history.as_ref() {
+        // no history yet is fine
+        let _ = editor.load_history(history);
+    }
+
+    println!("{} loaded, type help for commands", rom_path);
+    debugger.print_location();
+    let mut last = String::new();
+    loop {
+        let line = match editor.readline("(oxi8) ") {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(e) => {
+                eprintln!("can't read command: {}", e);
+                break;
+            }
+        };
+        let line = line.trim();
+        let command = if line.is_empty() {
+            last.clone()
+        } else {
+            let _ = editor.add_history_entry(line);
+            line.to_string()
+        };
+        if command.is_empty() {
+            continue;
+        }
+        if command == "quit" || command == "q" {
+            break;
+        }
+        if let Err(e) = debugger.command(&command) {
+            println!("{}", e);
+        }
+        last = command;
+    }
+    if let Some(history) = history.as_ref() {
+        if let Err(e) = editor.save_history(history) {
+            eprintln!("can't save history to {}: {}", history.display(), e);
+        }
+    }
+}
+
+fn usage() -> ! {
+    eprintln!(
+        "usage: oxi8_debug ROM [--seed N] [--clock HZ] [--symbols FILE] [--sanitize warn|stop] \
+         [--cheats FILE]"
+    );
+    std::process::exit(1);
+}
+
+fn sanitizer_mode(mode: &str) -> Option<SanitizerMode> {
+    match mode {
+        "off" => Some(SanitizerMode::Off),
+        "warn" => Some(SanitizerMode::Warn),
+        "stop" => Some(SanitizerMode::Stop),
+        _ => None,
+    }
+}
+
+fn write_png(path: &str, image: &RamImage) -> Result<(), String> {
+    let file = fs::File::create(path).map_err(|e| format!("can't create {}: {}", path, e))?;
+    image
+        .scale(IMAGE_SCALE)
+        .write_png(file)
+        .map_err(|e| format!("can't write {}: {}", path, e))
+}
+
+// tracepoint logs and sanitizer findings, except a finding that stopped it, which gets printed
+// as the reason
+fn print_logs(cpu: &mut Cpu<BoolDisplay, SeededRand>, stop: Option<&StopReason>) {
+    for log in cpu.debugger.take_logs() {
+        println!("{}", log);
+    }
+    for finding in cpu.sanitizer.take_findings() {
+        if stop != Some(&StopReason::Sanitizer(finding)) {
+            println!("sanitizer: {}", finding);
+        }
+    }
+}
+
+fn number_arg(name: &str, value: Option<String>) -> u64 {
+    value
+        .and_then(|value| value.parse().ok())
+        .unwrap_or_else(|| {
+            eprintln!("{} needs a number", name);
+            std::process::exit(1);
+        })
+}
+
+impl Debugger {
+    fn command(&mut self, command: &str) -> Result<(), String> {
+        let (name, rest) = match command.find(char::is_whitespace) {
+            Some(at) => (&command[..at], command[at..].trim()),
+            None => (command, ""),
+        };
+        // x/16xb is the only command with its arguments glued on
+        if let Some(format) = name.strip_prefix("x/") {
+            return self.examine(format, rest);
+        }
+        match name {
+            "help" | "h" => println!("{}", HELP),
+            "step" | "s" => self.repeat(rest, Cpu::step_into)?,
+            "next" | "n" => self.repeat(rest, Cpu::step_over)?,
+            "finish" => {
+                if self.cpu.stack_depth() == 0 {
+                    return Err("not in a subroutine, nothing to finish".to_string());
+                }
+                self.cpu.step_out();
+                self.resume_goal();
+            }
+            "continue" | "c" => {
+                let frames = self.count(rest, CONTINUE_MAX_FRAMES)?;
+                self.cpu.debugger.cancel_step();
+                if self.resume(frames).is_none() {
+                    println!("still running after {} frames", frames);
+                }
+            }
+            "frame" => {
+                let frames = self.count(rest, 1)?;
+                self.cpu.debugger.cancel_step();
+                if self.resume(frames).is_none() {
+                    self.print_location();
+                }
+            }
+            "until" => {
+                let addr = self.addr(rest)?;
+                self.cpu.run_to(addr);
+                self.resume_goal();
+            }
+            "break" | "b" if rest.is_empty() => self.list_breaks(),
+            "break" | "b" => self.add_break(rest)?,
+            "delete" | "d" => {
+                let addr = self.addr(rest)?;
+                if !self
+                    .cpu
+                    .debugger
+                    .breakpoints()
+                    .iter()
+                    .any(|b| b.addr == addr)
+                {
+                    return Err(format!("no breakpoint at 0x{:03X}", addr));
+                }
+                self.cpu.debugger.remove_breakpoint(addr);
+            }
+            "watch" => self.watch(rest, true)?,
+            "unwatch" => self.watch(rest, false)?,
+            "regs" | "r" => self.print_regs(),
+            "print" | "p" => {
+                let value = self.expr(rest)?.eval(&self.cpu);
+                println!("{} (0x{:X})", value, value);
+            }
+            "set" => {
+                let mut words = rest.splitn(2, char::is_whitespace);
+                let register = words
+                    .next()
+                    .and_then(Register::from_name)
+                    .ok_or("set needs a register, like set v3 0x10")?;
+                let value = self.expr(words.next().unwrap_or(""))?.eval(&self.cpu);
+                self.cpu.set_register(register, value as u16);
+                self.cpu.checkpoint();
+            }
+            "step-back" | "reverse-step" | "rs" => {
+                for _ in 0..self.count(rest, 1)? {
+                    self.cpu.step_back()?;
+                }
+                self.print_location();
+            }
+            "reverse-continue" | "rc" => {
+                match self.cpu.reverse_continue() {
+                    Some(stop) => println!("stopped: {}", stop),
+                    None => println!("reached the start of history"),
+                }
+                self.print_location();
+            }
+            "who-wrote" => {
+                let addr = self.addr(rest)?;
+                match self.cpu.last_write(addr) {
+                    Some(write) => println!(
+                        "{} was last written at cycle {} by {}: {:04X}  {}",
+                        self.cpu.symbols.format(addr),
+                        write.cycle,
+                        self.cpu.symbols.format(write.pc),
+                        write.opcode,
+                        disassemble_with_symbols(write.opcode, &self.cpu.symbols)
+                    ),
+                    None => println!(
+                        "{} wasn't written since cycle {}",
+                        self.cpu.symbols.format(addr),
+                        self.cpu.history_start().unwrap_or(0)
+                    ),
+                }
+            }
+            "disas" => {
+                let mut words = rest.split_whitespace();
+                let addr = match words.next() {
+                    Some(addr) => self.addr(addr)?,
+                    None => self.cpu.register(Register::Pc),
+                };
+                let count = self.count(words.next().unwrap_or(""), DISAS_COUNT as u64)?;
+                self.print_disassembly(addr, count as usize);
+            }
+            "stack" | "bt" => self.print_stack(),
+            "trace" => self.trace(rest)?,
+            "profile" => self.profile(rest)?,
+            "coverage" => self.coverage(rest)?,
+            "timeline" => self.timeline(rest)?,
+            "cheat" => self.cheat(rest)?,
+            "cheats" => self.cheats(rest)?,
+            "ramview" => {
+                let mut words = rest.split_whitespace();
+                let path = words.next().ok_or("ramview needs a file")?;
+                let width = self.count(words.next().unwrap_or(""), 1)?;
+                write_png(path, &self.cpu.ram_image(width as usize))?;
+            }
+            "sprite" => {
+                let preview = self
+                    .cpu
+                    .sprite_preview()
+                    .ok_or("no Dxyn coming up to preview")?;
+                println!(
+                    "{}: {:04X}  {}, from {}",
+                    self.cpu.symbols.format(preview.addr),
+                    preview.opcode,
+                    disassemble_with_symbols(preview.opcode, &self.cpu.symbols),
+                    self.cpu.symbols.format(preview.i)
+                );
+                if rest.is_empty() {
+                    let image = &preview.image;
+                    for row in image.rgba.chunks(image.width * 4) {
+                        let line: String = row
+                            .chunks(4)
+                            .map(|pixel| match pixel[0] {
+                                0x00 => '.',
+                                0xFF => '#',
+                                _ => ' ',
+                            })
+                            .collect();
+                        println!("  {}", line);
+                    }
+                } else {
+                    write_png(rest, &preview.image)?;
+                }
+            }
+            "sanitize" => {
+                let mode = sanitizer_mode(rest).ok_or("sanitize needs warn, stop or off")?;
+                self.cpu.sanitize(mode);
+            }
+            "screen" => {
+                let style = match rest {
+                    "" | "half" => TextStyle::HalfBlock,
+                    "ascii" => TextStyle::Ascii,
+                    "braille" => TextStyle::Braille,
+                    "ansi" => TextStyle::Ansi,
+                    _ => return Err(format!("unknown screen style: {}", rest)),
+                };
+                print!("{}", render_text(&self.cpu.display, style));
+            }
+            "key" => {
+                let mut words = rest.split_whitespace();
+                let key = words
+                    .next()
+                    .and_then(|key| u8::from_str_radix(key, 16).ok())
+                    .and_then(Key::from_u8)
+                    .ok_or("key needs a key 0-F")?;
+                let pressed = match words.next() {
+                    Some("down") => true,
+                    Some("up") => false,
+                    _ => return Err("key needs down or up".to_string()),
+                };
+                self.cpu.keyboard.toggle_key(key, pressed);
+            }
+            "save" => {
+                if rest.is_empty() {
+                    return Err("save needs a file".to_string());
+                }
+                fs::write(rest, self.cpu.save_state().to_string())
+                    .map_err(|e| format!("can't write {}: {}", rest, e))?;
+            }
+            "load" => {
+                let state =
+                    fs::read_to_string(rest).map_err(|e| format!("can't read {}: {}", rest, e))?;
+                self.cpu.load_state(&State::parse(&state)?)?;
+                self.print_location();
+            }
+            "symbols" => self.load_symbols(rest)?,
+            _ => return Err(format!("unknown command: {}, try help", name)),
+        }
+        Ok(())
+    }
+
+    fn load_symbols(&mut self, path: &str) -> Result<(), String> {
+        if path.is_empty() {
+            return Err("symbols needs a file".to_string());
+        }
+        let symbols =
+            fs::read_to_string(path).map_err(|e| format!("can't read {}: {}", path, e))?;
+        self.cpu.symbols = SymbolMap::parse(&symbols)?;
+        println!("{} labels loaded from {}", self.cpu.symbols.len(), path);
+        Ok(())
+    }
+
+    fn load_cheats(&mut self, path: &str) -> Result<(), String> {
+        if path.is_empty() {
+            return Err("cheats load needs a file".to_string());
+        }
+        let cheats = fs::read_to_string(path).map_err(|e| format!("can't read {}: {}", path, e))?;
+        let cheats = CheatFile::parse(&cheats)
+            .map_err(|e| format!("{}: {}", path, e))?
+            .cheats_for(self.cpu.rom_hash());
+        println!("{} cheats for this rom loaded from {}", cheats.len(), path);
+        self.cpu.load_cheats(cheats);
+        self.cpu.checkpoint();
+        Ok(())
+    }
+
+    // labels are resolved as the expression is parsed, so breakpoint conditions keep the
+    // addresses they were set with even if other symbols are loaded later
+    fn expr(&self, source: &str) -> Result<Expr, String> {
+        Expr::parse_with_symbols(source, &self.cpu.symbols)
+    }
+
+    // counts and addresses are expressions, anything below 0 is an error
+    fn value(&self, expr: &str) -> Result<u64, String> {
+        let value = self.expr(expr)?.eval(&self.cpu);
+        if value < 0 {
+            return Err(format!("{} is negative", expr));
+        }
+        Ok(value as u64)
+    }
+
+    fn addr(&self, expr: &str) -> Result<u16, String> {
+        if expr.is_empty() {
+            return Err("missing address".to_string());
+        }
+        let addr = self.value(expr)?;
+        if addr >= self.cpu.ram().len() as u64 {
+            return Err(format!("0x{:X} is past the end of ram", addr));
+        }
+        Ok(addr as u16)
+    }
+
+    fn count(&self, expr: &str, default: u64) -> Result<u64, String> {
+        if expr.is_empty() {
+            Ok(default)
+        } else {
+            self.value(expr)
+        }
+    }
+
+    fn repeat(
+        &mut self,
+        count: &str,
+        start: fn(&mut Cpu<BoolDisplay, SeededRand>),
+    ) -> Result<(), String> {
+        for _ in 0..self.count(count, 1)? {
+            start(&mut self.cpu);
+            match self.resume_goal() {
+                Some(StopReason::Step) | Some(StopReason::StepOver) => (),
+                _ => return Ok(()),
+            }
+        }
+        self.print_location();
+        Ok(())
+    }
+
+    // for stepping, finish and until, which could otherwise be left waiting on something that
+    // never happens
+    fn resume_goal(&mut self) -> Option<StopReason> {
+        let stop = self.resume(CONTINUE_MAX_FRAMES);
+        if stop.is_none() {
+            self.cpu.debugger.cancel_step();
+            println!("gave up after {} frames", CONTINUE_MAX_FRAMES);
+        }
+        stop
+    }
+
+    // runs until the debugger stops or frames have gone by, then says where and why it stopped
+    // a rom crashing the Cpu stops it too, instead of taking the whole debugger down with it
+    fn resume(&mut self, frames: u64) -> Option<StopReason> {
+        let cpu = &mut self.cpu;
+        // logs print as each frame finishes, so a crash partway through doesn't lose them
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            let mut stop = None;
+            for _ in 0..frames {
+                stop = cpu.cycle_60hz();
+                print_logs(cpu, stop.as_ref());
+                if stop.is_some() {
+                    break;
+                }
+            }
+            stop
+        }));
+        let stop = match result {
+            Ok(stop) => stop,
+            Err(e) => {
+                print_logs(&mut self.cpu, None);
+                let message = format!("cpu crashed: {}", panic_message(&*e));
+                self.cpu.timeline_error(&message);
+                println!("cpu crashed, state is as it was when it did");
+                self.print_trace(TRACE_SHOW);
+                None
+            }
+        };
+        match stop.as_ref() {
+            // stepping stops on every instruction, so leave printing where to repeat()
+            None | Some(StopReason::Step) | Some(StopReason::StepOver) => (),
+            Some(StopReason::StepOut) | Some(StopReason::RunTo(_)) => self.print_location(),
+            Some(stop) => {
+                println!("stopped: {}", stop);
+                self.print_location();
+            }
+        }
+        stop
+    }
+
+    fn add_break(&mut self, args: &str) -> Result<(), String> {
+        let (addr, condition) = match args.find(" if ") {
+            Some(at) => (&args[..at], Some(self.expr(&args[at + 4..])?)),
+            None => (args, None),
+        };
+        let mut breakpoint = Breakpoint::new(self.addr(addr.trim())?);
+        breakpoint.condition = condition;
+        println!(
+            "breakpoint at {}: {}",
+            self.cpu.symbols.format(breakpoint.addr),
+            disassemble_with_symbols(self.cpu.opcode_at(breakpoint.addr), &self.cpu.symbols)
+        );
+        self.cpu.debugger.set_breakpoint(breakpoint);
+        Ok(())
+    }
+
+    fn list_breaks(&self) {
+        let debugger = &self.cpu.debugger;
+        for breakpoint in debugger.breakpoints() {
+            print!(
+                "break {}, hit {} times",
+                self.cpu.symbols.format(breakpoint.addr),
+                breakpoint.hits()
+            );
+            match breakpoint.condition.as_ref() {
+                Some(condition) => println!(", if {}", condition),
+                None => println!(),
+            }
+        }
+        for (range, access) in debugger.watchpoints() {
+            println!(
+                "watch {:?} 0x{:03X}-0x{:03X}",
+                access,
+                range.start,
+                range.end - 1
+            );
+        }
+        for register in debugger.watched_registers() {
+            println!("watch {}", register);
+        }
+        for class in debugger.opcode_breaks() {
+            println!("watch opcode {}", class);
+        }
+    }
+
+    fn watch(&mut self, args: &str, add: bool) -> Result<(), String> {
+        let mut words = args.split_whitespace();
+        let target = words.next().ok_or("watch needs something to watch")?;
+        let debugger = &mut self.cpu.debugger;
+        if target == "opcode" {
+            let class = OpcodeClass::parse(words.next().unwrap_or(""))?;
+            if add {
+                debugger.break_on_opcode(class);
+            } else {
+                debugger.remove_opcode_break(&class);
+            }
+            return Ok(());
+        }
+        if let Some(register) = Register::from_name(&target.to_lowercase()) {
+            if add {
+                debugger.watch_register(register);
+            } else {
+                debugger.unwatch_register(register);
+            }
+            return Ok(());
+        }
+        let access = match words.next() {
+            None | Some("w") => Access::Write,
+            Some("r") => Access::Read,
+            Some("rw") => Access::ReadWrite,
+            Some(access) => return Err(format!("unknown access: {}, use r, w or rw", access)),
+        };
+        let range = self.range(target)?;
+        if add {
+            self.cpu.debugger.add_watchpoint(range, access);
+        } else {
+            self.cpu.debugger.remove_watchpoint(range, access);
+        }
+        Ok(())
+    }
+
+    fn trace(&mut self, args: &str) -> Result<(), String> {
+        let (name, rest) = match args.find(char::is_whitespace) {
+            Some(at) => (&args[..at], args[at..].trim()),
+            None => (args, ""),
+        };
+        match name {
+            "keep" => {
+                let count = self.count(rest, TRACE_RECORDS as u64)?;
+                self.cpu.tracer.keep(count as usize);
+            }
+            "file" if rest.is_empty() => self.cpu.tracer.stop_streaming(),
+            "file" => {
+                let file =
+                    fs::File::create(rest).map_err(|e| format!("can't create {}: {}", rest, e))?;
+                self.cpu.tracer.stream_to(file);
+            }
+            "only" => match rest.strip_prefix("opcode") {
+                Some(class) => self.cpu.tracer.add_class(OpcodeClass::parse(class.trim())?),
+                None => {
+                    let range = self.range(rest)?;
+                    self.cpu.tracer.add_range(range);
+                }
+            },
+            "all" => self.cpu.tracer.clear_filters(),
+            "save" => {
+                let mut file =
+                    fs::File::create(rest).map_err(|e| format!("can't create {}: {}", rest, e))?;
+                self.cpu
+                    .tracer
+                    .dump(&mut file)
+                    .map_err(|e| format!("can't write {}: {}", rest, e))?;
+            }
+            _ => {
+                let count = self.count(args, TRACE_SHOW as u64)?;
+                self.print_trace(count as usize);
+            }
+        }
+        if let Some(e) = self.cpu.tracer.error() {
+            println!("trace file stopped: {}", e);
+        }
+        Ok(())
+    }
+
+    fn profile(&mut self, args: &str) -> Result<(), String> {
+        let (name, rest) = match args.find(char::is_whitespace) {
+            Some(at) => (&args[..at], args[at..].trim()),
+            None => (args, ""),
+        };
+        let profiler = &mut self.cpu.profiler;
+        match name {
+            "start" => profiler.start(),
+            "stop" => profiler.stop(),
+            "clear" => profiler.clear(),
+            "flame" if rest.is_empty() => return Err("profile flame needs a file".to_string()),
+            "flame" => fs::write(rest, self.cpu.profile_collapsed())
+                .map_err(|e| format!("can't write {}: {}", rest, e))?,
+            _ => {
+                let top = self.count(args, PROFILE_TOP as u64)?;
+                print!("{}", self.cpu.profile_report(top as usize));
+            }
+        }
+        Ok(())
+    }
+
+    fn timeline(&mut self, args: &str) -> Result<(), String> {
+        let (name, rest) = match args.find(char::is_whitespace) {
+            Some(at) => (&args[..at], args[at..].trim()),
+            None => (args, ""),
+        };
+        let timeline = &mut self.cpu.timeline;
+        match name {
+            "start" => timeline.start(),
+            "stop" => timeline.stop(),
+            "clear" => timeline.clear(),
+            "json" | "chrome" if rest.is_empty() => {
+                return Err(format!("timeline {} needs a file", name))
+            }
+            "json" => fs::write(rest, self.cpu.timeline_json())
+                .map_err(|e| format!("can't write {}: {}", rest, e))?,
+            "chrome" => fs::write(rest, self.cpu.timeline_chrome_trace())
+                .map_err(|e| format!("can't write {}: {}", rest, e))?,
+            _ => {
+                let count = self.count(args, TIMELINE_SHOW as u64)? as usize;
+                let entries = self.cpu.timeline.entries();
+                for entry in entries.iter().skip(entries.len().saturating_sub(count)) {
+                    println!("{}", entry);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn cheat(&mut self, args: &str) -> Result<(), String> {
+        let (name, rest) = match args.find(char::is_whitespace) {
+            Some(at) => (&args[..at], args[at..].trim()),
+            None => (args, ""),
+        };
+        match name {
+            "start" => {
+                let search = self.cpu.cheat_search();
+                println!("{} candidates", search.len());
+                self.cheat_search = Some(search);
+            }
+            "set" => {
+                let mut words = rest.split_whitespace();
+                let target = words.next().ok_or("cheat set needs a target")?;
+                let target = match Register::from_name(target) {
+                    Some(Register::V(x)) => CheatTarget::V(x),
+                    _ => CheatTarget::Ram(self.addr(target)?),
+                };
+                let value = self.value(words.next().ok_or("cheat set needs a value")?)?;
+                if value > 0xFF {
+                    return Err(format!("{} doesn't fit in a byte", value));
+                }
+                self.cpu.set_cheat_value(target, value as u8);
+                self.cpu.checkpoint();
+            }
+            "" | "list" => {
+                let search = self
+                    .cheat_search
+                    .as_ref()
+                    .ok_or("no cheat search, start one with cheat start")?;
+                let count = self.count(rest, CHEAT_SHOW as u64)? as usize;
+                println!(
+                    "{} candidates after {} filters",
+                    search.len(),
+                    search.filters()
+                );
+                for (target, value) in search.candidates().iter().take(count) {
+                    let name = match target {
+                        CheatTarget::Ram(addr) => self.cpu.symbols.format(*addr),
+                        CheatTarget::V(_) => target.to_string(),
+                    };
+                    println!("  {} = {} (0x{:02X})", name, value, value);
+                }
+            }
+            _ => {
+                let filter = CheatFilter::parse(args)?;
+                let search = self
+                    .cheat_search
+                    .as_mut()
+                    .ok_or("no cheat search, start one with cheat start")?;
+                let left = self.cpu.cheat_filter(search, filter);
+                println!("{} candidates left", left);
+            }
+        }
+        Ok(())
+    }
+
+    fn cheats(&mut self, args: &str) -> Result<(), String> {
+        let (name, rest) = match args.find(char::is_whitespace) {
+            Some(at) => (&args[..at], args[at..].trim()),
+            None => (args, ""),
+        };
+        match name {
+            "" => {
+                if self.cpu.cheats().is_empty() {
+                    println!("no cheats, for rom {:016x}", self.cpu.rom_hash());
+                }
+                for (n, cheat) in self.cpu.cheats().iter().enumerate() {
+                    println!("  {}: {}", n, cheat);
+                }
+            }
+            "load" => self.load_cheats(rest)?,
+            "on" | "off" => {
+                if rest.is_empty() {
+                    return Err(format!("cheats {} needs a cheat number", name));
+                }
+                let n = self.value(rest)? as usize;
+                if !self.cpu.enable_cheat(n, name == "on") {
+                    return Err(format!("no cheat {}", n));
+                }
+                self.cpu.checkpoint();
+            }
+            "add" => {
+                self.cpu.add_cheat(Cheat::parse(&format!("on {}", rest))?);
+                self.cpu.checkpoint();
+            }
+            "delete" => {
+                if rest.is_empty() {
+                    return Err(format!("cheats {} needs a cheat number", name));
+                }
+                let n = self.value(rest)? as usize;
+                self.cpu
+                    .remove_cheat(n)
+                    .ok_or_else(|| format!("no cheat {}", n))?;
+                self.cpu.checkpoint();
+            }
+            "save" => {
+                if rest.is_empty() {
+                    return Err("cheats save needs a file".to_string());
+                }
+                // other roms' cheats in the file stay as they are
+                let mut file = match fs::read_to_string(rest) {
+                    Ok(cheats) => {
+                        CheatFile::parse(&cheats).map_err(|e| format!("{}: {}", rest, e))?
+                    }
+                    Err(_) => CheatFile::default(),
+                };
+                file.set_cheats(self.cpu.rom_hash(), &self.rom_name, self.cpu.cheats());
+                fs::write(rest, file.to_string())
+                    .map_err(|e| format!("can't write {}: {}", rest, e))?;
+            }
+            _ => return Err(format!("unknown cheats command: {}", name)),
+        }
+        Ok(())
+    }
+
+    fn coverage(&mut self, args: &str) -> Result<(), String> {
+        let (name, rest) = match args.find(char::is_whitespace) {
+            Some(at) => (&args[..at], args[at..].trim()),
+            None => (args, ""),
+        };
+        let coverage = &mut self.cpu.coverage;
+        match name {
+            "start" => coverage.start(),
+            "stop" => coverage.stop(),
+            "clear" => coverage.clear(),
+            "save" | "load" if rest.is_empty() => {
+                return Err(format!("coverage {} needs a file", name))
+            }
+            "save" => fs::write(rest, coverage.to_string())
+                .map_err(|e| format!("can't write {}: {}", rest, e))?,
+            "load" => {
+                let map =
+                    fs::read_to_string(rest).map_err(|e| format!("can't read {}: {}", rest, e))?;
+                coverage.merge(&Coverage::parse(&map)?);
+            }
+            "" => print!("{}", self.cpu.coverage_report(self.cpu.rom_range())),
+            _ => return Err(format!("unknown coverage command: {}", name)),
+        }
+        Ok(())
+    }
+
+    fn print_trace(&self, count: usize) {
+        let tracer = &self.cpu.tracer;
+        let kept = tracer.records().count();
+        for record in tracer.records().skip(kept.saturating_sub(count)) {
+            println!("{}", record);
+        }
+    }
+
+    // ADDR or ADDR-END, END inclusive like everything typed in
+    fn range(&self, range: &str) -> Result<Range<u16>, String> {
+        let mut ends = range.splitn(2, '-');
+        let start = self.addr(ends.next().unwrap_or(""))?;
+        let end = match ends.next() {
+            Some(end) => self.addr(end)?,
+            None => start,
+        };
+        if end < start {
+            return Err(format!("range ends before it starts: {}", range));
+        }
+        Ok(start..end + 1)
+    }
+
+    fn examine(&self, format: &str, addr: &str) -> Result<(), String> {
+        let digits = format
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(format.len());
+        let count: usize = if digits == 0 {
+            1
+        } else {
+            format[..digits].parse().map_err(|_| "bad count")?
+        };
+        let mut hex = true;
+        let mut size = 1;
+        for letter in format[digits..].chars() {
+            match letter {
+                'x' => hex = true,
+                'd' => hex = false,
+                'b' => size = 1,
+                'h' => size = 2,
+                _ => return Err(format!("unknown format letter: {}", letter)),
+            }
+        }
+        let start = self.addr(addr)? as usize;
+        let ram = self.cpu.ram();
+        let per_line = 16 / size;
+        for line in 0..count.div_ceil(per_line) {
+            let line_start = start + line * per_line * size;
+            print!("0x{:03X}:", line_start);
+            for n in 0..per_line.min(count - line * per_line) {
+                let at = line_start + n * size;
+                // big endian, same as opcodes
+                let value = ram[at..(at + size).min(ram.len())]
+                    .iter()
+                    .fold(0u16, |value, byte| (value << 8) | *byte as u16);
+                match (hex, size) {
+                    (true, 1) => print!(" {:02X}", value),
+                    (true, _) => print!(" {:04X}", value),
+                    (false, _) => print!(" {}", value),
+                }
+                if at + size >= ram.len() {
+                    break;
+                }
+            }
+            println!();
+            if line_start + per_line * size >= ram.len() {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn print_location(&self) {
+        self.print_disassembly(self.cpu.register(Register::Pc), 1);
+    }
+
+    fn print_disassembly(&self, addr: u16, count: usize) {
+        let pc = self.cpu.register(Register::Pc);
+        for (addr, opcode, mnemonic) in self.cpu.disassemble(addr, count) {
+            if let Some(name) = self.cpu.symbols.name(addr) {
+                println!("{}:", name);
+            }
+            let marker = if addr == pc { "=>" } else { "  " };
+            println!("{} 0x{:03X}: {:04X}  {}", marker, addr, opcode, mnemonic);
+        }
+    }
+
+    fn print_regs(&self) {
+        let cpu = &self.cpu;
+        for row in 0..2u8 {
+            for x in row * 8..row * 8 + 8 {
+                print!("V{:X}={:02X} ", x, cpu.register(Register::V(x)));
+            }
+            println!();
+        }
+        println!(
+            "I={:03X} PC={:03X} SP={} DT={} ST={} frame={} cycles={}",
+            cpu.register(Register::I),
+            cpu.register(Register::Pc),
+            cpu.register(Register::Sp),
+            cpu.register(Register::Delay),
+            cpu.register(Register::Sound),
+            cpu.frame(),
+            cpu.cycles()
+        );
+    }
+
+    // innermost first like gdb, each frame is where it is now and the subroutine it's in
+    fn print_stack(&self) {
+        let cpu = &self.cpu;
+        let frames = cpu.call_stack();
+        for (depth, frame) in frames.iter().enumerate() {
+            println!("#{} {}", depth, frame.describe(&cpu.symbols));
+        }
+        let main_pc = frames
+            .last()
+            .map_or(cpu.register(Register::Pc), |frame| frame.call_site);
+        println!("#{} {} in main", frames.len(), cpu.symbols.format(main_pc));
+        println!(
+            "deepest the stack has been: {} of {}",
+            cpu.stack_high_water(),
+            cpu.stack_capacity()
+        );
+    }
+}
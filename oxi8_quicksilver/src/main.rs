@@ -11,52 +11,73 @@ use quicksilver::{
 
 use std::collections::HashMap;
 
-use oxi8_cpu::{BoolDisplay, Cpu, Key, Rand, DISPLAY_HEIGHT, DISPLAY_WIDTH};
-
-use rand::prelude::{Rng, ThreadRng};
+use oxi8_cpu::{
+    disassemble_with_symbols, render_text, write_wav, Audio, BoolDisplay, Buzzer, Cheat,
+    CheatFilter, CheatSearch, Coverage, Cpu, Key, Movie, MovieEvent, SanitizerMode, SeededRand,
+    SymbolMap, TextStyle, DISPLAY_HEIGHT, DISPLAY_WIDTH,
+};
 
-use std::sync::atomic::{AtomicBool, Ordering};
+use rand::prelude::Rng;
 
 #[cfg(target_arch = "wasm32")]
 use base64::decode;
 #[cfg(target_arch = "wasm32")]
+use std::convert::TryInto;
+#[cfg(target_arch = "wasm32")]
 use stdweb::{
-    web::window,
+    web::{window, TypedArray},
     {_js_impl, js},
 };
 
 #[cfg(not(target_arch = "wasm32"))]
 use die::{die, Die};
 #[cfg(not(target_arch = "wasm32"))]
+use oxi8_cpu::{panic_message, CheatFile, GdbServer, RecordFormat, Recorder};
+#[cfg(not(target_arch = "wasm32"))]
+use std::panic::{self, AssertUnwindSafe};
+#[cfg(not(target_arch = "wasm32"))]
 use std::thread;
 #[cfg(not(target_arch = "wasm32"))]
+use std::time::{SystemTime, UNIX_EPOCH};
+#[cfg(not(target_arch = "wasm32"))]
 use std::{env, fs};
 
 const SCALE_FACTOR: u32 = 8;
 
-struct ThreadRand {
-    rng: ThreadRng,
-}
+// recordings are always hires sized, so this is SCALE_FACTOR / 2 to match the window
+#[cfg(not(target_arch = "wasm32"))]
+const RECORD_SCALE_FACTOR: u32 = SCALE_FACTOR / 2;
 
-impl ThreadRand {
-    pub fn new() -> ThreadRand {
-        ThreadRand {
-            rng: rand::thread_rng(),
-        }
-    }
-}
+// only used when rendering movies headless, live capture uses whatever the audio device runs at
+#[cfg(not(target_arch = "wasm32"))]
+const WAV_SAMPLE_RATE: u32 = 44_100;
 
-impl Rand for ThreadRand {
-    fn next(&mut self) -> u8 {
-        // 71 is good for debugging PONG
-        self.rng.gen::<u8>()
-    }
-}
+// hottest addresses listed in --profile reports
+#[cfg(not(target_arch = "wasm32"))]
+const PROFILE_TOP: usize = 50;
+
+// cheat search candidates are only listed once there are this few
+const CHEAT_SHOW: usize = 10;
+
+// with --audio-sync update() is called this many times per 60hz frame, and the audio device
+// decides how many frames actually run, so a jittery render loop can't make the sound stutter
+const AUDIO_SYNC_UPDATES_PER_FRAME: u32 = 4;
 
 struct DrawGeometry {
-    cpu: Cpu<BoolDisplay, ThreadRand>,
+    cpu: Cpu<BoolDisplay, SeededRand>,
+    rom: Vec<u8>,
     keymap: HashMap<QKey, Key>,
     cycle: fn(&mut DrawGeometry),
+    frame: u64, // number of 60hz frames ran, movies are timed against this
+    #[cfg(not(target_arch = "wasm32"))]
+    recorder: Option<(RecordFormat, Recorder)>,
+    movie: Option<Movie>,
+    audio_sync: bool,
+    updates: u32, // only counted with audio_sync and no audio device, to fall back to 60hz
+    #[cfg(not(target_arch = "wasm32"))]
+    gdb: Option<GdbServer>,
+    cheat_search: Option<CheatSearch>,
+    paused_cheats: Vec<usize>, // turned off with F10, to turn back on
 }
 
 impl DrawGeometry {
@@ -91,20 +112,183 @@ impl DrawGeometry {
         //#[cfg(not(target_arch = "wasm32"))]
         //Beep::start();
 
+        let audio = start_audio(get_buzzer());
+        audio.set_pacing(audio_sync());
+
         Ok(DrawGeometry {
-            cpu: Cpu::new(rom, BoolDisplay::new(SCALE_FACTOR), ThreadRand::new()),
+            cpu: DrawGeometry::new_cpu(rom, rand::thread_rng().gen(), audio),
+            rom: rom.to_vec(),
             keymap,
             cycle: DrawGeometry::cycle,
+            frame: 0,
+            #[cfg(not(target_arch = "wasm32"))]
+            recorder: None,
+            movie: None,
+            audio_sync: audio_sync(),
+            updates: 0,
+            #[cfg(not(target_arch = "wasm32"))]
+            gdb: start_gdb(),
+            cheat_search: None,
+            paused_cheats: Vec::new(),
         })
     }
 
+    fn new_cpu(rom: &[u8], seed: u64, audio: Audio) -> Cpu<BoolDisplay, SeededRand> {
+        let mut cpu = Cpu::new(rom, BoolDisplay::new(SCALE_FACTOR), SeededRand::new(seed));
+        cpu.audio = audio;
+        cpu.symbols = get_symbols();
+        start_trace(&mut cpu);
+        start_sanitizer(&mut cpu);
+        let cheats = get_cheats(cpu.rom_hash());
+        cpu.load_cheats(cheats);
+        cpu
+    }
+
     fn noop(&mut self) {
         // do nothing
     }
 
     fn cycle(&mut self) {
-        self.cpu.cycle_60hz();
-        SOUND_ON.store(self.cpu.sound > 0, Ordering::Relaxed);
+        #[cfg_attr(target_arch = "wasm32", allow(unused_variables))]
+        let stop = self.cpu.cycle_60hz();
+        self.frame += 1;
+        for finding in self.cpu.sanitizer.take_findings() {
+            println!("sanitizer: {}", finding);
+        }
+        #[cfg(not(target_arch = "wasm32"))]
+        if let Some((_, recorder)) = self.recorder.as_mut() {
+            recorder.capture(&self.cpu.display);
+        }
+        #[cfg(not(target_arch = "wasm32"))]
+        if let (Some(gdb), Some(stop)) = (self.gdb.as_mut(), stop) {
+            gdb.stopped(&stop);
+        }
+    }
+
+    // F1 starts a search for where the rom keeps something like lives, F2/F3/F4/F9 keep what
+    // went down, went up, stayed the same or changed since the last press, see CheatSearch
+    fn start_cheat_search(&mut self) {
+        let search = self.cpu.cheat_search();
+        println!("cheat search: {} candidates", search.len());
+        self.cheat_search = Some(search);
+    }
+
+    fn cheat_filter(&mut self, filter: CheatFilter) {
+        let search = match self.cheat_search.as_mut() {
+            Some(search) => search,
+            None => {
+                println!("cheat search: not started, press F1");
+                return;
+            }
+        };
+        let left = self.cpu.cheat_filter(search, filter);
+        print!("cheat search: {:?}, {} candidates left", filter, left);
+        if left <= CHEAT_SHOW {
+            for (target, value) in search.candidates() {
+                print!(" {}={:02X}", target, value);
+            }
+        }
+        println!();
+    }
+
+    // F10 turns the cheats that are on off, and then back on again, or all of them if none were
+    fn toggle_cheats(&mut self) {
+        if self.movie.is_some() {
+            println!("cheat: movies don't record cheats, stop recording first");
+            return;
+        }
+        let on: Vec<usize> = (0..self.cpu.cheats().len())
+            .filter(|n| self.cpu.cheats()[*n].is_enabled())
+            .collect();
+        if on.is_empty() {
+            let paused = std::mem::take(&mut self.paused_cheats);
+            let paused = if paused.is_empty() {
+                (0..self.cpu.cheats().len()).collect()
+            } else {
+                paused
+            };
+            for n in paused {
+                self.cpu.enable_cheat(n, true);
+            }
+        } else {
+            for n in &on {
+                self.cpu.enable_cheat(*n, false);
+            }
+            self.paused_cheats = on;
+        }
+        for cheat in self.cpu.cheats() {
+            println!("cheat: {}", cheat);
+        }
+    }
+
+    fn movie_event(&mut self, event: MovieEvent) {
+        if let Some(movie) = self.movie.as_mut() {
+            movie.push(self.frame, event);
+        }
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    fn toggle_recording(&mut self, format: RecordFormat) {
+        match self.recorder.take() {
+            Some((recording_format, recorder)) => {
+                let mut animation = Vec::new();
+                match recorder.encode(recording_format, RECORD_SCALE_FACTOR, &mut animation) {
+                    Ok(_) => save_file(recording_format.extension(), &animation),
+                    Err(e) => println!("recording failed: {}", e),
+                }
+            }
+            None => self.recorder = Some((format, Recorder::new())),
+        }
+    }
+
+    fn toggle_wav(&mut self) {
+        if !self.cpu.audio.is_capturing() {
+            self.cpu.audio.start_capture();
+            return;
+        }
+        let samples = self.cpu.audio.stop_capture();
+        let mut wav = Vec::new();
+        match write_wav(&samples, self.cpu.audio.sample_rate(), &mut wav) {
+            Ok(_) => save_file("wav", &wav),
+            Err(e) => println!("wav capture failed: {}", e),
+        }
+    }
+
+    // movies always start from power on, so this resets the rom with a fresh known seed, they
+    // don't record cheats so replays would go their own way, recording needs them off
+    fn toggle_movie(&mut self) {
+        match self.movie.take() {
+            Some(mut movie) => {
+                movie.frames = self.frame;
+                save_file("movie", movie.to_string().as_bytes());
+            }
+            None => {
+                if self.cpu.cheats().iter().any(Cheat::is_enabled) {
+                    println!("movie: movies don't record cheats, turn them off with F10 first");
+                    return;
+                }
+                let seed = rand::thread_rng().gen();
+                let clock_rate_hz = self.cpu.get_clock_rate_hz();
+                self.cpu = DrawGeometry::new_cpu(&self.rom, seed, self.cpu.audio.clone());
+                // the fresh cpu has the cheat file's cheats on again
+                for n in 0..self.cpu.cheats().len() {
+                    self.cpu.enable_cheat(n, false);
+                }
+                self.cpu.set_clock_rate_hz(clock_rate_hz);
+                self.frame = 0;
+                self.movie = Some(Movie::new(seed, clock_rate_hz));
+            }
+        }
+    }
+
+    fn set_clock_rate_hz(&mut self, rate_hz: u32) {
+        self.cpu.set_clock_rate_hz(rate_hz);
+        self.movie_event(MovieEvent::ClockRate(self.cpu.get_clock_rate_hz()));
+    }
+
+    fn inc_clock_rate_hz(&mut self, amount: i32) {
+        self.cpu.inc_clock_rate_hz(amount);
+        self.movie_event(MovieEvent::ClockRate(self.cpu.get_clock_rate_hz()));
     }
 
     fn toggle_debug(&mut self) {
@@ -122,9 +306,29 @@ impl State for DrawGeometry {
     }
 
     fn update(&mut self, _window: &mut Window) -> Result<()> {
-        // quicksilver is *supposed* to call this at exactly 60hz
-        // if it doesn't, we should call .cycle() instead
-        (self.cycle)(self);
+        let frames = if !self.audio_sync {
+            // quicksilver is *supposed* to call this at exactly 60hz
+            // if it doesn't, we should call .cycle() instead
+            1
+        } else if self.cpu.audio.sample_rate() == 0 {
+            // no audio device to sync to, so just run 1 frame every few updates
+            self.updates = (self.updates + 1) % AUDIO_SYNC_UPDATES_PER_FRAME;
+            (self.updates == 0) as u32
+        } else {
+            self.cpu.audio.frames_needed()
+        };
+        // a connected gdb decides whether the game runs at all
+        #[cfg(not(target_arch = "wasm32"))]
+        if let Some(gdb) = self.gdb.as_mut() {
+            gdb.poll(&mut self.cpu);
+        }
+        for _ in 0..frames {
+            #[cfg(not(target_arch = "wasm32"))]
+            if !self.gdb.as_ref().map_or(true, GdbServer::is_running) {
+                break;
+            }
+            (self.cycle)(self);
+        }
         Ok(())
     }
 
@@ -132,31 +336,63 @@ impl State for DrawGeometry {
         if let Event::Key(key, button_state) = event {
             let pressed = *button_state == ButtonState::Pressed;
             if pressed || *button_state == ButtonState::Released {
-                match self.keymap.get(key) {
-                    Some(key) => self.cpu.keyboard.toggle_key(*key, pressed),
+                match self.keymap.get(key).cloned() {
+                    Some(key) => {
+                        self.cpu.keyboard.toggle_key(key, pressed);
+                        self.movie_event(MovieEvent::Key(key, pressed));
+                    }
                     None => {
                         if pressed {
                             match *key {
-                                QKey::Return => self.cpu.reset(),
+                                QKey::Return => {
+                                    self.cpu.reset();
+                                    self.movie_event(MovieEvent::Reset);
+                                }
                                 QKey::Back => quit(),
                                 QKey::Space => self.toggle_debug(),
                                 QKey::I => self.toggle_debug(),
                                 QKey::O => {
                                     if self.cycle as usize == DrawGeometry::noop as usize {
+                                        // oxi8_debug does this and much more headless
                                         let instruction = self.cpu.next_instruction();
-                                        //println!("ins: {}", instruction);
-                                        print!("ins: {}, before: {:?}", instruction, self.cpu);
+                                        print!(
+                                            "ins: {} {}, before: {:?}",
+                                            instruction,
+                                            disassemble_with_symbols(
+                                                instruction.opcode(),
+                                                &self.cpu.symbols
+                                            ),
+                                            self.cpu
+                                        );
                                         self.cpu.decrement_timers();
                                         self.cpu.execute_next_instruction();
                                         println!(", after : {:?}", self.cpu);
                                     }
                                 }
-                                QKey::Equals => self.cpu.inc_clock_rate_hz(10),
+                                QKey::P => {
+                                    print!(
+                                        "{}",
+                                        render_text(&self.cpu.display, TextStyle::HalfBlock)
+                                    )
+                                }
+                                QKey::Equals => self.inc_clock_rate_hz(10),
                                 // todo: as a native app the _/- button is 'Subtract' but in WASM it's 'Minus'...
-                                QKey::Subtract => self.cpu.inc_clock_rate_hz(-10),
-                                QKey::Minus => self.cpu.inc_clock_rate_hz(-10),
-                                QKey::Key0 => self.cpu.set_clock_rate_hz(500), // default chip-8 speed
-                                QKey::Key9 => self.cpu.set_clock_rate_hz(1000), // default schip speed
+                                QKey::Subtract => self.inc_clock_rate_hz(-10),
+                                QKey::Minus => self.inc_clock_rate_hz(-10),
+                                QKey::Key0 => self.set_clock_rate_hz(500), // default chip-8 speed
+                                QKey::Key9 => self.set_clock_rate_hz(1000), // default schip speed
+                                #[cfg(not(target_arch = "wasm32"))]
+                                QKey::F5 => self.toggle_recording(RecordFormat::Gif),
+                                #[cfg(not(target_arch = "wasm32"))]
+                                QKey::F6 => self.toggle_recording(RecordFormat::Apng),
+                                QKey::F7 => self.toggle_movie(),
+                                QKey::F8 => self.toggle_wav(),
+                                QKey::F1 => self.start_cheat_search(),
+                                QKey::F2 => self.cheat_filter(CheatFilter::Decreased),
+                                QKey::F3 => self.cheat_filter(CheatFilter::Increased),
+                                QKey::F4 => self.cheat_filter(CheatFilter::Unchanged),
+                                QKey::F9 => self.cheat_filter(CheatFilter::Changed),
+                                QKey::F10 => self.toggle_cheats(),
                                 _ => (), // ignore everything else
                             }
                             //println!("key: {:?}", *key);
@@ -174,7 +410,6 @@ impl State for DrawGeometry {
         //window.set_size((DISPLAY_WIDTH * SCALE_FACTOR, DISPLAY_HEIGHT * SCALE_FACTOR));
         window.clear(Color::BLACK)?;
 
-        //let mut display = String::new();
         //println!("starting draw");
         let scale_factor = self.cpu.display.get_scale();
         let size = (scale_factor, scale_factor);
@@ -182,21 +417,15 @@ impl State for DrawGeometry {
             for (x, val) in row.iter().enumerate() {
                 if *val {
                     //println!("drawing point ({}, {})", j, i);
-                    //display.push_str("*");
                     let x = x as u32;
                     let y = y as u32;
                     window.draw(
                         &Rectangle::new((x * scale_factor, y * scale_factor), size),
                         Col(Color::WHITE),
                     );
-                //rect.s
-                } else {
-                    //display.push_str(" ");
                 }
             }
-            //display.push_str("\n");
         }
-        //fs::write("/tmp/display", display.as_bytes()).expect("Unable to write display file");
         //println!("finished draw");
         Ok(())
     }
@@ -246,12 +475,264 @@ fn get_rom() -> Vec<u8> {
 
 #[cfg(not(target_arch = "wasm32"))]
 fn get_rom() -> Vec<u8> {
-    match env::args().nth(1) {
+    match rom_arg() {
         Some(file_name) => fs::read(file_name).die("Unable to read first arg as rom"),
         None => PONG.to_vec(),
     }
 }
 
+// the rom is the first arg that isn't an option like --audio-sync
+#[cfg(not(target_arch = "wasm32"))]
+fn rom_arg() -> Option<String> {
+    env::args().skip(1).find(|arg| !arg.starts_with("--"))
+}
+
+#[cfg(target_arch = "wasm32")]
+fn audio_sync() -> bool {
+    false
+}
+
+// --audio-sync lets the audio device clock pace emulation instead of quicksilver's update timer
+#[cfg(not(target_arch = "wasm32"))]
+fn audio_sync() -> bool {
+    env::args().skip(1).any(|arg| arg == "--audio-sync")
+}
+
+// --gdb listens for gdb on localhost:1234, --gdb=ADDR on ADDR, then in gdb: target remote ADDR
+#[cfg(not(target_arch = "wasm32"))]
+fn start_gdb() -> Option<GdbServer> {
+    let addr = env::args().skip(1).find_map(|arg| match arg.as_str() {
+        "--gdb" => Some("127.0.0.1:1234".to_string()),
+        _ => arg.strip_prefix("--gdb=").map(str::to_string),
+    })?;
+    let gdb = GdbServer::bind(&addr).die("Unable to start gdb server");
+    println!("gdb server listening on {}", addr);
+    Some(gdb)
+}
+
+#[cfg(target_arch = "wasm32")]
+fn start_trace(_cpu: &mut Cpu<BoolDisplay, SeededRand>) {}
+
+// --trace=FILE writes every instruction to FILE as it runs, starting over whenever the Cpu is
+// replaced, like when recording a movie
+#[cfg(not(target_arch = "wasm32"))]
+fn start_trace(cpu: &mut Cpu<BoolDisplay, SeededRand>) {
+    if let Some(file_name) = env::args()
+        .skip(1)
+        .find_map(|arg| arg.strip_prefix("--trace=").map(str::to_string))
+    {
+        let file = fs::File::create(file_name).die("Unable to create trace file");
+        cpu.tracer.stream_to(file);
+    }
+}
+
+#[cfg(target_arch = "wasm32")]
+fn start_sanitizer(_cpu: &mut Cpu<BoolDisplay, SeededRand>) {}
+
+// --sanitize prints anything a working rom shouldn't do as it happens, see Sanitizer
+#[cfg(not(target_arch = "wasm32"))]
+fn start_sanitizer(cpu: &mut Cpu<BoolDisplay, SeededRand>) {
+    if env::args().skip(1).any(|arg| arg == "--sanitize") {
+        cpu.sanitize(SanitizerMode::Warn);
+    }
+}
+
+#[cfg(target_arch = "wasm32")]
+fn get_cheats(_rom_hash: u64) -> Vec<Cheat> {
+    Vec::new()
+}
+
+// --cheats=FILE uses the cheats in FILE for the rom being run, picked by the rom's hash so one
+// file can hold cheats for every rom, see CheatFile
+#[cfg(not(target_arch = "wasm32"))]
+fn get_cheats(rom_hash: u64) -> Vec<Cheat> {
+    match env::args()
+        .skip(1)
+        .find_map(|arg| arg.strip_prefix("--cheats=").map(str::to_string))
+    {
+        Some(file_name) => {
+            let cheats = fs::read_to_string(file_name).die("Unable to read cheats");
+            CheatFile::parse(&cheats)
+                .die("Unable to parse cheats")
+                .cheats_for(rom_hash)
+        }
+        None => Vec::new(),
+    }
+}
+
+#[cfg(target_arch = "wasm32")]
+fn get_buzzer() -> Buzzer {
+    Buzzer::default()
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn get_buzzer() -> Buzzer {
+    match rom_arg() {
+        Some(file_name) => get_buzzer_for(&file_name),
+        None => Buzzer::default(),
+    }
+}
+
+// per rom buzzer settings live next to the rom, so GAME gets GAME.buzzer, see Buzzer::parse
+#[cfg(not(target_arch = "wasm32"))]
+fn get_buzzer_for(rom_file_name: &str) -> Buzzer {
+    match fs::read_to_string(format!("{}.buzzer", rom_file_name)) {
+        Ok(settings) => Buzzer::parse(&settings).die("Unable to parse buzzer settings"),
+        Err(_) => Buzzer::default(),
+    }
+}
+
+#[cfg(target_arch = "wasm32")]
+fn get_symbols() -> SymbolMap {
+    SymbolMap::default()
+}
+
+// labels for the O key's instruction dumps live next to the rom too, GAME.sym, see SymbolMap
+#[cfg(not(target_arch = "wasm32"))]
+fn get_symbols() -> SymbolMap {
+    match rom_arg().map(|file_name| fs::read_to_string(format!("{}.sym", file_name))) {
+        Some(Ok(symbols)) => SymbolMap::parse(&symbols).die("Unable to parse symbols"),
+        _ => SymbolMap::default(),
+    }
+}
+
+#[cfg(target_arch = "wasm32")]
+fn save_file(extension: &str, _contents: &[u8]) {
+    window().alert(&format!(
+        "saving .{} files is only supported natively",
+        extension
+    ));
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn save_file(extension: &str, contents: &[u8]) {
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let file_name = format!("oxi8-{}.{}", secs, extension);
+    match fs::write(&file_name, contents) {
+        Ok(_) => println!("saved {}", file_name),
+        Err(e) => println!("unable to save {}: {}", file_name, e),
+    }
+}
+
+// oxi8_quicksilver --render ROM MOVIE OUT.gif|OUT.png|OUT.wav
+//   replays an input movie without a window and writes every frame to an animation,
+//   or all of its sound to a WAV file
+// oxi8_quicksilver --hashes ROM MOVIE OUT.txt
+//   same, but writes the hash of every frame instead
+// oxi8_quicksilver --check-hashes ROM MOVIE EXPECTED.txt
+//   same, but compares against previously written hashes, exiting non-zero on the first difference
+// oxi8_quicksilver --profile ROM MOVIE OUT.txt
+//   same, but profiles it, writing the report to OUT.txt and call stacks for flame graph tools
+//   to OUT.txt.folded
+// oxi8_quicksilver --coverage ROM MOVIE OUT.cov
+//   same, but writes which bytes of ram ran, were read and were written to OUT.cov, adding to
+//   what's already there so several movies can cover one rom, and prints a report
+// oxi8_quicksilver --timeline ROM MOVIE OUT.json|OUT.trace.json
+//   same, but writes a timeline of screen clears, sound, key waits and such as JSON, or for
+//   .trace.json in Chrome's trace event format, with the rom crashing on it as an error
+#[cfg(not(target_arch = "wasm32"))]
+fn headless() -> bool {
+    let args: Vec<String> = env::args().collect();
+    let mode = match args.get(1).map(String::as_str) {
+        Some(mode @ "--render")
+        | Some(mode @ "--hashes")
+        | Some(mode @ "--check-hashes")
+        | Some(mode @ "--profile")
+        | Some(mode @ "--coverage")
+        | Some(mode @ "--timeline") => mode,
+        _ => return false,
+    };
+    if args.len() != 5 {
+        eprintln!("usage: {} {} ROM MOVIE OUT", args[0], mode);
+        std::process::exit(1);
+    }
+    let rom = fs::read(&args[2]).die("Unable to read rom");
+    let movie = fs::read_to_string(&args[3]).die("Unable to read movie");
+    let movie = Movie::parse(&movie).die("Unable to parse movie");
+    let out = &args[4];
+    if mode == "--render" && out.to_lowercase().ends_with(".wav") {
+        let mut cpu = movie.new_cpu(&rom, BoolDisplay::new(SCALE_FACTOR));
+        cpu.audio = Audio::new(WAV_SAMPLE_RATE);
+        cpu.audio.set_buzzer(get_buzzer_for(&args[2]));
+        cpu.audio.start_capture();
+        movie.play_on(&mut cpu, |_| ());
+        let out = fs::File::create(out).die("Unable to create output file");
+        write_wav(&cpu.audio.stop_capture(), WAV_SAMPLE_RATE, out).die("Unable to write wav");
+        return true;
+    }
+    if mode == "--profile" {
+        let mut cpu = movie.new_cpu(&rom, BoolDisplay::new(SCALE_FACTOR));
+        cpu.symbols = get_symbols();
+        cpu.profiler.start();
+        movie.play_on(&mut cpu, |_| ());
+        fs::write(out, cpu.profile_report(PROFILE_TOP)).die("Unable to write profile");
+        fs::write(format!("{}.folded", out), cpu.profile_collapsed())
+            .die("Unable to write profile call stacks");
+        return true;
+    }
+    if mode == "--coverage" {
+        let mut cpu = movie.new_cpu(&rom, BoolDisplay::new(SCALE_FACTOR));
+        cpu.symbols = get_symbols();
+        cpu.coverage.start();
+        movie.play_on(&mut cpu, |_| ());
+        if let Ok(earlier) = fs::read_to_string(out) {
+            let earlier = Coverage::parse(&earlier).die("Unable to parse earlier coverage");
+            cpu.coverage.merge(&earlier);
+        }
+        fs::write(out, cpu.coverage.to_string()).die("Unable to write coverage");
+        print!("{}", cpu.coverage_report(cpu.rom_range()));
+        return true;
+    }
+    if mode == "--timeline" {
+        let mut cpu = movie.new_cpu(&rom, BoolDisplay::new(SCALE_FACTOR));
+        cpu.timeline.start();
+        if let Err(e) = panic::catch_unwind(AssertUnwindSafe(|| movie.play_on(&mut cpu, |_| ()))) {
+            cpu.timeline_error(&format!("cpu crashed: {}", panic_message(&*e)));
+        }
+        let timeline = if out.to_lowercase().ends_with(".trace.json") {
+            cpu.timeline_chrome_trace()
+        } else {
+            cpu.timeline_json()
+        };
+        fs::write(out, timeline).die("Unable to write timeline");
+        return true;
+    }
+    if mode == "--render" {
+        let format = if out.to_lowercase().ends_with(".gif") {
+            RecordFormat::Gif
+        } else {
+            RecordFormat::Apng
+        };
+        let mut recorder = Recorder::new();
+        movie.play(&rom, BoolDisplay::new(SCALE_FACTOR), |cpu| {
+            recorder.capture(&cpu.display)
+        });
+        let out = fs::File::create(out).die("Unable to create output file");
+        recorder
+            .encode(format, RECORD_SCALE_FACTOR, out)
+            .die("Unable to write animation");
+        return true;
+    }
+    let mut hashes = oxi8_cpu::FrameHashes::new();
+    movie.play(&rom, BoolDisplay::new(SCALE_FACTOR), |cpu| {
+        hashes.capture(&cpu.display)
+    });
+    if mode == "--hashes" {
+        fs::write(out, hashes.to_string()).die("Unable to write hashes");
+        return true;
+    }
+    let expected = fs::read_to_string(out).die("Unable to read expected hashes");
+    let expected = oxi8_cpu::FrameHashes::parse(&expected).die("Unable to parse expected hashes");
+    if let Some(frame) = expected.first_mismatch(&hashes) {
+        eprintln!("frame hashes differ starting at frame {}", frame);
+        std::process::exit(1);
+    }
+    true
+}
+
 #[cfg(target_arch = "wasm32")]
 fn quit() {
     //window().history().back().expect("can't go back?");
@@ -268,47 +749,47 @@ fn quit() {
     die!("exiting..."; 0);
 }
 
-static SOUND_ON: AtomicBool = AtomicBool::new(false);
-
 #[cfg(target_arch = "wasm32")]
-fn start_audio() {
-    let sound_on = || SOUND_ON.load(Ordering::Relaxed);
-    js! {
-        @(no_return)
+fn start_audio(buzzer: Buzzer) -> Audio {
+    let sample_rate: f64 = js! {
         var ctxClass = window.AudioContext || window.audioContext || window.webkitAudioContext;
         var ctx = new ctxClass();
-
-        var osc = undefined;
-
-        var sound_on = @{sound_on};
-
-        var on_now = false;
-
-        setInterval(function () {
-            var beep = sound_on();
-            if(beep == on_now)
-                return;
-            on_now = beep;
-            if(beep) {
-                osc = ctx.createOscillator();
-                // Only 0-4 are valid types.
-                //osc.type = (type % 5) || 0;
-                osc.type = "sine";
-                osc.connect(ctx.destination);
-
-                if (osc.noteOn) osc.noteOn(0);
-                if (osc.start) osc.start();
-            } else {
-                if (osc.noteOff) osc.noteOff(0);
-                if (osc.stop) osc.stop();
-            }
-        }, 16); // 1000 / 60 = 16.166667 = 60hz
+        window.oxi8AudioCtx = ctx;
+        return ctx.sampleRate;
+    }
+    .try_into()
+    .unwrap_or(0.0);
+
+    let audio = Audio::new(sample_rate as u32);
+    audio.set_buzzer(buzzer);
+    let output = audio.clone();
+    let fill = move |len: u32| -> TypedArray<f32> {
+        let mut samples = vec![0.0; len as usize];
+        output.fill_samples(&mut samples);
+        TypedArray::from(&samples[..])
+    };
+    js! {
+        @(no_return)
+        var ctx = window.oxi8AudioCtx;
+        var fill = @{fill};
+        var node = ctx.createScriptProcessor(2048, 0, 1);
+        node.onaudioprocess = function (e) {
+            var out = e.outputBuffer.getChannelData(0);
+            out.set(fill(out.length));
+        };
+        node.connect(ctx.destination);
+        // browsers won't start audio until the user does something
+        document.addEventListener("keydown", function () {
+            ctx.resume();
+        });
     }
+    audio
 }
 
 #[cfg(not(target_arch = "wasm32"))]
-fn start_audio() {
-    thread::spawn(|| {
+fn start_audio(buzzer: Buzzer) -> Audio {
+    let (tx, rx) = std::sync::mpsc::channel();
+    thread::spawn(move || {
         let device =
             cpal::default_output_device().expect("Failed to get default audio output device");
         let format = device
@@ -318,25 +799,26 @@ fn start_audio() {
         let stream_id = event_loop.build_output_stream(&device, &format).unwrap();
         event_loop.play_stream(stream_id);
 
-        let sample_rate = format.sample_rate.0 as f32; // 44_100 on my computer
-        let mut sample_clock = 0f32;
-
-        // Produce a sinusoid of maximum amplitude.
-        let mut next_value = || {
-            if SOUND_ON.load(Ordering::Relaxed) {
-                sample_clock = (sample_clock + 1.0) % sample_rate;
-                (sample_clock * 440.0 * 2.0 * std::f32::consts::PI / sample_rate).sin()
-            } else {
-                0.0
-            }
+        let audio = Audio::new(format.sample_rate.0); // 44_100 on my computer
+        audio.set_buzzer(buzzer);
+        tx.send(audio.clone()).unwrap();
+
+        let channels = format.channels as usize;
+        let mut mono = Vec::new();
+        // every channel gets the same mono samples
+        let mut next_values = move |len: usize| {
+            mono.resize(len / channels, 0.0);
+            audio.fill_samples(&mut mono);
+            mono.clone()
         };
 
         event_loop.run(move |_, data| match data {
             cpal::StreamData::Output {
                 buffer: cpal::UnknownTypeOutputBuffer::U16(mut buffer),
             } => {
-                for sample in buffer.chunks_mut(format.channels as usize) {
-                    let value = ((next_value() * 0.5 + 0.5) * std::u16::MAX as f32) as u16;
+                let values = next_values(buffer.len());
+                for (sample, value) in buffer.chunks_mut(channels).zip(values) {
+                    let value = ((value * 0.5 + 0.5) * u16::MAX as f32) as u16;
                     for out in sample.iter_mut() {
                         *out = value;
                     }
@@ -345,8 +827,9 @@ fn start_audio() {
             cpal::StreamData::Output {
                 buffer: cpal::UnknownTypeOutputBuffer::I16(mut buffer),
             } => {
-                for sample in buffer.chunks_mut(format.channels as usize) {
-                    let value = (next_value() * std::i16::MAX as f32) as i16;
+                let values = next_values(buffer.len());
+                for (sample, value) in buffer.chunks_mut(channels).zip(values) {
+                    let value = (value * i16::MAX as f32) as i16;
                     for out in sample.iter_mut() {
                         *out = value;
                     }
@@ -355,8 +838,8 @@ fn start_audio() {
             cpal::StreamData::Output {
                 buffer: cpal::UnknownTypeOutputBuffer::F32(mut buffer),
             } => {
-                for sample in buffer.chunks_mut(format.channels as usize) {
-                    let value = next_value();
+                let values = next_values(buffer.len());
+                for (sample, value) in buffer.chunks_mut(channels).zip(values) {
                     for out in sample.iter_mut() {
                         *out = value;
                     }
@@ -365,10 +848,17 @@ fn start_audio() {
             _ => (),
         });
     });
+    // no audio device just means no sound, not no game
+    rx.recv().unwrap_or_else(|_| Audio::new(0))
 }
 
 fn main() {
-    start_audio();
+    #[cfg(not(target_arch = "wasm32"))]
+    {
+        if headless() {
+            return;
+        }
+    }
 
     let settings = Settings {
         show_cursor: false,
@@ -381,7 +871,11 @@ fn main() {
         resize: ResizeStrategy::default(),
         scale: ImageScaleStrategy::default(),
         fullscreen: false,
-        update_rate: 1000. / 60.,
+        update_rate: if audio_sync() {
+            1000. / (60 * AUDIO_SYNC_UPDATES_PER_FRAME) as f64
+        } else {
+            1000. / 60.
+        },
         max_updates: 0,
         draw_rate: 0.,
         icon_path: None,
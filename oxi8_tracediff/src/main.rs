@@ -0,0 +1,123 @@
+use std::{env, fs, process};
+
+use oxi8_cpu::{Divergence, TraceStep};
+
+// finds the first instruction where an oxi8 trace and another interpreter's stop agreeing:
+//   oxi8_tracediff OXI8_TRACE REFERENCE_TRACE [--context N]
+// OXI8_TRACE is from oxi8_debug's trace file or quicksilver's --trace=FILE, REFERENCE_TRACE is
+// one 'PC OPCODE V0 ... VF I' line per instruction, see TraceStep
+// exits 0 when they agree, 1 when they diverge and 2 when a trace can't be read
+
+const CONTEXT: usize = 5;
+
+fn main() {
+    let mut args = env::args().skip(1);
+    let mut paths = Vec::new();
+    let mut context = CONTEXT;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--context" => {
+                context = args
+                    .next()
+                    .and_then(|context| context.parse().ok())
+                    .unwrap_or_else(|| usage())
+            }
+            _ => paths.push(arg),
+        }
+    }
+    if paths.len() != 2 {
+        usage();
+    }
+    let (ours, ours_text) = read(&paths[0], TraceStep::parse_oxi8);
+    let (theirs, theirs_text) = read(&paths[1], TraceStep::parse_reference);
+
+    let divergence = match Divergence::find(&ours, &theirs) {
+        Some(divergence) => divergence,
+        None => {
+            let agreed = ours.len().min(theirs.len());
+            println!("traces agree for all {} instructions", agreed);
+            if ours.len() != theirs.len() {
+                println!(
+                    "the oxi8 trace has {} and the reference {}, only the first {} were compared",
+                    ours.len(),
+                    theirs.len(),
+                    agreed
+                );
+            }
+            return;
+        }
+    };
+
+    let index = divergence.index;
+    println!(
+        "traces agree for {} instructions, then differ going into 0x{:03X}, oxi8 vs reference:",
+        index, ours[index].pc
+    );
+    for difference in divergence.differences.iter() {
+        println!("  {}", difference);
+    }
+    if index > 0 {
+        println!(
+            "registers are from right before each instruction, so the last one to agree, 0x{:03X} \
+             {:04X}, is what set them differently",
+            ours[index - 1].pc,
+            ours[index - 1].opcode
+        );
+    }
+    let start = index.saturating_sub(context);
+    let end = index + context + 1;
+    println!("\n{}:", paths[0]);
+    print_context(&ours, &ours_text, start..end, index);
+    println!("\nsame instructions as reference state, ?? for registers the trace doesn't show:");
+    for (n, step) in ours.iter().enumerate().take(end).skip(start) {
+        println!("{} {}", marker(n, index), step);
+    }
+    println!("\n{}:", paths[1]);
+    print_context(&theirs, &theirs_text, start..end, index);
+    process::exit(1);
+}
+
+fn usage() -> ! {
+    eprintln!("usage: oxi8_tracediff OXI8_TRACE REFERENCE_TRACE [--context N]");
+    process::exit(2);
+}
+
+fn read(
+    path: &str,
+    parse: fn(&str) -> Result<Vec<TraceStep>, String>,
+) -> (Vec<TraceStep>, Vec<String>) {
+    let text = fs::read_to_string(path).unwrap_or_else(|e| {
+        eprintln!("can't read {}: {}", path, e);
+        process::exit(2);
+    });
+    let steps = parse(&text).unwrap_or_else(|e| {
+        eprintln!("can't parse {}: {}", path, e);
+        process::exit(2);
+    });
+    (steps, text.lines().map(str::to_string).collect())
+}
+
+// the lines as they are in the file, so anything else they say, like disassembly, shows too
+fn print_context(
+    steps: &[TraceStep],
+    lines: &[String],
+    range: std::ops::Range<usize>,
+    index: usize,
+) {
+    for (n, step) in steps.iter().enumerate().take(range.end).skip(range.start) {
+        println!(
+            "{} {:>6}: {}",
+            marker(n, index),
+            step.line,
+            lines[step.line - 1]
+        );
+    }
+}
+
+fn marker(n: usize, index: usize) -> &'static str {
+    if n == index {
+        "=>"
+    } else {
+        "  "
+    }
+}